@@ -1,3 +1,8 @@
-mod unit_of_work_classical_design;
-mod unit_of_work_practical_design;
-mod unit_of_work_refactored_classical_design;
+#[cfg(feature = "backend-sea-orm")]
+pub mod unit_of_work_classical_design;
+pub mod unit_of_work_practical_design;
+#[cfg(feature = "backend-sea-orm")]
+pub mod unit_of_work_refactored_classical_design;
+
+#[cfg(test)]
+mod test_support;
@@ -0,0 +1,187 @@
+//! Helpers shared by the test modules of the three designs.
+
+// Only the sea_orm tests capture logs.
+#![cfg_attr(not(feature = "backend-sea-orm"), allow(dead_code))]
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span,
+    subscriber::Interest,
+    Event, Level, Metadata, Subscriber,
+};
+use tracing_core::span::Current;
+
+/// tokio's `#[tokio::test]` macro is not a dependency, so tests drive their futures here.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+/// Connects to the database at `POSTGRES_URL` with `schema`, created afresh, as its search
+/// path, so concurrent tests neither see nor clash over each other's tables. `None` when
+/// the variable is unset, for the Postgres-only tests to skip.
+#[cfg(feature = "backend-sea-orm")]
+pub async fn postgres(schema: &str) -> Option<sea_orm::DatabaseConnection> {
+    use sea_orm::{ConnectOptions, ConnectionTrait, Database};
+
+    let url = std::env::var("POSTGRES_URL").ok()?;
+    let admin = Database::connect(&url).await.unwrap();
+    admin
+        .execute_unprepared(&format!(
+            "DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0}",
+            schema
+        ))
+        .await
+        .unwrap();
+    let mut options = ConnectOptions::new(url);
+    options.set_schema_search_path(schema);
+    Some(Database::connect(options).await.unwrap())
+}
+
+#[derive(Debug, Clone)]
+pub struct CapturedEvent {
+    pub level: Level,
+    pub target: String,
+    pub fields: BTreeMap<String, String>,
+}
+
+impl CapturedEvent {
+    pub fn message(&self) -> &str {
+        self.fields.get("message").map_or("", String::as_str)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CapturedSpan {
+    pub name: &'static str,
+    pub fields: BTreeMap<String, String>,
+    metadata: &'static Metadata<'static>,
+}
+
+/// A subscriber that keeps every event and span it sees, for asserting on logs.
+#[derive(Clone, Default)]
+pub struct Capture {
+    events: Arc<Mutex<Vec<CapturedEvent>>>,
+    spans: Arc<Mutex<Vec<CapturedSpan>>>,
+    entered: Arc<Mutex<Vec<span::Id>>>,
+}
+
+impl Capture {
+    /// Runs `f` with this subscriber as the thread's default. Futures must be driven inside
+    /// `f`, e.g. with `block_on`, for their logs to be captured.
+    pub fn run<T>(&self, f: impl FnOnce() -> T) -> T {
+        tracing::subscriber::with_default(self.clone(), f)
+    }
+
+    pub fn events(&self) -> Vec<CapturedEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// The messages logged at `level` by this crate, leaving out sea_orm's and sqlx's own.
+    pub fn messages(&self, level: Level) -> Vec<String> {
+        self.events()
+            .into_iter()
+            .filter(|event| {
+                event.level == level && event.target.starts_with(env!("CARGO_CRATE_NAME"))
+            })
+            .map(|event| event.message().to_string())
+            .collect()
+    }
+
+    pub fn spans(&self) -> Vec<CapturedSpan> {
+        self.spans.lock().unwrap().clone()
+    }
+
+    pub fn span(&self, name: &str) -> Option<CapturedSpan> {
+        self.spans().into_iter().find(|span| span.name == name)
+    }
+}
+
+struct Fields<'a>(&'a mut BTreeMap<String, String>);
+
+impl Visit for Fields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+impl Subscriber for Capture {
+    fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        Interest::always()
+    }
+
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::TRACE)
+    }
+
+    fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+        let mut fields = BTreeMap::new();
+        attrs.record(&mut Fields(&mut fields));
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(CapturedSpan {
+            name: attrs.metadata().name(),
+            fields,
+            metadata: attrs.metadata(),
+        });
+        span::Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+        let mut spans = self.spans.lock().unwrap();
+        if let Some(span) = spans.get_mut(span.into_u64() as usize - 1) {
+            values.record(&mut Fields(&mut span.fields));
+        }
+    }
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = BTreeMap::new();
+        event.record(&mut Fields(&mut fields));
+        self.events.lock().unwrap().push(CapturedEvent {
+            level: *event.metadata().level(),
+            target: event.metadata().target().to_string(),
+            fields,
+        });
+    }
+
+    fn enter(&self, span: &span::Id) {
+        self.entered.lock().unwrap().push(span.clone());
+    }
+
+    fn exit(&self, span: &span::Id) {
+        let mut entered = self.entered.lock().unwrap();
+        if let Some(position) = entered.iter().rposition(|id| id == span) {
+            entered.remove(position);
+        }
+    }
+
+    /// Lets `Span::current().record(..)` reach the span it was called in.
+    fn current_span(&self) -> Current {
+        let Some(id) = self.entered.lock().unwrap().last().cloned() else {
+            return Current::none();
+        };
+        let metadata = self.spans.lock().unwrap()[id.into_u64() as usize - 1].metadata;
+        Current::new(id, metadata)
+    }
+}
@@ -1,52 +1,550 @@
-mod domain {
+pub mod domain {
+    use std::{
+        collections::HashMap,
+        fmt::{self, Debug},
+        marker::PhantomData,
+        time::Duration,
+    };
+
     use anyhow::Result;
     use async_trait::async_trait;
     use derive_new::new;
+    use sea_orm::{DatabaseBackend, DatabaseTransaction, DbErr, Value};
+    use serde::{Deserialize, Serialize};
+    use thiserror::Error;
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, new)]
+    pub struct User {
+        id: i64,
+        #[new(default)]
+        tenant_id: Option<TenantId>,
+    }
+
+    impl User {
+        pub fn id(&self) -> i64 {
+            self.id
+        }
+
+        pub fn with_tenant(mut self, tenant_id: TenantId) -> Self {
+            self.tenant_id = Some(tenant_id);
+            self
+        }
+
+        pub fn tenant_id(&self) -> Option<TenantId> {
+            self.tenant_id
+        }
+
+        pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+            validate_id(self.id)
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, new)]
+    pub struct Shop {
+        id: i64,
+        #[new(default)]
+        tenant_id: Option<TenantId>,
+    }
+
+    impl Shop {
+        pub fn id(&self) -> i64 {
+            self.id
+        }
+
+        pub fn with_tenant(mut self, tenant_id: TenantId) -> Self {
+            self.tenant_id = Some(tenant_id);
+            self
+        }
+
+        pub fn tenant_id(&self) -> Option<TenantId> {
+            self.tenant_id
+        }
+
+        pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+            validate_id(self.id)
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, new)]
+    pub struct Order {
+        id: i64,
+        #[new(default)]
+        tenant_id: Option<TenantId>,
+    }
+
+    impl Order {
+        pub fn id(&self) -> i64 {
+            self.id
+        }
+
+        pub fn with_tenant(mut self, tenant_id: TenantId) -> Self {
+            self.tenant_id = Some(tenant_id);
+            self
+        }
+
+        pub fn tenant_id(&self) -> Option<TenantId> {
+            self.tenant_id
+        }
 
-    #[derive(Debug, new)]
-    pub struct User;
+        pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+            validate_id(self.id)
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, new)]
+    pub struct Product {
+        id: i64,
+        #[new(default)]
+        tenant_id: Option<TenantId>,
+    }
+
+    impl Product {
+        pub fn id(&self) -> i64 {
+            self.id
+        }
+
+        pub fn with_tenant(mut self, tenant_id: TenantId) -> Self {
+            self.tenant_id = Some(tenant_id);
+            self
+        }
+
+        pub fn tenant_id(&self) -> Option<TenantId> {
+            self.tenant_id
+        }
+
+        pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+            validate_id(self.id)
+        }
+    }
+
+    /// One field-level problem; `validate` collects every one it finds instead of stopping
+    /// at the first.
+    #[derive(Debug, Clone, PartialEq, Eq, Error, new)]
+    #[error("{field} {message}")]
+    pub struct ValidationError {
+        pub field: &'static str,
+        pub message: String,
+    }
+
+    fn validate_id(id: i64) -> Result<(), Vec<ValidationError>> {
+        if id <= 0 {
+            return Err(vec![ValidationError::new(
+                "id",
+                "must be positive".to_string(),
+            )]);
+        }
+        Ok(())
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, new)]
+    pub struct TenantId(i64);
 
-    #[derive(Debug, new)]
-    pub struct Shop;
+    /// Who performed a commit, written into `created_by`/`updated_by`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, new)]
+    pub struct ActorId(i64);
 
-    #[derive(Debug, new)]
-    pub struct Order;
+    impl From<ActorId> for Value {
+        fn from(actor: ActorId) -> Self {
+            actor.0.into()
+        }
+    }
 
     #[async_trait]
-    pub trait UnitOfWork {
-        fn create<T>(&mut self, aggregate: T) -> ()
+    pub trait UnitOfWork<A = Aggregate> {
+        fn create<T>(&mut self, aggregate: T) -> Result<()>
+        where
+            T: Into<A>;
+
+        fn update<T>(&mut self, aggregate: T) -> Result<()>
+        where
+            T: Into<A>;
+
+        fn delete<T>(&mut self, aggregate: T) -> Result<()>
+        where
+            T: Into<A>;
+
+        fn upsert<T>(&mut self, aggregate: T) -> Result<()>
+        where
+            T: Into<A>;
+
+        fn create_with_key<T>(&mut self, aggregate: T, key: String) -> Result<()>
+        where
+            T: Into<A>;
+
+        fn update_with_key<T>(&mut self, aggregate: T, key: String) -> Result<()>
         where
-            T: Into<Aggregate>;
+            T: Into<A>;
 
-        fn update<T>(&mut self, aggregate: T) -> ()
+        /// Commands are stable-sorted by priority at commit; the plain methods use 0.
+        fn create_with_priority<T>(&mut self, aggregate: T, priority: i32) -> Result<()>
         where
-            T: Into<Aggregate>;
+            T: Into<A>;
 
-        fn delete<T>(&mut self, aggregate: T) -> ()
+        fn update_with_priority<T>(&mut self, aggregate: T, priority: i32) -> Result<()>
         where
-            T: Into<Aggregate>;
+            T: Into<A>;
 
-        async fn commit(&mut self) -> Result<()>;
+        fn delete_with_priority<T>(&mut self, aggregate: T, priority: i32) -> Result<()>
+        where
+            T: Into<A>;
+
+        /// `metadata` is not written to the aggregate's table; it is carried into the
+        /// `Change` reported for the command.
+        fn create_with_metadata<T>(&mut self, aggregate: T, metadata: Metadata) -> Result<()>
+        where
+            T: Into<A>;
+
+        fn update_with_metadata<T>(&mut self, aggregate: T, metadata: Metadata) -> Result<()>
+        where
+            T: Into<A>;
+
+        fn delete_with_metadata<T>(&mut self, aggregate: T, metadata: Metadata) -> Result<()>
+        where
+            T: Into<A>;
+
+        async fn commit(&mut self) -> Result<CommitReport>;
     }
 
-    #[derive(Debug, new)]
-    pub struct Command {
-        pub aggregate: Aggregate,
-        pub db_operation: DBOperation,
+    /// Chained staging, e.g. `uow.staging().create(user).update(shop).done()?`.
+    pub trait StageExt<A>: UnitOfWork<A> + Sized {
+        fn staging(&mut self) -> Stager<'_, Self, A> {
+            Stager {
+                uow: self,
+                result: Ok(()),
+                aggregate: PhantomData,
+            }
+        }
+    }
+
+    impl<A, U: UnitOfWork<A>> StageExt<A> for U {}
+
+    /// Stages through the wrapped unit of work. After the first failure the remaining
+    /// calls are skipped and `done` returns that error.
+    pub struct Stager<'a, U, A> {
+        uow: &'a mut U,
+        result: Result<()>,
+        aggregate: PhantomData<A>,
+    }
+
+    impl<U: UnitOfWork<A>, A> Stager<'_, U, A> {
+        pub fn create<T: Into<A>>(mut self, aggregate: T) -> Self {
+            if self.result.is_ok() {
+                self.result = self.uow.create(aggregate);
+            }
+            self
+        }
+
+        pub fn update<T: Into<A>>(mut self, aggregate: T) -> Self {
+            if self.result.is_ok() {
+                self.result = self.uow.update(aggregate);
+            }
+            self
+        }
+
+        pub fn delete<T: Into<A>>(mut self, aggregate: T) -> Self {
+            if self.result.is_ok() {
+                self.result = self.uow.delete(aggregate);
+            }
+            self
+        }
+
+        pub fn done(self) -> Result<()> {
+            self.result
+        }
+    }
+
+    /// Lets `DatabaseClient` persist aggregates defined outside this module. Only `apply` is
+    /// required; the remaining methods tune batching, ordering and `optimize`.
+    #[async_trait]
+    pub trait Dispatch: Clone + Debug + Send + Sync + Sized + 'static {
+        async fn apply(
+            self,
+            op: DBOperation,
+            txn: &DatabaseTransaction,
+        ) -> Result<Option<i64>, DbErr>;
+
+        async fn apply_creates(
+            batch: Vec<Self>,
+            txn: &DatabaseTransaction,
+        ) -> Result<Vec<i64>, DbErr> {
+            let mut ids = Vec::with_capacity(batch.len());
+            for aggregate in batch {
+                ids.extend(aggregate.apply(DBOperation::Create, txn).await?);
+            }
+            Ok(ids)
+        }
+
+        /// `apply` on behalf of `actor`, for aggregates whose rows record who wrote them.
+        /// Ignores the actor by default.
+        async fn apply_as(
+            self,
+            op: DBOperation,
+            _actor: Option<ActorId>,
+            txn: &DatabaseTransaction,
+        ) -> Result<Option<i64>, DbErr> {
+            self.apply(op, txn).await
+        }
+
+        /// `apply_creates` on behalf of `actor`. Ignores the actor by default.
+        async fn apply_creates_as(
+            batch: Vec<Self>,
+            _actor: Option<ActorId>,
+            txn: &DatabaseTransaction,
+        ) -> Result<Vec<i64>, DbErr> {
+            Self::apply_creates(batch, txn).await
+        }
+
+        fn is_same_kind(&self, _other: &Self) -> bool {
+            false
+        }
+
+        fn is_same_as(&self, _other: &Self) -> bool {
+            false
+        }
+
+        fn dependency_rank(&self) -> u8 {
+            0
+        }
+
+        fn kind(&self) -> &'static str {
+            "aggregate"
+        }
+
+        fn payload(&self) -> serde_json::Value {
+            serde_json::Value::Null
+        }
+
+        fn aggregate_id(&self) -> Option<i64> {
+            None
+        }
+
+        fn validate(&self) -> Result<(), Vec<ValidationError>> {
+            Ok(())
+        }
+
+        fn tenant_id(&self) -> Option<TenantId> {
+            None
+        }
+
+        /// The SQL `apply_as` would run for `op` on behalf of `actor`, with values inlined,
+        /// when it can be known without touching the database.
+        fn explain(
+            &self,
+            _op: DBOperation,
+            _actor: Option<ActorId>,
+            _backend: DatabaseBackend,
+        ) -> Option<String> {
+            None
+        }
+    }
+
+    #[derive(Debug, Error)]
+    pub enum UnitOfWorkError {
+        #[error("commit timed out after {0:?}")]
+        Timeout(Duration),
+        #[error("cannot stage more than {0} commands")]
+        QueueFull(usize),
+        #[error("commit was cancelled")]
+        Cancelled,
+        #[error("no connection was available within {0:?}")]
+        AcquireTimeout(Duration),
+        #[error("command {}: unknown aggregate kind `{kind}`", index + 1)]
+        UnknownAggregate { index: usize, kind: String },
+        #[error("{}", describe_invalid(.0))]
+        Invalid(Vec<(usize, ValidationError)>),
+        #[error("nothing to commit")]
+        EmptyCommit,
+        #[error("command {}: aggregate of tenant {found:?} staged by tenant {expected:?}", index + 1)]
+        CrossTenant {
+            index: usize,
+            expected: TenantId,
+            found: Option<TenantId>,
+        },
+    }
+
+    /// Pairs are `(staging index, error)`; the message numbers commands from 1.
+    fn describe_invalid(errors: &[(usize, ValidationError)]) -> String {
+        let details = errors
+            .iter()
+            .map(|(index, err)| format!("command {}: {}", index + 1, err))
+            .collect::<Vec<_>>();
+        format!("invalid aggregates: {}", details.join("; "))
+    }
+
+    #[derive(Debug, Default)]
+    pub struct CommitReport {
+        pub created_ids: Vec<(usize, i64)>,
+        pub events: Vec<DomainEvent>,
+        /// Statements the unit of work issued itself, with a batched insert counted once and
+        /// the `SET` statements run before the commands included. Whatever a registered hook
+        /// runs is opaque to it and not included.
+        pub stmt_count: usize,
+        /// Raw statements run, which `stmt_count` includes as well.
+        pub raw_count: usize,
+        pub changes: Vec<Change>,
+        /// Whether the commit did any work, for callers that only need to branch on that,
+        /// e.g. `match uow.commit().await?.result`.
+        pub result: CommitResult,
     }
 
+    /// `count` is the commands plus raw statements executed; the `SET` statements run
+    /// before them are not work, so a commit of only those is `Empty`, as is a dry run.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub enum CommitResult {
+        #[default]
+        Empty,
+        Committed {
+            count: usize,
+        },
+    }
+
+    /// What `commit` does when nothing is staged. `Error` is for callers that treat an
+    /// empty commit as a logic bug.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub enum EmptyCommitPolicy {
+        #[default]
+        Ok,
+        Error,
+        Warn,
+    }
+
+    /// One operation that actually ran, in execution order.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Change {
+        pub kind: &'static str,
+        pub operation: DBOperation,
+        pub id: Option<i64>,
+        pub metadata: Metadata,
+    }
+
+    /// Free-form context for a command, such as the source system, for downstream
+    /// consumers of its `Change`.
+    pub type Metadata = HashMap<String, String>;
+
     #[derive(Debug)]
+    #[allow(clippy::enum_variant_names)]
+    pub enum DomainEvent {
+        UserCreated,
+        ShopCreated,
+        OrderCreated,
+        ProductCreated,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, new)]
+    pub struct Command<A = Aggregate> {
+        pub aggregate: A,
+        pub db_operation: DBOperation,
+        #[new(default)]
+        pub idempotency_key: Option<String>,
+        #[new(default)]
+        pub priority: i32,
+        #[new(default)]
+        #[serde(default)]
+        pub metadata: Metadata,
+    }
+
+    impl<A> Command<A> {
+        pub fn with_idempotency_key(mut self, key: String) -> Self {
+            self.idempotency_key = Some(key);
+            self
+        }
+
+        pub fn with_priority(mut self, priority: i32) -> Self {
+            self.priority = priority;
+            self
+        }
+
+        pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+            self.metadata = metadata;
+            self
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub enum Aggregate {
         User(User),
         Shop(Shop),
         Order(Order),
+        Product(Product),
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum AggregateKind {
+        User,
+        Shop,
+        Order,
+        Product,
+    }
+
+    impl AggregateKind {
+        pub const ALL: [Self; 4] = [Self::User, Self::Shop, Self::Order, Self::Product];
+
+        pub fn from_name(name: &str) -> Option<Self> {
+            Self::ALL.into_iter().find(|kind| kind.as_str() == name)
+        }
+
+        pub fn as_str(self) -> &'static str {
+            match self {
+                Self::User => "user",
+                Self::Shop => "shop",
+                Self::Order => "order",
+                Self::Product => "product",
+            }
+        }
+    }
+
+    impl From<AggregateKind> for &'static str {
+        fn from(kind: AggregateKind) -> Self {
+            kind.as_str()
+        }
+    }
+
+    impl From<&Aggregate> for AggregateKind {
+        fn from(aggregate: &Aggregate) -> Self {
+            match aggregate {
+                Aggregate::User(_) => Self::User,
+                Aggregate::Shop(_) => Self::Shop,
+                Aggregate::Order(_) => Self::Order,
+                Aggregate::Product(_) => Self::Product,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
     pub enum DBOperation {
         Create,
         Update,
         Delete,
+        Upsert,
+    }
+
+    impl fmt::Display for DBOperation {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(match self {
+                Self::Create => "create",
+                Self::Update => "update",
+                Self::Delete => "delete",
+                Self::Upsert => "upsert",
+            })
+        }
+    }
+
+    impl Aggregate {
+        pub fn id(&self) -> i64 {
+            match self {
+                Self::User(user) => user.id(),
+                Self::Shop(shop) => shop.id(),
+                Self::Order(order) => order.id(),
+                Self::Product(product) => product.id(),
+            }
+        }
+    }
+
+    pub fn dependency_rank(aggregate: &Aggregate) -> u8 {
+        match aggregate {
+            Aggregate::User(_) | Aggregate::Shop(_) | Aggregate::Product(_) => 0,
+            Aggregate::Order(_) => 1,
+        }
     }
 
     impl From<User> for Aggregate {
@@ -66,159 +564,4750 @@ mod domain {
             Self::Order(order)
         }
     }
+
+    impl From<Product> for Aggregate {
+        fn from(product: Product) -> Self {
+            Self::Product(product)
+        }
+    }
+}
+
+pub mod metrics {
+    use std::{
+        collections::HashMap,
+        sync::{Mutex, OnceLock},
+        time::Duration,
+    };
+
+    use super::domain::{AggregateKind, DBOperation};
+
+    /// Upper bounds of the first seven buckets; the last bucket counts everything slower.
+    pub const BUCKET_BOUNDS: [Duration; 7] = [
+        Duration::from_millis(1),
+        Duration::from_millis(5),
+        Duration::from_millis(10),
+        Duration::from_millis(50),
+        Duration::from_millis(100),
+        Duration::from_millis(500),
+        Duration::from_secs(1),
+    ];
+
+    type Histograms = HashMap<(AggregateKind, DBOperation), [u64; 8]>;
+
+    static LATENCIES: OnceLock<Mutex<Histograms>> = OnceLock::new();
+
+    fn latencies() -> &'static Mutex<Histograms> {
+        LATENCIES.get_or_init(Default::default)
+    }
+
+    pub fn record_latency(kind: AggregateKind, operation: DBOperation, elapsed: Duration) {
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|bound| elapsed <= *bound)
+            .unwrap_or(BUCKET_BOUNDS.len());
+        latencies()
+            .lock()
+            .unwrap()
+            .entry((kind, operation))
+            .or_default()[bucket] += 1;
+    }
+
+    /// Per-dispatch latencies since process start. A batched create is one dispatch and is
+    /// recorded once.
+    pub fn latency_buckets() -> Histograms {
+        latencies().lock().unwrap().clone()
+    }
 }
 
-mod infrastructure {
-    use super::domain::{Aggregate, Command, DBOperation, Order, Shop, UnitOfWork, User};
+pub mod infrastructure {
+    use super::{
+        domain::{
+            dependency_rank, ActorId, Aggregate, AggregateKind, Change, Command, CommitReport,
+            CommitResult, DBOperation, Dispatch, DomainEvent, EmptyCommitPolicy, Metadata, Order,
+            Product, Shop, TenantId, UnitOfWork, UnitOfWorkError, User, ValidationError,
+        },
+        metrics,
+    };
+
+    use std::{
+        collections::HashSet,
+        future::Future,
+        marker::PhantomData,
+        ops::{Deref, DerefMut},
+        pin::Pin,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        task::{ready, Context as TaskContext, Poll},
+        time::{Duration, Instant},
+    };
 
     use anyhow::Context;
     use async_trait::async_trait;
     use derive_new::new;
     use sea_orm::{
         prelude::{DatabaseConnection, DbErr},
-        DatabaseTransaction, TransactionTrait,
+        sea_query::{
+            Alias, DeleteStatement, Expr, InsertStatement, OnConflict, Query, UpdateStatement,
+        },
+        ConnectionTrait, DatabaseBackend, DatabaseTransaction, IsolationLevel, RuntimeErr,
+        Statement, TransactionError, TransactionTrait, Value,
     };
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use serde_json::json;
+    use thiserror::Error;
+    use tokio::sync::mpsc::{self, error::TrySendError};
+    use tokio_stream::Stream;
+    use tokio_util::sync::CancellationToken;
 
-    #[derive(new)]
-    pub struct DatabaseClient {
-        conn: DatabaseConnection,
-        commands: Vec<Command>,
+    pub type Validator<A = Aggregate> =
+        Box<dyn Fn(&[Command<A>]) -> anyhow::Result<()> + Send + Sync>;
+
+    pub type Callback = Box<dyn FnOnce() + Send>;
+
+    type Progress = mpsc::UnboundedSender<anyhow::Result<Change>>;
+
+    /// What `commit_stream` returns: drives `commit` whenever the changes it reports so far
+    /// have all been yielded, then yields its failure, if any, last.
+    struct CommitStream {
+        changes: mpsc::UnboundedReceiver<anyhow::Result<Change>>,
+        commit: Option<BoxFuture<'static, anyhow::Result<()>>>,
+        failure: Option<anyhow::Error>,
     }
 
-    #[async_trait]
-    impl UnitOfWork for DatabaseClient {
-        fn create<T>(&mut self, aggregate: T) -> ()
-        where
-            T: Into<Aggregate>,
-        {
-            self.commands
-                .push(Command::new(aggregate.into(), DBOperation::Create));
+    impl CommitStream {
+        /// A stream with nothing to run. The sender is dropped at once, so the channel ends.
+        fn done() -> Self {
+            Self {
+                changes: mpsc::unbounded_channel().1,
+                commit: None,
+                failure: None,
+            }
         }
 
-        fn update<T>(&mut self, aggregate: T) -> ()
-        where
-            T: Into<Aggregate>,
-        {
-            self.commands
-                .push(Command::new(aggregate.into(), DBOperation::Update));
+        fn failed(err: anyhow::Error) -> Self {
+            Self {
+                failure: Some(err),
+                ..Self::done()
+            }
         }
+    }
 
-        fn delete<T>(&mut self, aggregate: T) -> ()
-        where
-            T: Into<Aggregate>,
-        {
-            self.commands
-                .push(Command::new(aggregate.into(), DBOperation::Delete));
+    impl Stream for CommitStream {
+        type Item = anyhow::Result<Change>;
+
+        fn poll_next(
+            mut self: Pin<&mut Self>,
+            cx: &mut TaskContext<'_>,
+        ) -> Poll<Option<Self::Item>> {
+            let this = &mut *self;
+            loop {
+                if let Poll::Ready(Some(change)) = this.changes.poll_recv(cx) {
+                    return Poll::Ready(Some(change));
+                }
+                let Some(commit) = this.commit.as_mut() else {
+                    return Poll::Ready(this.failure.take().map(Err));
+                };
+                // The sender lives in `commit`, so once it finishes the channel only holds
+                // what is left to yield.
+                this.failure = ready!(commit.as_mut().poll(cx)).err();
+                this.commit = None;
+            }
         }
+    }
 
-        async fn commit(&mut self) -> anyhow::Result<()> {
-            let commands = self.commands.drain(..).collect::<Vec<_>>();
-            self.conn
-                .transaction::<_, (), DbErr>(|txn| {
-                    Box::pin(async move {
-                        for command in commands {
-                            match command.aggregate {
-                                Aggregate::User(user) => match command.db_operation {
-                                    DBOperation::Create => create_user(user, txn).await,
-                                    DBOperation::Update => update_user(user, txn).await,
-                                    DBOperation::Delete => delete_user(user, txn).await,
-                                },
-                                Aggregate::Shop(shop) => match command.db_operation {
-                                    DBOperation::Create => create_shop(shop, txn).await,
-                                    DBOperation::Update => update_shop(shop, txn).await,
-                                    DBOperation::Delete => delete_shop(shop, txn).await,
-                                },
-                                Aggregate::Order(order) => match command.db_operation {
-                                    DBOperation::Create => create_order(order, txn).await,
-                                    DBOperation::Update => update_order(order, txn).await,
-                                    DBOperation::Delete => delete_order(order, txn).await,
-                                },
-                            }?;
-                        }
-                        Ok(())
-                    })
-                })
-                .await
-                .with_context(|| format!("failed to commit transaction"))?;
-            Ok(())
+    /// Rows assumed for a raw statement, whose effect cannot be known before it runs.
+    const RAW_STATEMENT_ROWS: usize = 100;
+
+    pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+    /// Receives the operation that ran and the aggregate's id, so a hook can act on some
+    /// operations only.
+    pub type HookFn = dyn for<'a> Fn(
+            &'a DatabaseTransaction,
+            DBOperation,
+            Option<i64>,
+        ) -> BoxFuture<'a, Result<(), DbErr>>
+        + Send
+        + Sync;
+
+    pub type Hook = Box<HookFn>;
+
+    /// Stands in for the database during a commit, set with `DatabaseClient::with_executor`.
+    /// It receives each planned step after deduplication, ordering and batching, so that
+    /// logic can be exercised without a connection. A batched create arrives as one call.
+    pub trait CommandExecutor<A>: Send + Sync {
+        fn execute(&self, operation: DBOperation, aggregates: &[A]) -> Result<(), DbErr>;
+    }
+
+    /// Tells apart the connections clients were provided from, which sea_orm cannot
+    /// compare. Each `Context` draws its own.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ConnectionId(u64);
+
+    impl ConnectionId {
+        pub fn next() -> Self {
+            static NEXT: AtomicU64 = AtomicU64::new(1);
+            Self(NEXT.fetch_add(1, Ordering::Relaxed))
         }
     }
 
-    async fn create_user(_user: User, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    /// `new` is kept for use inside this module; callers should go through
+    /// `DatabaseClient::builder()`.
+    #[derive(new)]
+    pub struct DatabaseClient<A = Aggregate> {
+        conn: DatabaseConnection,
+        commands: Vec<Command<A>>,
+        #[new(default)]
+        events: Vec<DomainEvent>,
+        #[new(default)]
+        validators: Vec<Validator<A>>,
+        #[new(default)]
+        callbacks: Mutex<Vec<Callback>>,
+        #[new(default)]
+        max_commands: Option<usize>,
+        #[new(default)]
+        isolation: Option<IsolationLevel>,
+        #[new(default)]
+        dry_run: bool,
+        #[new(default)]
+        event_store: Option<EventStore>,
+        #[new(default)]
+        hooks: Vec<(&'static str, Arc<HookFn>)>,
+        #[new(default)]
+        raw_statements: Vec<RawStatement>,
+        #[new(default)]
+        slow_threshold: Option<Duration>,
+        #[new(default)]
+        defer_constraints: bool,
+        #[new(default)]
+        statement_timeout_ms: Option<u64>,
+        #[new(default)]
+        actor: Option<ActorId>,
+        #[new(default)]
+        empty_commit: EmptyCommitPolicy,
+        #[new(default)]
+        delete_before_create: bool,
+        #[new(default)]
+        tenant: Option<TenantId>,
+        #[new(default)]
+        event_sink: Option<mpsc::Sender<Change>>,
+        #[new(default)]
+        executor: Option<Arc<dyn CommandExecutor<A>>>,
+        /// Set when enlisted in a transaction, as `conn` is then `Disconnected`.
+        #[new(default)]
+        backend: Option<DatabaseBackend>,
+        #[new(default)]
+        connection_id: Option<ConnectionId>,
     }
 
-    async fn update_user(_user: User, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    /// An escape hatch for SQL the typed commands cannot express. `sql` is sent to the
+    /// database verbatim, so it must never be built from user input: pass every variable part
+    /// through `values` as a bound parameter to avoid SQL injection.
+    #[derive(Debug, Clone, new)]
+    pub struct RawStatement {
+        pub sql: String,
+        pub values: Vec<Value>,
+        /// How many commands were staged before this statement, which all run before it.
+        #[new(default)]
+        position: usize,
     }
 
-    async fn delete_user(_user: User, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    /// The staged commands and raw statements at a point in time, taken with
+    /// `DatabaseClient::snapshot`.
+    #[derive(Debug, Clone)]
+    pub struct QueueSnapshot<A = Aggregate> {
+        commands: Vec<Command<A>>,
+        raw_statements: Vec<RawStatement>,
     }
 
-    async fn create_shop(_shop: Shop, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    /// Appends every executed command to an audit table inside the commit's transaction, so
+    /// a failed append aborts the whole batch.
+    #[derive(Debug, Clone, new)]
+    pub struct EventStore {
+        table: String,
     }
 
-    async fn update_shop(_shop: Shop, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    impl Default for EventStore {
+        fn default() -> Self {
+            Self::new("event_log".to_string())
+        }
     }
 
-    async fn delete_shop(_shop: Shop, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    impl EventStore {
+        async fn append<A: Dispatch>(
+            &self,
+            operation: DBOperation,
+            aggregate: &A,
+            txn: &DatabaseTransaction,
+        ) -> Result<(), DbErr> {
+            let stmt = Query::insert()
+                .into_table(Alias::new(&self.table))
+                .columns([
+                    Alias::new("operation"),
+                    Alias::new("aggregate_kind"),
+                    Alias::new("payload"),
+                ])
+                .values_panic([
+                    operation.to_string().into(),
+                    aggregate.kind().into(),
+                    aggregate.payload().into(),
+                ])
+                .to_owned();
+            txn.execute(txn.get_database_backend().build(&stmt)).await?;
+            Ok(())
+        }
     }
 
-    async fn create_order(_order: Order, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    /// A bound value of a raw statement as written by `to_json`. The variant is kept so a
+    /// NULL is restored with its type.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    enum StoredValue {
+        Bool(Option<bool>),
+        TinyInt(Option<i8>),
+        SmallInt(Option<i16>),
+        Int(Option<i32>),
+        BigInt(Option<i64>),
+        TinyUnsigned(Option<u8>),
+        SmallUnsigned(Option<u16>),
+        Unsigned(Option<u32>),
+        BigUnsigned(Option<u64>),
+        Float(Option<f32>),
+        Double(Option<f64>),
+        String(Option<String>),
+        Char(Option<char>),
+        Bytes(Option<Vec<u8>>),
+        Json(Option<serde_json::Value>),
     }
 
-    async fn update_order(_order: Order, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    impl TryFrom<&Value> for StoredValue {
+        type Error = anyhow::Error;
+
+        fn try_from(value: &Value) -> anyhow::Result<Self> {
+            Ok(match value {
+                Value::Bool(v) => Self::Bool(*v),
+                Value::TinyInt(v) => Self::TinyInt(*v),
+                Value::SmallInt(v) => Self::SmallInt(*v),
+                Value::Int(v) => Self::Int(*v),
+                Value::BigInt(v) => Self::BigInt(*v),
+                Value::TinyUnsigned(v) => Self::TinyUnsigned(*v),
+                Value::SmallUnsigned(v) => Self::SmallUnsigned(*v),
+                Value::Unsigned(v) => Self::Unsigned(*v),
+                Value::BigUnsigned(v) => Self::BigUnsigned(*v),
+                Value::Float(v) => Self::Float(*v),
+                Value::Double(v) => Self::Double(*v),
+                Value::String(v) => Self::String(v.as_deref().cloned()),
+                Value::Char(v) => Self::Char(*v),
+                Value::Bytes(v) => Self::Bytes(v.as_deref().cloned()),
+                Value::Json(v) => Self::Json(v.as_deref().cloned()),
+                value => anyhow::bail!("cannot serialize raw statement value {:?}", value),
+            })
+        }
     }
 
-    async fn delete_order(_order: Order, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    impl From<StoredValue> for Value {
+        fn from(value: StoredValue) -> Self {
+            match value {
+                StoredValue::Bool(v) => Value::Bool(v),
+                StoredValue::TinyInt(v) => Value::TinyInt(v),
+                StoredValue::SmallInt(v) => Value::SmallInt(v),
+                StoredValue::Int(v) => Value::Int(v),
+                StoredValue::BigInt(v) => Value::BigInt(v),
+                StoredValue::TinyUnsigned(v) => Value::TinyUnsigned(v),
+                StoredValue::SmallUnsigned(v) => Value::SmallUnsigned(v),
+                StoredValue::Unsigned(v) => Value::Unsigned(v),
+                StoredValue::BigUnsigned(v) => Value::BigUnsigned(v),
+                StoredValue::Float(v) => Value::Float(v),
+                StoredValue::Double(v) => Value::Double(v),
+                StoredValue::String(v) => Value::String(v.map(Box::new)),
+                StoredValue::Char(v) => Value::Char(v),
+                StoredValue::Bytes(v) => Value::Bytes(v.map(Box::new)),
+                StoredValue::Json(v) => Value::Json(v.map(Box::new)),
+            }
+        }
+    }
+
+    /// `position` is missing from logs written when raw statements ran after every command,
+    /// so it defaults to that.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct StoredStatement {
+        sql: String,
+        values: Vec<StoredValue>,
+        #[serde(default)]
+        position: Option<usize>,
     }
-}
 
-mod context {
-    use sea_orm::prelude::DatabaseConnection;
+    impl TryFrom<&RawStatement> for StoredStatement {
+        type Error = anyhow::Error;
 
-    use super::infrastructure::DatabaseClient;
+        fn try_from(raw: &RawStatement) -> anyhow::Result<Self> {
+            Ok(Self {
+                sql: raw.sql.clone(),
+                values: raw
+                    .values
+                    .iter()
+                    .map(StoredValue::try_from)
+                    .collect::<anyhow::Result<_>>()?,
+                position: Some(raw.position),
+            })
+        }
+    }
 
-    pub trait ProvideUnitOfWork {
-        type UnitOfWork: super::domain::UnitOfWork + Send + Sync;
-        fn provide(&self) -> Self::UnitOfWork;
+    impl StoredStatement {
+        /// `commands` is the number of commands in the log, where an old entry belongs.
+        fn restore(self, commands: usize) -> RawStatement {
+            RawStatement {
+                sql: self.sql,
+                values: self.values.into_iter().map(Value::from).collect(),
+                position: self.position.unwrap_or(commands).min(commands),
+            }
+        }
     }
 
-    pub struct Context {
-        conn: DatabaseConnection,
+    fn restore_statements(stored: Vec<StoredStatement>, commands: usize) -> Vec<RawStatement> {
+        stored
+            .into_iter()
+            .map(|stored| stored.restore(commands))
+            .collect()
     }
 
-    impl ProvideUnitOfWork for Context {
-        type UnitOfWork = super::infrastructure::DatabaseClient;
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(bound(deserialize = "A: DeserializeOwned"))]
+    struct StoredQueue<A> {
+        commands: Vec<Command<A>>,
+        #[serde(default)]
+        raw_statements: Vec<StoredStatement>,
+    }
 
-        fn provide(&self) -> Self::UnitOfWork {
-            DatabaseClient::new(self.conn.clone(), vec![])
+    /// Only the staged commands and raw statements are persisted; events, validators and
+    /// callbacks are process-local and must be re-registered after `load_from_json`. Each raw
+    /// statement is stored with its position among the commands.
+    impl<A: Dispatch + Serialize + DeserializeOwned> DatabaseClient<A> {
+        pub fn to_json(&self) -> anyhow::Result<String> {
+            let queue = StoredQueue {
+                commands: self.commands.clone(),
+                raw_statements: self
+                    .raw_statements
+                    .iter()
+                    .map(StoredStatement::try_from)
+                    .collect::<anyhow::Result<_>>()?,
+            };
+            serde_json::to_string(&queue).with_context(|| "failed to serialize commands")
+        }
+
+        pub fn load_from_json(conn: DatabaseConnection, json: &str) -> anyhow::Result<Self> {
+            let queue: StoredQueue<A> =
+                serde_json::from_str(json).with_context(|| "failed to deserialize commands")?;
+            let mut client = Self::new(conn, queue.commands);
+            client.raw_statements = restore_statements(queue.raw_statements, client.commands.len());
+            Ok(client)
         }
     }
-}
 
-mod use_case {
-    use super::{
-        context::{Context, ProvideUnitOfWork},
-        domain::{Order, Shop, UnitOfWork, User},
-    };
+    /// Commits a log written by `to_json` in one transaction, e.g. to finish the work queued
+    /// by a process that crashed. Entries naming an aggregate this binary does not know are
+    /// reported as `UnknownAggregate` rather than as a generic deserialization error.
+    pub async fn replay(json: &str, conn: DatabaseConnection) -> anyhow::Result<CommitReport> {
+        let mut log: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(json).with_context(|| "failed to parse command log")?;
+        let entries: Vec<serde_json::Value> =
+            serde_json::from_value(log.remove("commands").unwrap_or_default())
+                .with_context(|| "failed to parse command log")?;
+        let raw_statements: Vec<StoredStatement> =
+            serde_json::from_value(log.remove("raw_statements").unwrap_or(json!([])))
+                .with_context(|| "failed to deserialize raw statements")?;
+        let commands = entries
+            .into_iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                if let Some(kind) = unknown_aggregate(&entry) {
+                    return Err(UnitOfWorkError::UnknownAggregate { index, kind }.into());
+                }
+                serde_json::from_value(entry)
+                    .with_context(|| format!("failed to deserialize command {}", index + 1))
+            })
+            .collect::<anyhow::Result<Vec<Command>>>()?;
+        let mut client = DatabaseClient::new(conn, commands);
+        client.raw_statements = restore_statements(raw_statements, client.commands.len());
+        client.commit().await
+    }
 
-    #[allow(unused)]
-    async fn use_case(context: Context) -> anyhow::Result<()> {
-        let mut uow = context.provide();
+    /// `Aggregate` is externally tagged, so the tag is the variant name, which is also the
+    /// `Debug` output of the matching `AggregateKind`.
+    fn unknown_aggregate(entry: &serde_json::Value) -> Option<String> {
+        let tag = entry.get("aggregate")?.as_object()?.keys().next()?;
+        let known = AggregateKind::ALL
+            .iter()
+            .any(|kind| format!("{:?}", kind) == *tag);
+        (!known).then(|| tag.clone())
+    }
 
-        uow.update(User::new());
-        uow.update(Shop::new());
-        uow.create(Order::new());
-        uow.commit().await?;
+    pub struct DatabaseClientBuilder<A = Aggregate> {
+        conn: Option<DatabaseConnection>,
+        max_commands: Option<usize>,
+        isolation: Option<IsolationLevel>,
+        dry_run: bool,
+        event_store: Option<EventStore>,
+        defer_constraints: bool,
+        statement_timeout_ms: Option<u64>,
+        actor: Option<ActorId>,
+        empty_commit: EmptyCommitPolicy,
+        delete_before_create: bool,
+        tenant: Option<TenantId>,
+        connection_id: Option<ConnectionId>,
+        aggregate: PhantomData<A>,
+    }
 
-        Ok(())
+    impl<A: Dispatch> DatabaseClientBuilder<A> {
+        pub fn conn(mut self, conn: DatabaseConnection) -> Self {
+            self.conn = Some(conn);
+            self
+        }
+
+        pub fn max_commands(mut self, limit: usize) -> Self {
+            self.max_commands = Some(limit);
+            self
+        }
+
+        pub fn isolation(mut self, level: IsolationLevel) -> Self {
+            self.isolation = Some(level);
+            self
+        }
+
+        pub fn dry_run(mut self, enabled: bool) -> Self {
+            self.dry_run = enabled;
+            self
+        }
+
+        pub fn event_store(mut self, store: EventStore) -> Self {
+            self.event_store = Some(store);
+            self
+        }
+
+        /// Defers foreign key checks to the end of each commit, so interdependent rows can
+        /// be inserted in any order. Only Postgres supports this; elsewhere a warning is
+        /// logged and the checks stay immediate.
+        pub fn defer_constraints(mut self, enabled: bool) -> Self {
+            self.defer_constraints = enabled;
+            self
+        }
+
+        /// Has the database cancel any statement of a commit that runs longer than `ms`, on
+        /// top of any client-side timeout. Only applied on Postgres.
+        pub fn statement_timeout_ms(mut self, ms: u64) -> Self {
+            self.statement_timeout_ms = Some(ms);
+            self
+        }
+
+        /// Writes `actor` into the `created_by` column of every row a commit creates and
+        /// the `updated_by` column of every row it updates or upserts, as part of the same
+        /// statement. Aggregates defined elsewhere get it through `Dispatch::apply_as`;
+        /// `explain` previews the statements without it.
+        pub fn with_actor(mut self, actor: ActorId) -> Self {
+            self.actor = Some(actor);
+            self
+        }
+
+        pub fn empty_commit_policy(mut self, policy: EmptyCommitPolicy) -> Self {
+            self.empty_commit = policy;
+            self
+        }
+
+        /// Runs a delete ahead of the creates of the same kind staged just before it, so a
+        /// unique value can be moved from one row to another in a single commit. Off by
+        /// default, as it changes the staging order.
+        pub fn delete_before_create(mut self, enabled: bool) -> Self {
+            self.delete_before_create = enabled;
+            self
+        }
+
+        /// Refuses, as a whole, any commit that stages an aggregate of another tenant or of
+        /// none, so one tenant's request cannot touch another's rows.
+        pub fn tenant(mut self, tenant: TenantId) -> Self {
+            self.tenant = Some(tenant);
+            self
+        }
+
+        /// Marks the client as provided from the connection `id`, so `absorb` refuses a
+        /// client marked with another one.
+        pub fn connection_id(mut self, id: ConnectionId) -> Self {
+            self.connection_id = Some(id);
+            self
+        }
+
+        pub fn build(self) -> anyhow::Result<DatabaseClient<A>> {
+            let conn = self
+                .conn
+                .with_context(|| "DatabaseClient requires a connection")?;
+            let mut client = DatabaseClient::new(conn, vec![]);
+            client.max_commands = self.max_commands;
+            client.isolation = self.isolation;
+            client.dry_run = self.dry_run;
+            client.event_store = self.event_store;
+            client.defer_constraints = self.defer_constraints;
+            client.statement_timeout_ms = self.statement_timeout_ms;
+            client.actor = self.actor;
+            client.empty_commit = self.empty_commit;
+            client.delete_before_create = self.delete_before_create;
+            client.tenant = self.tenant;
+            client.connection_id = self.connection_id;
+            Ok(client)
+        }
+    }
+
+    impl<A: Dispatch> DatabaseClient<A> {
+        pub fn builder() -> DatabaseClientBuilder<A> {
+            DatabaseClientBuilder {
+                conn: None,
+                max_commands: None,
+                isolation: None,
+                dry_run: false,
+                event_store: None,
+                defer_constraints: false,
+                statement_timeout_ms: None,
+                actor: None,
+                empty_commit: EmptyCommitPolicy::Ok,
+                delete_before_create: false,
+                tenant: None,
+                connection_id: None,
+                aggregate: PhantomData,
+            }
+        }
+
+        /// Shorthand for a builder with only a connection and `tenant` set.
+        pub fn for_tenant(conn: DatabaseConnection, tenant: TenantId) -> Self {
+            Self::builder()
+                .conn(conn)
+                .tenant(tenant)
+                .build()
+                .expect("connection is set")
+        }
+
+        /// Sends every executed `Change` to `tx` once a commit has landed, for an async event
+        /// processor. A full or closed channel is only logged, as the data is already durable
+        /// and a commit must not wait on the processor. Flushes
+        /// through `from_txn` publish nothing, since the owner may still roll back.
+        pub fn with_event_sink(mut self, tx: mpsc::Sender<Change>) -> Self {
+            self.event_sink = Some(tx);
+            self
+        }
+
+        /// Hands commits to `executor` instead of opening a transaction. Raw statements,
+        /// hooks, the event store and the other transaction settings are not applied.
+        pub fn with_executor(mut self, executor: Arc<dyn CommandExecutor<A>>) -> Self {
+            self.executor = Some(executor);
+            self
+        }
+
+        fn backend(&self) -> DatabaseBackend {
+            self.backend
+                .unwrap_or_else(|| self.conn.get_database_backend())
+        }
+
+        /// Appends `other`'s queue to this one, keeping order, together with its raw
+        /// statements, events and callbacks; its settings are dropped. A client of another
+        /// connection is refused. sea_orm cannot tell two pools apart, so this relies on the
+        /// `ConnectionId` a `Context` marks its clients with; for a client built without
+        /// one, only the database backends are compared.
+        pub fn absorb(&mut self, mut other: DatabaseClient<A>) -> anyhow::Result<()> {
+            if self.backend() != other.backend() {
+                anyhow::bail!("cannot absorb a client of another database backend");
+            }
+            if let (Some(ours), Some(theirs)) = (self.connection_id, other.connection_id) {
+                if ours != theirs {
+                    anyhow::bail!("cannot absorb a client of another connection");
+                }
+            }
+            if let Some(limit) = self.max_commands {
+                if self.pending_len() + other.pending_len() > limit {
+                    return Err(UnitOfWorkError::QueueFull(limit).into());
+                }
+            }
+            let offset = self.commands.len();
+            self.commands.append(&mut other.commands);
+            for mut raw in other.raw_statements {
+                raw.position += offset;
+                self.raw_statements.push(raw);
+            }
+            self.events.append(&mut other.events);
+            self.callbacks
+                .get_mut()
+                .unwrap()
+                .append(other.callbacks.get_mut().unwrap());
+            Ok(())
+        }
+
+        /// Whether a command of `op` on an aggregate of `kind` is queued, e.g. to avoid
+        /// staging a second create for the same kind.
+        pub fn has_pending(&self, kind: AggregateKind, op: DBOperation) -> bool {
+            self.commands.iter().any(|command| {
+                command.db_operation == op && command.aggregate.kind() == kind.as_str()
+            })
+        }
+
+        pub fn pending_len(&self) -> usize {
+            self.commands.len() + self.raw_statements.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.commands.is_empty() && self.raw_statements.is_empty()
+        }
+
+        /// Rough upper bound on the rows the next commit will write, for admission control:
+        /// one per command, and `RAW_STATEMENT_ROWS` per raw statement.
+        pub fn estimated_write_rows(&self) -> usize {
+            self.commands.len() + self.raw_statements.len() * RAW_STATEMENT_ROWS
+        }
+
+        pub fn clear(&mut self) {
+            self.commands.clear();
+            self.raw_statements.clear();
+        }
+
+        pub fn snapshot(&self) -> QueueSnapshot<A> {
+            QueueSnapshot {
+                commands: self.commands.clone(),
+                raw_statements: self.raw_statements.clone(),
+            }
+        }
+
+        /// Replaces the queue with `snap`, discarding whatever was staged since it was taken.
+        /// Recorded events, callbacks and hooks are left as they are.
+        pub fn restore(&mut self, snap: QueueSnapshot<A>) {
+            self.commands = snap.commands;
+            self.raw_statements = snap.raw_statements;
+        }
+
+        /// Stages a raw statement, which runs inside the same transaction where it was
+        /// staged: after every command staged before it and before every command staged after
+        /// it. Priorities, create ordering and batching only rearrange the commands between
+        /// two raw statements. See `RawStatement` for the injection risk.
+        pub fn execute_raw(
+            &mut self,
+            sql: impl Into<String>,
+            params: Vec<Value>,
+        ) -> anyhow::Result<()> {
+            self.check_room()?;
+            let mut raw = RawStatement::new(sql.into(), params);
+            raw.position = self.commands.len();
+            self.raw_statements.push(raw);
+            Ok(())
+        }
+
+        /// The raw statements stay staged, now ahead of any command staged later.
+        pub fn take_commands(&mut self) -> Vec<Command<A>> {
+            for raw in &mut self.raw_statements {
+                raw.position = 0;
+            }
+            self.commands.drain(..).collect()
+        }
+
+        pub fn commands(&self) -> &[Command<A>] {
+            &self.commands
+        }
+
+        /// Renders the queue as e.g. "3 commands: 1 create user, 1 update shop, 1 create
+        /// order", counting each operation/kind pair in order of first appearance.
+        pub fn summary(&self) -> String {
+            let mut counts: Vec<(DBOperation, &str, usize)> = Vec::new();
+            for command in &self.commands {
+                let kind = command.aggregate.kind();
+                match counts
+                    .iter_mut()
+                    .find(|(op, k, _)| *op == command.db_operation && *k == kind)
+                {
+                    Some((_, _, count)) => *count += 1,
+                    None => counts.push((command.db_operation, kind, 1)),
+                }
+            }
+            let noun = if self.commands.len() == 1 {
+                "command"
+            } else {
+                "commands"
+            };
+            if counts.is_empty() {
+                return format!("{} {}", self.commands.len(), noun);
+            }
+            let parts = counts
+                .iter()
+                .map(|(op, kind, count)| format!("{} {} {}", count, op, kind))
+                .collect::<Vec<_>>();
+            format!("{} {}: {}", self.commands.len(), noun, parts.join(", "))
+        }
+
+        pub fn record_event(&mut self, event: DomainEvent) {
+            self.events.push(event);
+        }
+
+        pub fn add_validator(&mut self, f: Validator<A>) {
+            self.validators.push(f);
+        }
+
+        pub fn on_commit(&mut self, f: Callback) {
+            self.callbacks.get_mut().unwrap().push(f);
+        }
+
+        /// Runs `hook` inside the commit's transaction after every command on an aggregate
+        /// of `kind`, whatever its operation, once per aggregate in a batched create.
+        pub fn register_hook(&mut self, kind: impl Into<&'static str>, hook: Hook) {
+            self.hooks.push((kind.into(), Arc::from(hook)));
+        }
+
+        pub fn set_max_commands(&mut self, limit: Option<usize>) {
+            self.max_commands = limit;
+        }
+
+        /// Stages an update only when `new` differs from `old`, so unchanged rows are not
+        /// written. Returns whether a command was queued.
+        pub fn update_if_changed<T>(&mut self, old: &T, new: T) -> anyhow::Result<bool>
+        where
+            T: Into<A> + PartialEq,
+        {
+            if *old == new {
+                return Ok(false);
+            }
+            self.stage(Command::new(new.into(), DBOperation::Update))?;
+            Ok(true)
+        }
+
+        /// Logs a warning for every command whose dispatch takes longer than `threshold`.
+        pub fn set_slow_threshold(&mut self, threshold: Option<Duration>) {
+            self.slow_threshold = threshold;
+        }
+
+        /// Cancels a create followed by a delete of the same aggregate, and folds updates
+        /// that follow a create into the create itself.
+        pub fn optimize(&mut self) {
+            let mut commands = self.commands.drain(..).map(Some).collect::<Vec<_>>();
+            for i in 0..commands.len() {
+                match &commands[i] {
+                    Some(command) if command.db_operation == DBOperation::Create => {}
+                    _ => continue,
+                }
+                for j in i + 1..commands.len() {
+                    let (created, later) = match (&commands[i], &commands[j]) {
+                        (Some(created), Some(later)) => (created, later),
+                        _ => continue,
+                    };
+                    if !created.aggregate.is_same_as(&later.aggregate) {
+                        continue;
+                    }
+                    match later.db_operation {
+                        DBOperation::Update => {
+                            let later = commands[j].take().unwrap();
+                            let created = commands[i].as_mut().unwrap();
+                            created.aggregate = later.aggregate;
+                            created.metadata.extend(later.metadata);
+                        }
+                        DBOperation::Delete => {
+                            commands[i] = None;
+                            commands[j] = None;
+                            break;
+                        }
+                        DBOperation::Create | DBOperation::Upsert => break,
+                    }
+                }
+            }
+            for raw in &mut self.raw_statements {
+                raw.position = commands[..raw.position]
+                    .iter()
+                    .filter(|command| command.is_some())
+                    .count();
+            }
+            self.commands = commands.into_iter().flatten().collect();
+        }
+
+        /// Unlike the practical design, whose single `DatabaseTransaction` cannot be re-run,
+        /// the staged commands are kept in memory so a serialization failure can be retried
+        /// by replaying them in a fresh transaction.
+        pub async fn commit_with_retry(
+            &mut self,
+            max_attempts: u32,
+        ) -> anyhow::Result<CommitReport> {
+            self.commit_retrying(max_attempts, is_serialization_failure)
+                .await
+        }
+
+        /// Same replay strategy as `commit_with_retry`, but only for deadlocks (SQLSTATE
+        /// 40P01); any other database error fails immediately.
+        pub async fn commit_with_deadlock_retry(
+            &mut self,
+            attempts: u32,
+        ) -> anyhow::Result<CommitReport> {
+            self.commit_retrying(attempts, is_deadlock).await
+        }
+
+        async fn commit_retrying(
+            &mut self,
+            max_attempts: u32,
+            should_retry: fn(&TransactionError<CommitError>) -> bool,
+        ) -> anyhow::Result<CommitReport> {
+            if self.is_empty() || self.dry_run {
+                return self.commit().await;
+            }
+            self.validate()?;
+            let mut delay = Duration::from_millis(10);
+            let mut attempt = 1;
+            loop {
+                match self
+                    .run(self.commands.clone(), self.raw_statements.clone())
+                    .await
+                {
+                    Ok(report) => return Ok(self.finish(report)),
+                    Err(err) if attempt < max_attempts && should_retry(&err) => {
+                        tokio::time::sleep(delay).await;
+                        delay *= 2;
+                        attempt += 1;
+                    }
+                    Err(err) => {
+                        return Err(err).with_context(|| {
+                            format!(
+                                "failed to commit transaction after {} attempt{}",
+                                attempt,
+                                if attempt == 1 { "" } else { "s" }
+                            )
+                        })
+                    }
+                }
+            }
+        }
+
+        /// Stages a create and commits the whole queue once it holds `threshold` commands,
+        /// keeping memory bounded during streaming imports. Returns whether a flush happened.
+        pub async fn create_and_maybe_flush<T>(
+            &mut self,
+            aggregate: T,
+            threshold: usize,
+        ) -> anyhow::Result<bool>
+        where
+            T: Into<A> + Send,
+        {
+            self.create(aggregate)?;
+            if self.pending_len() < threshold {
+                return Ok(false);
+            }
+            self.commit().await?;
+            Ok(true)
+        }
+
+        /// Optimizes the queue and commits it, returning what actually executed for a
+        /// change-data-capture feed. Pairs cancelled by `optimize` and duplicates dropped by
+        /// idempotency key do not appear. The `EmptyCommitPolicy` applies to the queue as
+        /// staged, so one whose pairs all cancel out is not an empty commit.
+        pub async fn commit_with_changelog(&mut self) -> anyhow::Result<Vec<Change>> {
+            if self.is_empty() {
+                return Ok(self.complete_empty()?.changes);
+            }
+            self.optimize();
+            Ok(self
+                .commit_with_policy(EmptyCommitPolicy::Ok)
+                .await?
+                .changes)
+        }
+
+        /// `commit` with `policy` in place of the configured `EmptyCommitPolicy`.
+        async fn commit_with_policy(
+            &mut self,
+            policy: EmptyCommitPolicy,
+        ) -> anyhow::Result<CommitReport> {
+            let configured = std::mem::replace(&mut self.empty_commit, policy);
+            let result = self.commit().await;
+            self.empty_commit = configured;
+            result
+        }
+
+        /// Commits as the returned stream is polled, yielding each `Change` as soon as its
+        /// command has run inside the transaction, so large imports can report progress.
+        /// Nothing is durable until the stream ends: the commit happens after the last change
+        /// is yielded, and any failure, including of the commit itself, arrives as a final
+        /// `Err`. Events are discarded and callbacks run once the commit lands.
+        ///
+        /// The transaction runs on the caller's task, so dropping the stream before it ends
+        /// rolls it back.
+        pub async fn commit_stream(&mut self) -> impl Stream<Item = anyhow::Result<Change>> {
+            if let Err(err) = self.validate() {
+                return CommitStream::failed(err);
+            }
+            if self.dry_run {
+                self.log_commands();
+                return CommitStream::done();
+            }
+            if self.is_empty() {
+                return match self.complete_empty() {
+                    Ok(_) => CommitStream::done(),
+                    Err(err) => CommitStream::failed(err),
+                };
+            }
+            let commands = self.commands.drain(..).collect::<Vec<_>>();
+            let raw_statements = std::mem::take(&mut self.raw_statements);
+            let callbacks = std::mem::take(self.callbacks.get_mut().unwrap());
+            self.events.clear();
+            let (progress, changes) = mpsc::unbounded_channel();
+            let commit = self.run_detached(commands, raw_statements, Some(progress));
+            let sink = self.event_sink.clone();
+            let commit = async move {
+                let report = commit
+                    .await
+                    .with_context(|| "failed to commit transaction")?;
+                callbacks.into_iter().for_each(|callback| callback());
+                publish(sink.as_ref(), &report.changes);
+                Ok(())
+            };
+            CommitStream {
+                changes,
+                commit: Some(Box::pin(commit)),
+                failure: None,
+            }
+        }
+
+        /// Previews the SQL each staged command and raw statement would run, in staging
+        /// order, without executing anything. Commands whose SQL cannot be known up front are
+        /// described in a comment instead.
+        pub fn explain(&self) -> Vec<String> {
+            let backend = self.backend();
+            let explain_command = |command: &Command<A>| {
+                command
+                    .aggregate
+                    .explain(command.db_operation, self.actor, backend)
+                    .unwrap_or_else(|| {
+                        format!(
+                            "-- {} {}: no SQL preview",
+                            command.db_operation,
+                            command.aggregate.kind()
+                        )
+                    })
+            };
+            let mut sql = Vec::with_capacity(self.pending_len());
+            let mut explained = 0;
+            for raw in &self.raw_statements {
+                sql.extend(
+                    self.commands[explained..raw.position]
+                        .iter()
+                        .map(explain_command),
+                );
+                explained = raw.position;
+                sql.push(
+                    Statement::from_sql_and_values(backend, raw.sql.clone(), raw.values.clone())
+                        .to_string(),
+                );
+            }
+            sql.extend(self.commands[explained..].iter().map(explain_command));
+            sql
+        }
+
+        /// Runs each command in its own savepoint inside one transaction, so a failing command
+        /// is rolled back on its own and the rest still commit. Results are in staging order,
+        /// with a duplicate dropped by idempotency key reported as `Ok`; commands run in the
+        /// order `commit` would use, but creates are not batched. Validation and dry-run work
+        /// as in `commit`. Raw statements cannot be reported on per command, so when any are
+        /// staged nothing runs, every command reports an error and the queue is left as it is.
+        /// If the outer transaction cannot begin or commit, every command reports that error.
+        pub async fn commit_best_effort(&mut self) -> Vec<Result<(), DbErr>> {
+            let count = self.commands.len();
+            let fail_all = |message: String| {
+                (0..count)
+                    .map(|_| Err(DbErr::Custom(message.clone())))
+                    .collect::<Vec<_>>()
+            };
+            if let Err(err) = self.validate() {
+                return fail_all(format!("{:#}", err));
+            }
+            if !self.raw_statements.is_empty() {
+                return fail_all("commit_best_effort cannot run raw statements".to_string());
+            }
+            if self.dry_run {
+                self.log_commands();
+                return (0..count).map(|_| Ok(())).collect();
+            }
+            let commands = order(self.commands.drain(..).collect(), self.delete_before_create);
+            let mut results = (0..count).map(|_| Ok(())).collect::<Vec<_>>();
+            let mut report = CommitReport::default();
+            if let Some(executor) = self.executor.clone() {
+                for (index, command) in commands {
+                    match simulate(vec![Step::Single(index, command)], &*executor, None) {
+                        Ok(done) => merge(&mut report, done),
+                        Err(err) => results[index] = Err(err.into_db_err()),
+                    }
+                }
+                self.finish(report);
+                return results;
+            }
+            let txn = match self.conn.begin_with_config(self.isolation, None).await {
+                Ok(txn) => txn,
+                Err(err) => {
+                    self.abandon();
+                    return fail_all(err.to_string());
+                }
+            };
+            match prepare(self.defer_constraints, self.statement_timeout_ms, &txn).await {
+                Ok(setup) => report.stmt_count += setup,
+                Err(err) => {
+                    self.abandon();
+                    return fail_all(err.into_db_err().to_string());
+                }
+            }
+            for (index, command) in commands {
+                let step = Step::Single(index, command);
+                match self.apply_in_savepoint(step, &txn).await {
+                    Ok(done) => merge(&mut report, done),
+                    Err(err) => results[index] = Err(err),
+                }
+            }
+            match txn.commit().await {
+                Ok(()) => {
+                    self.finish(report);
+                    results
+                }
+                Err(err) => {
+                    self.abandon();
+                    fail_all(err.to_string())
+                }
+            }
+        }
+
+        fn step_settings(&self) -> StepSettings<'_> {
+            StepSettings {
+                event_store: self.event_store.as_ref(),
+                hooks: &self.hooks,
+                slow_threshold: self.slow_threshold,
+                actor: self.actor,
+            }
+        }
+
+        async fn apply_in_savepoint(
+            &self,
+            step: Step<A>,
+            txn: &DatabaseTransaction,
+        ) -> Result<CommitReport, DbErr> {
+            let savepoint = txn.begin().await?;
+            let mut report = CommitReport::default();
+            let result = execute_step(step, self.step_settings(), &mut report, &savepoint).await;
+            match result {
+                Ok(()) => {
+                    savepoint.commit().await?;
+                    Ok(report)
+                }
+                Err(err) => {
+                    savepoint.rollback().await?;
+                    Err(err.into_db_err())
+                }
+            }
+        }
+
+        /// On elapse the transaction future is dropped, which lets sea_orm roll it back. The
+        /// staged commands are left intact so the caller can retry.
+        pub async fn commit_with_timeout(&mut self, dur: Duration) -> anyhow::Result<CommitReport> {
+            if self.is_empty() || self.dry_run {
+                return self.commit().await;
+            }
+            self.validate()?;
+            match tokio::time::timeout(
+                dur,
+                self.run(self.commands.clone(), self.raw_statements.clone()),
+            )
+            .await
+            {
+                Ok(report) => {
+                    let report = report.with_context(|| "failed to commit transaction")?;
+                    Ok(self.finish(report))
+                }
+                Err(_) => Err(UnitOfWorkError::Timeout(dur).into()),
+            }
+        }
+
+        /// Like `commit_with_timeout`, but gives up when `token` is cancelled, e.g. on
+        /// shutdown. The staged commands are kept so the work can be retried later.
+        pub async fn commit_with_cancel(
+            &mut self,
+            token: CancellationToken,
+        ) -> anyhow::Result<CommitReport> {
+            if self.is_empty() || self.dry_run {
+                return self.commit().await;
+            }
+            self.validate()?;
+            match token
+                .run_until_cancelled(self.run(self.commands.clone(), self.raw_statements.clone()))
+                .await
+            {
+                Some(report) => {
+                    let report = report.with_context(|| "failed to commit transaction")?;
+                    Ok(self.finish(report))
+                }
+                None => Err(UnitOfWorkError::Cancelled.into()),
+            }
+        }
+
+        fn stage(&mut self, command: Command<A>) -> anyhow::Result<()> {
+            self.check_room()?;
+            self.commands.push(command);
+            Ok(())
+        }
+
+        /// Raw statements count towards the limit like commands, as in `pending_len`.
+        fn check_room(&self) -> Result<(), UnitOfWorkError> {
+            match self.max_commands {
+                Some(limit) if self.pending_len() >= limit => {
+                    Err(UnitOfWorkError::QueueFull(limit))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        /// Every aggregate is checked before the validators run, so one error reports all
+        /// invalid commands together.
+        fn validate(&self) -> anyhow::Result<()> {
+            self.check_tenant()?;
+            let invalid = self
+                .commands
+                .iter()
+                .enumerate()
+                .flat_map(|(index, command)| {
+                    let errors = command.aggregate.validate().err().unwrap_or_default();
+                    errors.into_iter().map(move |err| (index, err))
+                })
+                .collect::<Vec<_>>();
+            if !invalid.is_empty() {
+                return Err(UnitOfWorkError::Invalid(invalid).into());
+            }
+            for validator in &self.validators {
+                validator(&self.commands).with_context(|| "validation failed")?;
+            }
+            Ok(())
+        }
+
+        fn check_tenant(&self) -> Result<(), UnitOfWorkError> {
+            let Some(expected) = self.tenant else {
+                return Ok(());
+            };
+            for (index, command) in self.commands.iter().enumerate() {
+                let found = command.aggregate.tenant_id();
+                if found != Some(expected) {
+                    return Err(UnitOfWorkError::CrossTenant {
+                        index,
+                        expected,
+                        found,
+                    });
+                }
+            }
+            Ok(())
+        }
+
+        /// Nothing is written in dry-run mode, so events and callbacks are discarded along
+        /// with the commands rather than reported as committed.
+        fn log_commands(&mut self) {
+            for (index, command) in self.commands.drain(..).enumerate() {
+                tracing::info!(
+                    index,
+                    operation = %command.db_operation,
+                    aggregate = command.aggregate.kind(),
+                    "dry run"
+                );
+            }
+            for raw in self.raw_statements.drain(..) {
+                tracing::info!(sql = raw.sql, "dry run");
+            }
+            self.abandon();
+        }
+
+        fn complete(&mut self, mut report: CommitReport) -> CommitReport {
+            self.clear();
+            report.result = match report.changes.len() + report.raw_count {
+                0 => CommitResult::Empty,
+                count => CommitResult::Committed { count },
+            };
+            report.events = std::mem::take(&mut self.events);
+            for callback in self.callbacks.get_mut().unwrap().drain(..) {
+                callback();
+            }
+            report
+        }
+
+        /// Finishes a commit with nothing staged, as the configured `EmptyCommitPolicy` says.
+        /// On `Error` events and callbacks are kept, as nothing was committed.
+        fn complete_empty(&mut self) -> anyhow::Result<CommitReport> {
+            match self.empty_commit {
+                EmptyCommitPolicy::Ok => {}
+                EmptyCommitPolicy::Warn => tracing::warn!("committing an empty unit of work"),
+                EmptyCommitPolicy::Error => return Err(UnitOfWorkError::EmptyCommit.into()),
+            }
+            Ok(self.complete(CommitReport::default()))
+        }
+
+        /// `complete` plus publishing to the event sink, for paths where the data is durable.
+        fn finish(&mut self, report: CommitReport) -> CommitReport {
+            let report = self.complete(report);
+            publish(self.event_sink.as_ref(), &report.changes);
+            report
+        }
+
+        fn abandon(&mut self) {
+            self.events.clear();
+            self.callbacks.get_mut().unwrap().clear();
+        }
+
+        async fn run(
+            &self,
+            commands: Vec<Command<A>>,
+            raw_statements: Vec<RawStatement>,
+        ) -> Result<CommitReport, TransactionError<CommitError>> {
+            self.run_detached(commands, raw_statements, None).await
+        }
+
+        /// Everything the transaction needs is cloned up front, so the returned future does
+        /// not borrow `self` and can be spawned.
+        fn run_detached(
+            &self,
+            commands: Vec<Command<A>>,
+            raw_statements: Vec<RawStatement>,
+            progress: Option<Progress>,
+        ) -> impl Future<Output = Result<CommitReport, TransactionError<CommitError>>> + Send + 'static
+        {
+            let (conn, isolation) = (self.conn.clone(), self.isolation);
+            let (event_store, hooks) = (self.event_store.clone(), self.hooks.clone());
+            let slow_threshold = self.slow_threshold;
+            let (defer, timeout_ms) = (self.defer_constraints, self.statement_timeout_ms);
+            let (actor, executor) = (self.actor, self.executor.clone());
+            let delete_first = self.delete_before_create;
+            async move {
+                let steps = plan(commands, raw_statements, delete_first);
+                if let Some(executor) = executor {
+                    return simulate(steps, &*executor, progress.as_ref())
+                        .map_err(TransactionError::Transaction);
+                }
+                conn.transaction_with_config::<_, CommitReport, CommitError>(
+                    |txn| {
+                        Box::pin(async move {
+                            let setup = prepare(defer, timeout_ms, txn).await?;
+                            let settings = StepSettings {
+                                event_store: event_store.as_ref(),
+                                hooks: &hooks,
+                                slow_threshold,
+                                actor,
+                            };
+                            let mut report = flush(steps, settings, progress.as_ref(), txn).await?;
+                            report.stmt_count += setup;
+                            Ok(report)
+                        })
+                    },
+                    isolation,
+                    None,
+                )
+                .await
+            }
+        }
+    }
+
+    /// A unit of work enlisted in a transaction owned by the caller, created with
+    /// `DatabaseClient::from_txn`. It borrows the transaction for `'t`, so the owner cannot
+    /// commit or roll back until this client is dropped; `commit` only flushes the staged
+    /// commands into it, inside a savepoint so a failing flush leaves the outer transaction
+    /// as it was. Committing the outer transaction remains the owner's job.
+    ///
+    /// Staging goes through the inner `DatabaseClient` via `Deref`. Its other commit
+    /// variants open their own transaction and fail here, as there is no connection.
+    pub struct EnlistedClient<'t, A = Aggregate> {
+        client: DatabaseClient<A>,
+        txn: &'t DatabaseTransaction,
+    }
+
+    impl<A: Dispatch> DatabaseClient<A> {
+        pub fn from_txn(txn: &DatabaseTransaction) -> EnlistedClient<'_, A> {
+            let mut client = DatabaseClient::new(DatabaseConnection::Disconnected, vec![]);
+            client.backend = Some(txn.get_database_backend());
+            EnlistedClient { client, txn }
+        }
+    }
+
+    impl<A: Dispatch> EnlistedClient<'_, A> {
+        pub async fn commit(&mut self) -> anyhow::Result<CommitReport> {
+            let client = &mut self.client;
+            client.validate()?;
+            if client.dry_run {
+                client.log_commands();
+                return Ok(CommitReport::default());
+            }
+            if client.is_empty() {
+                return client.complete_empty();
+            }
+            let commands = client.commands.drain(..).collect::<Vec<_>>();
+            let raw_statements = std::mem::take(&mut client.raw_statements);
+            let result = async {
+                let savepoint = self.txn.begin().await?;
+                let report = flush(
+                    plan(commands, raw_statements, client.delete_before_create),
+                    client.step_settings(),
+                    None,
+                    &savepoint,
+                )
+                .await?;
+                savepoint.commit().await?;
+                Ok::<_, anyhow::Error>(report)
+            }
+            .await;
+            match result {
+                Ok(report) => Ok(client.complete(report)),
+                Err(err) => {
+                    client.abandon();
+                    Err(err).with_context(|| "failed to flush into the outer transaction")
+                }
+            }
+        }
+    }
+
+    impl<A> Deref for EnlistedClient<'_, A> {
+        type Target = DatabaseClient<A>;
+
+        fn deref(&self) -> &Self::Target {
+            &self.client
+        }
+    }
+
+    impl<A> DerefMut for EnlistedClient<'_, A> {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.client
+        }
+    }
+
+    /// Never waits on the consumer: a change that does not fit is dropped, so a stalled
+    /// processor cannot hold up commits.
+    fn publish(sink: Option<&mpsc::Sender<Change>>, changes: &[Change]) {
+        let Some(sink) = sink else {
+            return;
+        };
+        for change in changes {
+            match sink.try_send(change.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(change)) => {
+                    tracing::warn!(
+                        ?change,
+                        "event sink is full; committed change was not published"
+                    );
+                }
+                Err(TrySendError::Closed(_)) => {
+                    tracing::warn!("event sink is closed; committed changes were not published");
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn prepare(
+        defer: bool,
+        timeout_ms: Option<u64>,
+        txn: &DatabaseTransaction,
+    ) -> Result<usize, CommitError> {
+        let mut stmt_count = 0;
+        if defer {
+            stmt_count += defer_constraints(txn).await?;
+        }
+        if let Some(ms) = timeout_ms {
+            stmt_count += statement_timeout(txn, ms).await?;
+        }
+        Ok(stmt_count)
+    }
+
+    async fn defer_constraints(txn: &DatabaseTransaction) -> Result<usize, CommitError> {
+        match txn.get_database_backend() {
+            DatabaseBackend::Postgres => {
+                txn.execute_unprepared("SET CONSTRAINTS ALL DEFERRED")
+                    .await
+                    .map_err(CommitError::Setup)?;
+                Ok(1)
+            }
+            backend => {
+                tracing::warn!(?backend, "deferred constraints are not supported; ignoring");
+                Ok(0)
+            }
+        }
+    }
+
+    async fn statement_timeout(txn: &DatabaseTransaction, ms: u64) -> Result<usize, CommitError> {
+        match txn.get_database_backend() {
+            DatabaseBackend::Postgres => {
+                txn.execute_unprepared(&format!("SET LOCAL statement_timeout = {}", ms))
+                    .await
+                    .map_err(CommitError::Setup)?;
+                Ok(1)
+            }
+            backend => {
+                tracing::debug!(?backend, "statement_timeout is not supported; ignoring");
+                Ok(0)
+            }
+        }
+    }
+
+    fn simulate<A: Dispatch>(
+        steps: Vec<Step<A>>,
+        executor: &dyn CommandExecutor<A>,
+        progress: Option<&Progress>,
+    ) -> Result<CommitReport, CommitError> {
+        let mut report = CommitReport::default();
+        for step in steps {
+            let (indices, operation, aggregates, metadata) = match step {
+                Step::CreateMany(indices, batch, metadata) => {
+                    (indices, DBOperation::Create, batch, metadata)
+                }
+                Step::Single(index, command) => (
+                    vec![index],
+                    command.db_operation,
+                    vec![command.aggregate],
+                    vec![command.metadata],
+                ),
+                Step::Raw(..) => continue,
+            };
+            let kind = aggregates[0].kind();
+            executor
+                .execute(operation, &aggregates)
+                .map_err(|source| CommandError::new(indices[0], kind, operation, source))?;
+            report.stmt_count += 1;
+            for ((index, aggregate), metadata) in indices.into_iter().zip(&aggregates).zip(metadata)
+            {
+                let change = Change {
+                    kind,
+                    operation,
+                    id: aggregate.aggregate_id(),
+                    metadata,
+                };
+                if let (DBOperation::Create, Some(id)) = (operation, change.id) {
+                    report.created_ids.push((index, id));
+                }
+                if let Some(progress) = progress {
+                    let _ = progress.send(Ok(change.clone()));
+                }
+                report.changes.push(change);
+            }
+        }
+        Ok(report)
+    }
+
+    /// What each step of a commit runs with, borrowed from the client.
+    #[derive(Clone, Copy)]
+    struct StepSettings<'a> {
+        event_store: Option<&'a EventStore>,
+        hooks: &'a [(&'static str, Arc<HookFn>)],
+        slow_threshold: Option<Duration>,
+        actor: Option<ActorId>,
+    }
+
+    async fn flush<A: Dispatch>(
+        steps: Vec<Step<A>>,
+        settings: StepSettings<'_>,
+        progress: Option<&Progress>,
+        txn: &DatabaseTransaction,
+    ) -> Result<CommitReport, CommitError> {
+        let mut report = CommitReport::default();
+        for step in steps {
+            let executed = report.changes.len();
+            execute_step(step, settings, &mut report, txn).await?;
+            if let Some(progress) = progress {
+                for change in &report.changes[executed..] {
+                    // The receiver may be gone; the commit goes ahead regardless.
+                    let _ = progress.send(Ok(change.clone()));
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    async fn execute_step<A: Dispatch>(
+        step: Step<A>,
+        settings: StepSettings<'_>,
+        report: &mut CommitReport,
+        txn: &DatabaseTransaction,
+    ) -> Result<(), CommitError> {
+        let StepSettings {
+            event_store,
+            hooks,
+            slow_threshold,
+            actor,
+        } = settings;
+        match step {
+            Step::CreateMany(indices, batch, metadata) => {
+                let (index, kind) = (indices[0], batch[0].kind());
+                let fail = |source| CommandError::new(index, kind, DBOperation::Create, source);
+                if let Some(store) = event_store {
+                    for aggregate in &batch {
+                        store
+                            .append(DBOperation::Create, aggregate, txn)
+                            .await
+                            .map_err(fail)?;
+                        report.stmt_count += 1;
+                    }
+                }
+                let changes = batch
+                    .iter()
+                    .zip(metadata)
+                    .map(|(aggregate, metadata)| Change {
+                        kind,
+                        operation: DBOperation::Create,
+                        id: aggregate.aggregate_id(),
+                        metadata,
+                    })
+                    .collect::<Vec<_>>();
+                let started = Instant::now();
+                let ids = A::apply_creates_as(batch, actor, txn).await.map_err(fail)?;
+                observe(
+                    slow_threshold,
+                    index,
+                    kind,
+                    DBOperation::Create,
+                    started.elapsed(),
+                );
+                report.stmt_count += 1;
+                for change in &changes {
+                    run_hooks(hooks, kind, DBOperation::Create, change.id, txn)
+                        .await
+                        .map_err(fail)?;
+                }
+                report.created_ids.extend(indices.into_iter().zip(ids));
+                report.changes.extend(changes);
+            }
+            Step::Single(index, command) => {
+                let (kind, operation) = (command.aggregate.kind(), command.db_operation);
+                let fail = |source| CommandError::new(index, kind, operation, source);
+                if let Some(store) = event_store {
+                    store
+                        .append(operation, &command.aggregate, txn)
+                        .await
+                        .map_err(fail)?;
+                    report.stmt_count += 1;
+                }
+                let change = Change {
+                    kind,
+                    operation,
+                    id: command.aggregate.aggregate_id(),
+                    metadata: command.metadata,
+                };
+                let started = Instant::now();
+                let id = command
+                    .aggregate
+                    .apply_as(operation, actor, txn)
+                    .await
+                    .map_err(fail)?;
+                observe(slow_threshold, index, kind, operation, started.elapsed());
+                report.stmt_count += 1;
+                run_hooks(hooks, kind, operation, change.id, txn)
+                    .await
+                    .map_err(fail)?;
+                if let Some(id) = id {
+                    report.created_ids.push((index, id));
+                }
+                report.changes.push(change);
+            }
+            Step::Raw(index, raw) => {
+                let stmt =
+                    Statement::from_sql_and_values(txn.get_database_backend(), raw.sql, raw.values);
+                txn.execute(stmt)
+                    .await
+                    .map_err(|source| CommitError::Raw { index, source })?;
+                report.stmt_count += 1;
+                report.raw_count += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn observe(
+        threshold: Option<Duration>,
+        index: usize,
+        kind: &str,
+        operation: DBOperation,
+        elapsed: Duration,
+    ) {
+        if let Some(kind) = AggregateKind::from_name(kind) {
+            metrics::record_latency(kind, operation, elapsed);
+        }
+        if threshold.is_some_and(|threshold| elapsed > threshold) {
+            tracing::warn!(index, aggregate = kind, ?elapsed, "slow command");
+        }
+    }
+
+    async fn run_hooks(
+        hooks: &[(&'static str, Arc<HookFn>)],
+        kind: &str,
+        operation: DBOperation,
+        id: Option<i64>,
+        txn: &DatabaseTransaction,
+    ) -> Result<(), DbErr> {
+        for (_, hook) in hooks.iter().filter(|(k, _)| *k == kind) {
+            hook(txn, operation, id).await?;
+        }
+        Ok(())
+    }
+
+    #[derive(Debug, Error)]
+    pub enum CommitError {
+        #[error(transparent)]
+        Command(#[from] CommandError),
+        #[error("raw statement {}: failed", index + 1)]
+        Raw {
+            index: usize,
+            #[source]
+            source: DbErr,
+        },
+        #[error("failed to prepare the transaction")]
+        Setup(#[source] DbErr),
+    }
+
+    /// Adds what `other` executed to `report`, for commits that run their steps one by one.
+    fn merge(report: &mut CommitReport, other: CommitReport) {
+        report.created_ids.extend(other.created_ids);
+        report.events.extend(other.events);
+        report.stmt_count += other.stmt_count;
+        report.raw_count += other.raw_count;
+        report.changes.extend(other.changes);
+    }
+
+    impl CommitError {
+        fn into_db_err(self) -> DbErr {
+            match self {
+                Self::Command(e) => e.source,
+                Self::Raw { source, .. } => source,
+                Self::Setup(source) => source,
+            }
+        }
+
+        fn db_err(&self) -> &DbErr {
+            match self {
+                Self::Command(e) => &e.source,
+                Self::Raw { source, .. } => source,
+                Self::Setup(source) => source,
+            }
+        }
+    }
+
+    /// Identifies the command that failed inside a commit. `index` is the staging index, as
+    /// in `CommitReport::created_ids`; the message numbers commands from 1.
+    #[derive(Debug, Error, new)]
+    #[error("command {}: {operation} {kind} failed", index + 1)]
+    pub struct CommandError {
+        pub index: usize,
+        pub kind: &'static str,
+        pub operation: DBOperation,
+        #[source]
+        pub source: DbErr,
+    }
+
+    fn sqlstate(err: &DbErr) -> Option<String> {
+        match err {
+            DbErr::Exec(RuntimeErr::SqlxError(e)) | DbErr::Query(RuntimeErr::SqlxError(e)) => e
+                .as_database_error()
+                .and_then(|e| e.code())
+                .map(|code| code.into_owned()),
+            _ => None,
+        }
+    }
+
+    fn transaction_sqlstate(err: &TransactionError<CommitError>) -> Option<String> {
+        match err {
+            TransactionError::Connection(e) => sqlstate(e),
+            TransactionError::Transaction(e) => sqlstate(e.db_err()),
+        }
+    }
+
+    fn is_serialization_failure(err: &TransactionError<CommitError>) -> bool {
+        transaction_sqlstate(err).as_deref() == Some("40001")
+    }
+
+    fn is_deadlock(err: &TransactionError<CommitError>) -> bool {
+        transaction_sqlstate(err).as_deref() == Some("40P01")
+    }
+
+    #[async_trait]
+    impl<A: Dispatch> UnitOfWork<A> for DatabaseClient<A> {
+        fn create<T>(&mut self, aggregate: T) -> anyhow::Result<()>
+        where
+            T: Into<A>,
+        {
+            self.stage(Command::new(aggregate.into(), DBOperation::Create))
+        }
+
+        fn update<T>(&mut self, aggregate: T) -> anyhow::Result<()>
+        where
+            T: Into<A>,
+        {
+            self.stage(Command::new(aggregate.into(), DBOperation::Update))
+        }
+
+        fn delete<T>(&mut self, aggregate: T) -> anyhow::Result<()>
+        where
+            T: Into<A>,
+        {
+            self.stage(Command::new(aggregate.into(), DBOperation::Delete))
+        }
+
+        fn upsert<T>(&mut self, aggregate: T) -> anyhow::Result<()>
+        where
+            T: Into<A>,
+        {
+            self.stage(Command::new(aggregate.into(), DBOperation::Upsert))
+        }
+
+        fn create_with_key<T>(&mut self, aggregate: T, key: String) -> anyhow::Result<()>
+        where
+            T: Into<A>,
+        {
+            self.stage(
+                Command::new(aggregate.into(), DBOperation::Create).with_idempotency_key(key),
+            )
+        }
+
+        fn update_with_key<T>(&mut self, aggregate: T, key: String) -> anyhow::Result<()>
+        where
+            T: Into<A>,
+        {
+            self.stage(
+                Command::new(aggregate.into(), DBOperation::Update).with_idempotency_key(key),
+            )
+        }
+
+        fn create_with_priority<T>(&mut self, aggregate: T, priority: i32) -> anyhow::Result<()>
+        where
+            T: Into<A>,
+        {
+            self.stage(Command::new(aggregate.into(), DBOperation::Create).with_priority(priority))
+        }
+
+        fn update_with_priority<T>(&mut self, aggregate: T, priority: i32) -> anyhow::Result<()>
+        where
+            T: Into<A>,
+        {
+            self.stage(Command::new(aggregate.into(), DBOperation::Update).with_priority(priority))
+        }
+
+        fn delete_with_priority<T>(&mut self, aggregate: T, priority: i32) -> anyhow::Result<()>
+        where
+            T: Into<A>,
+        {
+            self.stage(Command::new(aggregate.into(), DBOperation::Delete).with_priority(priority))
+        }
+
+        fn create_with_metadata<T>(
+            &mut self,
+            aggregate: T,
+            metadata: Metadata,
+        ) -> anyhow::Result<()>
+        where
+            T: Into<A>,
+        {
+            self.stage(Command::new(aggregate.into(), DBOperation::Create).with_metadata(metadata))
+        }
+
+        fn update_with_metadata<T>(
+            &mut self,
+            aggregate: T,
+            metadata: Metadata,
+        ) -> anyhow::Result<()>
+        where
+            T: Into<A>,
+        {
+            self.stage(Command::new(aggregate.into(), DBOperation::Update).with_metadata(metadata))
+        }
+
+        fn delete_with_metadata<T>(
+            &mut self,
+            aggregate: T,
+            metadata: Metadata,
+        ) -> anyhow::Result<()>
+        where
+            T: Into<A>,
+        {
+            self.stage(Command::new(aggregate.into(), DBOperation::Delete).with_metadata(metadata))
+        }
+
+        async fn commit(&mut self) -> anyhow::Result<CommitReport> {
+            self.validate()?;
+            if self.dry_run {
+                self.log_commands();
+                return Ok(CommitReport::default());
+            }
+            if self.is_empty() {
+                return self.complete_empty();
+            }
+            let commands = self.commands.drain(..).collect::<Vec<_>>();
+            let raw_statements = std::mem::take(&mut self.raw_statements);
+            match self.run(commands, raw_statements).await {
+                Ok(report) => Ok(self.finish(report)),
+                Err(err) => {
+                    self.abandon();
+                    Err(err).with_context(|| "failed to commit transaction")
+                }
+            }
+        }
+    }
+
+    /// `Raw` carries the raw statement's index among the raw statements.
+    enum Step<A> {
+        CreateMany(Vec<usize>, Vec<A>, Vec<Metadata>),
+        Single(usize, Command<A>),
+        Raw(usize, RawStatement),
+    }
+
+    /// Each raw statement splits the commands where it was staged; the commands between two
+    /// of them are ordered and batched on their own, so none is moved across one.
+    fn plan<A: Dispatch>(
+        commands: Vec<Command<A>>,
+        raw_statements: Vec<RawStatement>,
+        delete_first: bool,
+    ) -> Vec<Step<A>> {
+        let mut commands = dedupe(commands.into_iter().enumerate().collect())
+            .into_iter()
+            .peekable();
+        let mut steps = Vec::new();
+        for (index, raw) in raw_statements.into_iter().enumerate() {
+            let position = raw.position;
+            let before = std::iter::from_fn(|| commands.next_if(|(i, _)| *i < position));
+            steps.extend(group_creates(arrange(before.collect(), delete_first)));
+            steps.push(Step::Raw(index, raw));
+        }
+        steps.extend(group_creates(arrange(commands.collect(), delete_first)));
+        steps
+    }
+
+    /// Drops duplicates and orders the commands as a commit runs them, paired with their
+    /// staging index.
+    fn order<A: Dispatch>(
+        commands: Vec<Command<A>>,
+        delete_first: bool,
+    ) -> Vec<(usize, Command<A>)> {
+        arrange(
+            dedupe(commands.into_iter().enumerate().collect()),
+            delete_first,
+        )
+    }
+
+    fn arrange<A: Dispatch>(
+        mut commands: Vec<(usize, Command<A>)>,
+        delete_first: bool,
+    ) -> Vec<(usize, Command<A>)> {
+        commands.sort_by_key(|(_, command)| command.priority);
+        let mut commands = order_creates(commands);
+        if delete_first {
+            commands = deletes_before_creates(commands);
+        }
+        commands
+    }
+
+    /// Moves each delete back to just ahead of the earliest create of its own kind staged
+    /// before it, passing commands on other aggregates on the way. It never passes a command
+    /// of another priority, nor one on the aggregate it deletes, so priorities and
+    /// create-then-delete pairs keep their order.
+    fn deletes_before_creates<A: Dispatch>(
+        mut commands: Vec<(usize, Command<A>)>,
+    ) -> Vec<(usize, Command<A>)> {
+        for j in 0..commands.len() {
+            let deleted = &commands[j].1;
+            if deleted.db_operation != DBOperation::Delete {
+                continue;
+            }
+            let start = commands[..j]
+                .iter()
+                .rposition(|(_, command)| {
+                    command.priority != deleted.priority
+                        || command.aggregate.is_same_as(&deleted.aggregate)
+                })
+                .map_or(0, |i| i + 1);
+            let target = commands[start..j].iter().position(|(_, command)| {
+                command.db_operation == DBOperation::Create
+                    && command.aggregate.is_same_kind(&deleted.aggregate)
+            });
+            if let Some(offset) = target {
+                let command = commands.remove(j);
+                commands.insert(start + offset, command);
+            }
+        }
+        commands
+    }
+
+    /// Stable-sorts the creates by priority and then by dependency rank, and puts them back
+    /// into the positions creates held, so e.g. a user is inserted before an order that
+    /// references it while updates and deletes keep their places. The commands are already
+    /// in priority order, so a create never changes priority. A command on an aggregate
+    /// created before it closes the run of creates being sorted, so that aggregate's create
+    /// is never moved past it.
+    fn order_creates<A: Dispatch>(commands: Vec<(usize, Command<A>)>) -> Vec<(usize, Command<A>)> {
+        let mut ordered = Vec::with_capacity(commands.len());
+        let mut segment: Vec<(usize, Command<A>)> = Vec::new();
+        for entry in commands {
+            let closes = entry.1.db_operation != DBOperation::Create
+                && segment.iter().any(|(_, command)| {
+                    command.db_operation == DBOperation::Create
+                        && command.aggregate.is_same_as(&entry.1.aggregate)
+                });
+            if closes {
+                ordered.extend(sort_creates(std::mem::take(&mut segment)));
+            }
+            segment.push(entry);
+        }
+        ordered.extend(sort_creates(segment));
+        ordered
+    }
+
+    fn sort_creates<A: Dispatch>(commands: Vec<(usize, Command<A>)>) -> Vec<(usize, Command<A>)> {
+        let is_create = |command: &Command<A>| command.db_operation == DBOperation::Create;
+        let slots = commands
+            .iter()
+            .map(|(_, command)| is_create(command))
+            .collect::<Vec<_>>();
+        let (mut creates, others): (Vec<_>, Vec<_>) = commands
+            .into_iter()
+            .partition(|(_, command)| is_create(command));
+        creates.sort_by_key(|(_, command)| (command.priority, command.aggregate.dependency_rank()));
+        let (mut creates, mut others) = (creates.into_iter(), others.into_iter());
+        slots
+            .into_iter()
+            .map(|slot| if slot { creates.next() } else { others.next() }.unwrap())
+            .collect()
+    }
+
+    fn dedupe<A>(commands: Vec<(usize, Command<A>)>) -> Vec<(usize, Command<A>)> {
+        let mut seen = HashSet::new();
+        commands
+            .into_iter()
+            .filter(|(_, command)| match &command.idempotency_key {
+                Some(key) => seen.insert(key.clone()),
+                None => true,
+            })
+            .collect()
+    }
+
+    fn group_creates<A: Dispatch>(commands: Vec<(usize, Command<A>)>) -> Vec<Step<A>> {
+        let mut steps: Vec<Step<A>> = Vec::new();
+        for (index, command) in commands {
+            if command.db_operation != DBOperation::Create {
+                steps.push(Step::Single(index, command));
+                continue;
+            }
+            if let Some(Step::CreateMany(indices, batch, metadata)) = steps.last_mut() {
+                if batch[0].is_same_kind(&command.aggregate) {
+                    indices.push(index);
+                    batch.push(command.aggregate);
+                    metadata.push(command.metadata);
+                    continue;
+                }
+            }
+            steps.push(Step::CreateMany(
+                vec![index],
+                vec![command.aggregate],
+                vec![command.metadata],
+            ));
+        }
+        steps
+    }
+
+    enum CreateBatch {
+        Users(Vec<User>),
+        Shops(Vec<Shop>),
+        Orders(Vec<Order>),
+        Products(Vec<Product>),
+    }
+
+    impl CreateBatch {
+        fn push(&mut self, aggregate: Aggregate) -> Result<(), Aggregate> {
+            match (self, aggregate) {
+                (Self::Users(users), Aggregate::User(user)) => users.push(user),
+                (Self::Shops(shops), Aggregate::Shop(shop)) => shops.push(shop),
+                (Self::Orders(orders), Aggregate::Order(order)) => orders.push(order),
+                (Self::Products(products), Aggregate::Product(product)) => products.push(product),
+                (_, aggregate) => return Err(aggregate),
+            }
+            Ok(())
+        }
+    }
+
+    impl From<Aggregate> for CreateBatch {
+        fn from(aggregate: Aggregate) -> Self {
+            match aggregate {
+                Aggregate::User(user) => Self::Users(vec![user]),
+                Aggregate::Shop(shop) => Self::Shops(vec![shop]),
+                Aggregate::Order(order) => Self::Orders(vec![order]),
+                Aggregate::Product(product) => Self::Products(vec![product]),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Dispatch for Aggregate {
+        async fn apply(
+            self,
+            op: DBOperation,
+            txn: &DatabaseTransaction,
+        ) -> Result<Option<i64>, DbErr> {
+            self.apply_as(op, None, txn).await
+        }
+
+        async fn apply_creates(
+            batch: Vec<Self>,
+            txn: &DatabaseTransaction,
+        ) -> Result<Vec<i64>, DbErr> {
+            Self::apply_creates_as(batch, None, txn).await
+        }
+
+        async fn apply_as(
+            self,
+            op: DBOperation,
+            actor: Option<ActorId>,
+            txn: &DatabaseTransaction,
+        ) -> Result<Option<i64>, DbErr> {
+            match self {
+                Aggregate::User(user) => match op {
+                    DBOperation::Create => create_user(user, actor, txn).await.map(Some),
+                    DBOperation::Update => update_user(user, actor, txn).await.map(|_| None),
+                    DBOperation::Delete => delete_user(user, txn).await.map(|_| None),
+                    DBOperation::Upsert => upsert_user(user, actor, txn).await.map(|_| None),
+                },
+                Aggregate::Shop(shop) => match op {
+                    DBOperation::Create => create_shop(shop, actor, txn).await.map(Some),
+                    DBOperation::Update => update_shop(shop, actor, txn).await.map(|_| None),
+                    DBOperation::Delete => delete_shop(shop, txn).await.map(|_| None),
+                    DBOperation::Upsert => upsert_shop(shop, actor, txn).await.map(|_| None),
+                },
+                Aggregate::Order(order) => match op {
+                    DBOperation::Create => create_order(order, actor, txn).await.map(Some),
+                    DBOperation::Update => update_order(order, actor, txn).await.map(|_| None),
+                    DBOperation::Delete => delete_order(order, txn).await.map(|_| None),
+                    DBOperation::Upsert => upsert_order(order, actor, txn).await.map(|_| None),
+                },
+                Aggregate::Product(product) => match op {
+                    DBOperation::Create => create_product(product, actor, txn).await.map(Some),
+                    DBOperation::Update => update_product(product, actor, txn).await.map(|_| None),
+                    DBOperation::Delete => delete_product(product, txn).await.map(|_| None),
+                    DBOperation::Upsert => upsert_product(product, actor, txn).await.map(|_| None),
+                },
+            }
+        }
+
+        async fn apply_creates_as(
+            batch: Vec<Self>,
+            actor: Option<ActorId>,
+            txn: &DatabaseTransaction,
+        ) -> Result<Vec<i64>, DbErr> {
+            let mut aggregates = batch.into_iter();
+            let Some(first) = aggregates.next() else {
+                return Ok(vec![]);
+            };
+            let mut batch = CreateBatch::from(first);
+            for aggregate in aggregates {
+                batch
+                    .push(aggregate)
+                    .map_err(|_| DbErr::Custom("mixed aggregate kinds in batch".to_string()))?;
+            }
+            match batch {
+                CreateBatch::Users(users) => create_users(users, actor, txn).await,
+                CreateBatch::Shops(shops) => create_shops(shops, actor, txn).await,
+                CreateBatch::Orders(orders) => create_orders(orders, actor, txn).await,
+                CreateBatch::Products(products) => create_products(products, actor, txn).await,
+            }
+        }
+
+        fn is_same_kind(&self, other: &Self) -> bool {
+            std::mem::discriminant(self) == std::mem::discriminant(other)
+        }
+
+        fn is_same_as(&self, other: &Self) -> bool {
+            self.is_same_kind(other) && self.id() == other.id()
+        }
+
+        fn dependency_rank(&self) -> u8 {
+            dependency_rank(self)
+        }
+
+        fn kind(&self) -> &'static str {
+            AggregateKind::from(self).as_str()
+        }
+
+        fn payload(&self) -> serde_json::Value {
+            serde_json::to_value(self).unwrap_or_default()
+        }
+
+        fn aggregate_id(&self) -> Option<i64> {
+            Some(self.id())
+        }
+
+        fn validate(&self) -> Result<(), Vec<ValidationError>> {
+            match self {
+                Aggregate::User(user) => user.validate(),
+                Aggregate::Shop(shop) => shop.validate(),
+                Aggregate::Order(order) => order.validate(),
+                Aggregate::Product(product) => product.validate(),
+            }
+        }
+
+        fn tenant_id(&self) -> Option<TenantId> {
+            match self {
+                Aggregate::User(user) => user.tenant_id(),
+                Aggregate::Shop(shop) => shop.tenant_id(),
+                Aggregate::Order(order) => order.tenant_id(),
+                Aggregate::Product(product) => product.tenant_id(),
+            }
+        }
+
+        fn explain(
+            &self,
+            op: DBOperation,
+            actor: Option<ActorId>,
+            backend: DatabaseBackend,
+        ) -> Option<String> {
+            let table = table_of(self.into());
+            match op {
+                DBOperation::Create => Some(backend.build(&insert_ids(table, [self.id()], actor))),
+                DBOperation::Delete => Some(backend.build(&delete_id(table, self.id()))),
+                DBOperation::Update => Some(backend.build(&update_id(table, self.id(), actor))),
+                DBOperation::Upsert => Some(backend.build(&upsert_id(table, self.id(), actor))),
+            }
+            .map(|stmt| stmt.to_string())
+        }
+    }
+
+    fn table_of(kind: AggregateKind) -> &'static str {
+        match kind {
+            AggregateKind::User => "users",
+            AggregateKind::Shop => "shops",
+            AggregateKind::Order => "orders",
+            AggregateKind::Product => "products",
+        }
+    }
+
+    /// The aggregates carry nothing but their id, so that is all a create can insert
+    /// besides the acting user.
+    fn insert_ids(
+        table: &str,
+        ids: impl IntoIterator<Item = i64>,
+        actor: Option<ActorId>,
+    ) -> InsertStatement {
+        insert_rows(table, ids, "created_by", actor)
+    }
+
+    fn insert_rows(
+        table: &str,
+        ids: impl IntoIterator<Item = i64>,
+        actor_column: &str,
+        actor: Option<ActorId>,
+    ) -> InsertStatement {
+        let mut stmt = Query::insert().into_table(Alias::new(table)).to_owned();
+        match actor {
+            Some(actor) => {
+                stmt.columns([Alias::new("id"), Alias::new(actor_column)]);
+                for id in ids {
+                    stmt.values_panic([id.into(), actor.into()]);
+                }
+            }
+            None => {
+                stmt.columns([Alias::new("id")]);
+                for id in ids {
+                    stmt.values_panic([id.into()]);
+                }
+            }
+        }
+        stmt
+    }
+
+    /// Inserts the row, or leaves it in place when the id is taken; there is no other column
+    /// to overwrite but the acting user.
+    fn upsert_id(table: &str, id: i64, actor: Option<ActorId>) -> InsertStatement {
+        let column = Alias::new(if actor.is_some() { "updated_by" } else { "id" });
+        insert_rows(table, [id], "updated_by", actor)
+            .on_conflict(
+                OnConflict::column(Alias::new("id"))
+                    .update_column(column)
+                    .to_owned(),
+            )
+            .to_owned()
+    }
+
+    /// There is no column besides the id to write, so an update only touches its row, or
+    /// records the acting user.
+    fn update_id(table: &str, id: i64, actor: Option<ActorId>) -> UpdateStatement {
+        let mut stmt = Query::update().table(Alias::new(table)).to_owned();
+        match actor {
+            Some(actor) => stmt.value(Alias::new("updated_by"), actor),
+            None => stmt.value(Alias::new("id"), Expr::col(Alias::new("id"))),
+        };
+        stmt.and_where(Expr::col(Alias::new("id")).eq(id))
+            .to_owned()
+    }
+
+    fn delete_id(table: &str, id: i64) -> DeleteStatement {
+        Query::delete()
+            .from_table(Alias::new(table))
+            .and_where(Expr::col(Alias::new("id")).eq(id))
+            .to_owned()
+    }
+
+    async fn create_by_ids(
+        table: &str,
+        ids: Vec<i64>,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<Vec<i64>, DbErr> {
+        txn.execute(txn.get_database_backend().build(&insert_ids(
+            table,
+            ids.iter().copied(),
+            actor,
+        )))
+        .await?;
+        Ok(ids)
+    }
+
+    async fn update_by_id(
+        table: &str,
+        id: i64,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<(), DbErr> {
+        txn.execute(
+            txn.get_database_backend()
+                .build(&update_id(table, id, actor)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn upsert_by_id(
+        table: &str,
+        id: i64,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<(), DbErr> {
+        txn.execute(
+            txn.get_database_backend()
+                .build(&upsert_id(table, id, actor)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_by_id(table: &str, id: i64, txn: &DatabaseTransaction) -> Result<(), DbErr> {
+        txn.execute(txn.get_database_backend().build(&delete_id(table, id)))
+            .await?;
+        Ok(())
+    }
+
+    async fn create_user(
+        user: User,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<i64, DbErr> {
+        let ids = create_by_ids("users", vec![user.id()], actor, txn).await?;
+        Ok(ids[0])
+    }
+
+    async fn create_users(
+        users: Vec<User>,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<Vec<i64>, DbErr> {
+        let ids = users.iter().map(User::id).collect();
+        create_by_ids("users", ids, actor, txn).await
+    }
+
+    async fn update_user(
+        user: User,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<(), DbErr> {
+        update_by_id("users", user.id(), actor, txn).await
+    }
+
+    async fn delete_user(user: User, txn: &DatabaseTransaction) -> Result<(), DbErr> {
+        delete_by_id("users", user.id(), txn).await
+    }
+
+    async fn upsert_user(
+        user: User,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<(), DbErr> {
+        upsert_by_id("users", user.id(), actor, txn).await
+    }
+
+    async fn create_shop(
+        shop: Shop,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<i64, DbErr> {
+        let ids = create_by_ids("shops", vec![shop.id()], actor, txn).await?;
+        Ok(ids[0])
+    }
+
+    async fn create_shops(
+        shops: Vec<Shop>,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<Vec<i64>, DbErr> {
+        let ids = shops.iter().map(Shop::id).collect();
+        create_by_ids("shops", ids, actor, txn).await
+    }
+
+    async fn update_shop(
+        shop: Shop,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<(), DbErr> {
+        update_by_id("shops", shop.id(), actor, txn).await
+    }
+
+    async fn delete_shop(shop: Shop, txn: &DatabaseTransaction) -> Result<(), DbErr> {
+        delete_by_id("shops", shop.id(), txn).await
+    }
+
+    async fn upsert_shop(
+        shop: Shop,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<(), DbErr> {
+        upsert_by_id("shops", shop.id(), actor, txn).await
+    }
+
+    async fn create_order(
+        order: Order,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<i64, DbErr> {
+        let ids = create_by_ids("orders", vec![order.id()], actor, txn).await?;
+        Ok(ids[0])
+    }
+
+    async fn create_orders(
+        orders: Vec<Order>,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<Vec<i64>, DbErr> {
+        let ids = orders.iter().map(Order::id).collect();
+        create_by_ids("orders", ids, actor, txn).await
+    }
+
+    async fn update_order(
+        order: Order,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<(), DbErr> {
+        update_by_id("orders", order.id(), actor, txn).await
+    }
+
+    async fn delete_order(order: Order, txn: &DatabaseTransaction) -> Result<(), DbErr> {
+        delete_by_id("orders", order.id(), txn).await
+    }
+
+    async fn upsert_order(
+        order: Order,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<(), DbErr> {
+        upsert_by_id("orders", order.id(), actor, txn).await
+    }
+
+    async fn create_product(
+        product: Product,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<i64, DbErr> {
+        let ids = create_by_ids("products", vec![product.id()], actor, txn).await?;
+        Ok(ids[0])
+    }
+
+    async fn create_products(
+        products: Vec<Product>,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<Vec<i64>, DbErr> {
+        let ids = products.iter().map(Product::id).collect();
+        create_by_ids("products", ids, actor, txn).await
+    }
+
+    async fn update_product(
+        product: Product,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<(), DbErr> {
+        update_by_id("products", product.id(), actor, txn).await
+    }
+
+    async fn delete_product(product: Product, txn: &DatabaseTransaction) -> Result<(), DbErr> {
+        delete_by_id("products", product.id(), txn).await
+    }
+
+    async fn upsert_product(
+        product: Product,
+        actor: Option<ActorId>,
+        txn: &DatabaseTransaction,
+    ) -> Result<(), DbErr> {
+        upsert_by_id("products", product.id(), actor, txn).await
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing {
+    use super::{
+        domain::{AggregateKind, DBOperation, Dispatch},
+        infrastructure::CommandExecutor,
+    };
+
+    use std::sync::Mutex;
+
+    use sea_orm::DbErr;
+
+    /// Records every operation it is handed, in order, instead of writing anything.
+    #[derive(Default)]
+    pub struct TestExecutor {
+        operations: Mutex<Vec<(AggregateKind, DBOperation, i64)>>,
+    }
+
+    impl TestExecutor {
+        pub fn operations(&self) -> Vec<(AggregateKind, DBOperation, i64)> {
+            self.operations.lock().unwrap().clone()
+        }
+    }
+
+    impl<A: Dispatch> CommandExecutor<A> for TestExecutor {
+        fn execute(&self, operation: DBOperation, aggregates: &[A]) -> Result<(), DbErr> {
+            let mut operations = self.operations.lock().unwrap();
+            for aggregate in aggregates {
+                let (Some(kind), Some(id)) = (
+                    AggregateKind::from_name(aggregate.kind()),
+                    aggregate.aggregate_id(),
+                ) else {
+                    return Err(DbErr::Custom(format!(
+                        "TestExecutor cannot record a {} aggregate",
+                        aggregate.kind()
+                    )));
+                };
+                operations.push((kind, operation, id));
+            }
+            Ok(())
+        }
+    }
+}
+
+pub mod context {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use anyhow::{Context as _, Result};
+    use async_trait::async_trait;
+    use derive_new::new;
+    use sea_orm::{
+        prelude::DatabaseConnection,
+        sea_query::{Alias, ColumnDef, Table},
+        ConnectionTrait, Database,
+    };
+    use tokio::sync::Mutex;
+
+    use super::{
+        domain::{TenantId, UnitOfWorkError},
+        infrastructure::{ConnectionId, DatabaseClient},
+    };
+
+    #[async_trait]
+    pub trait ProvideUnitOfWork {
+        type UnitOfWork: super::domain::UnitOfWork + Send + Sync;
+        fn provide(&self) -> Self::UnitOfWork;
+
+        async fn provide_async(&self) -> Result<Self::UnitOfWork> {
+            Ok(self.provide())
+        }
+
+        /// Fails fast with `AcquireTimeout` when a saturated pool cannot hand out a
+        /// connection within `dur`, instead of queueing behind other callers.
+        async fn provide_with_timeout(&self, dur: Duration) -> Result<Self::UnitOfWork> {
+            tokio::time::timeout(dur, self.provide_async())
+                .await
+                .map_err(|_| UnitOfWorkError::AcquireTimeout(dur))?
+        }
+    }
+
+    #[derive(new)]
+    pub struct Context {
+        conn: DatabaseConnection,
+        #[new(value = "ConnectionId::next()")]
+        connection_id: ConnectionId,
+    }
+
+    impl Context {
+        pub async fn connect(url: &str) -> Result<Self> {
+            let conn = Database::connect(url)
+                .await
+                .with_context(|| "Failed to connect to database")?;
+            Ok(Self::new(conn))
+        }
+
+        /// Creates the tables the aggregates are written to, if they are missing, so the
+        /// example can run against an empty database.
+        pub async fn ensure_schema(&self) -> Result<()> {
+            let backend = self.conn.get_database_backend();
+            for table in ["users", "shops", "orders", "products"] {
+                let stmt = Table::create()
+                    .table(Alias::new(table))
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Alias::new("id"))
+                            .big_integer()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Alias::new("created_by")).big_integer())
+                    .col(ColumnDef::new(Alias::new("updated_by")).big_integer())
+                    .to_owned();
+                self.conn
+                    .execute(backend.build(&stmt))
+                    .await
+                    .with_context(|| format!("Failed to create table {}", table))?;
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl ProvideUnitOfWork for Context {
+        type UnitOfWork = super::infrastructure::DatabaseClient;
+
+        fn provide(&self) -> Self::UnitOfWork {
+            DatabaseClient::builder()
+                .conn(self.conn.clone())
+                .connection_id(self.connection_id)
+                .build()
+                .expect("connection is set")
+        }
+
+        async fn provide_async(&self) -> Result<Self::UnitOfWork> {
+            self.conn
+                .ping()
+                .await
+                .with_context(|| "Failed to acquire connection")?;
+            Ok(self.provide())
+        }
+    }
+
+    /// Hands out one `DatabaseClient` shared by every handler of a request, so commands
+    /// staged in different places are flushed by a single commit.
+    ///
+    /// The lock is async and holding it across `commit` blocks every other handler until
+    /// the transaction finishes; stage and release quickly, and commit from one place only.
+    pub struct SharedContext {
+        client: Arc<Mutex<DatabaseClient>>,
+    }
+
+    impl SharedContext {
+        pub fn new(context: &Context) -> Self {
+            Self {
+                client: Arc::new(Mutex::new(context.provide())),
+            }
+        }
+
+        pub fn provide_shared(&self) -> Arc<Mutex<DatabaseClient>> {
+            Arc::clone(&self.client)
+        }
+    }
+
+    #[derive(new)]
+    pub struct ShardedContext {
+        shards: HashMap<TenantId, DatabaseConnection>,
+    }
+
+    impl ShardedContext {
+        pub fn provide_for(&self, tenant: TenantId) -> Result<DatabaseClient> {
+            let conn = self
+                .shards
+                .get(&tenant)
+                .with_context(|| format!("Unknown tenant {:?}", tenant))?;
+            DatabaseClient::builder().conn(conn.clone()).build()
+        }
+    }
+}
+
+mod use_case {
+    use super::{
+        context::{Context, ProvideUnitOfWork},
+        domain::{Order, Shop, UnitOfWork, User},
+    };
+
+    #[allow(unused)]
+    async fn use_case(context: Context) -> anyhow::Result<()> {
+        let mut uow = context.provide();
+
+        uow.update(User::new(1))?;
+        uow.update(Shop::new(1))?;
+        uow.create(Order::new(1))?;
+        uow.commit().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        context::{Context, ProvideUnitOfWork, ShardedContext, SharedContext},
+        domain::{
+            dependency_rank, ActorId, Aggregate, AggregateKind, CommitResult, DBOperation,
+            Dispatch, DomainEvent, EmptyCommitPolicy, Order, Product, Shop, StageExt, TenantId,
+            UnitOfWork, UnitOfWorkError, User,
+        },
+        infrastructure::{replay, DatabaseClient, EnlistedClient, EventStore},
+        metrics,
+        testing::TestExecutor,
+    };
+    use crate::test_support::{block_on, postgres, Capture};
+
+    use std::{
+        borrow::Cow,
+        collections::{HashMap, HashSet},
+        error::Error as StdError,
+        fmt,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use sea_orm::{
+        ConnectionTrait, Database, DatabaseConnection, DatabaseTransaction, DbErr, RuntimeErr,
+        Statement, TransactionTrait, Value,
+    };
+    use sqlx::error::{DatabaseError, ErrorKind};
+    use tokio::sync::mpsc;
+    use tokio_stream::StreamExt;
+    use tokio_util::sync::CancellationToken;
+    use tracing::Level;
+
+    async fn connect() -> DatabaseConnection {
+        let conn = Database::connect("sqlite::memory:").await.unwrap();
+        Context::new(conn.clone()).ensure_schema().await.unwrap();
+        conn
+    }
+
+    fn client(conn: &DatabaseConnection) -> DatabaseClient {
+        DatabaseClient::builder()
+            .conn(conn.clone())
+            .build()
+            .unwrap()
+    }
+
+    /// A client without a database that records what it would have run.
+    fn recording() -> (DatabaseClient, Arc<TestExecutor>) {
+        let executor = Arc::new(TestExecutor::default());
+        let uow = client(&DatabaseConnection::Disconnected).with_executor(executor.clone());
+        (uow, executor)
+    }
+
+    async fn ids(conn: &DatabaseConnection, table: &str) -> Vec<i64> {
+        let sql = format!("SELECT id FROM {} ORDER BY id", table);
+        let rows = conn
+            .query_all(Statement::from_string(conn.get_database_backend(), sql))
+            .await
+            .unwrap();
+        rows.iter()
+            .map(|row| row.try_get::<i64>("", "id").unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn upsert_inserts_a_missing_row_and_keeps_an_existing_one() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.upsert(User::new(1)).unwrap();
+            uow.commit().await.unwrap();
+
+            uow.upsert(User::new(1)).unwrap();
+            uow.upsert(Shop::new(2)).unwrap();
+            uow.commit().await.unwrap();
+
+            assert_eq!(ids(&conn, "users").await, [1]);
+            assert_eq!(ids(&conn, "shops").await, [2]);
+            assert!(ids(&conn, "orders").await.is_empty());
+        });
+    }
+
+    #[test]
+    fn upsert_is_dispatched_to_its_own_kind() {
+        block_on(async {
+            let (mut uow, executor) = recording();
+            uow.upsert(Order::new(3)).unwrap();
+            uow.upsert(User::new(4)).unwrap();
+            uow.commit().await.unwrap();
+
+            assert_eq!(
+                executor.operations(),
+                [
+                    (AggregateKind::Order, DBOperation::Upsert, 3),
+                    (AggregateKind::User, DBOperation::Upsert, 4),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn creates_are_moved_ahead_of_the_higher_ranked_creates_before_them() {
+        block_on(async {
+            let (mut uow, executor) = recording();
+            uow.create(Order::new(1)).unwrap();
+            uow.create(User::new(2)).unwrap();
+            uow.create(Shop::new(3)).unwrap();
+            uow.commit().await.unwrap();
+
+            assert_eq!(
+                executor.operations(),
+                [
+                    (AggregateKind::User, DBOperation::Create, 2),
+                    (AggregateKind::Shop, DBOperation::Create, 3),
+                    (AggregateKind::Order, DBOperation::Create, 1),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn creates_are_moved_over_other_operations_but_not_across_priorities() {
+        block_on(async {
+            let (mut uow, executor) = recording();
+            uow.create(Order::new(1)).unwrap();
+            uow.update(Shop::new(5)).unwrap();
+            uow.create(User::new(2)).unwrap();
+            uow.create_with_priority(Order::new(3), 1).unwrap();
+            uow.create_with_priority(User::new(4), 2).unwrap();
+            uow.commit().await.unwrap();
+
+            assert_eq!(
+                executor.operations(),
+                [
+                    (AggregateKind::User, DBOperation::Create, 2),
+                    (AggregateKind::Shop, DBOperation::Update, 5),
+                    (AggregateKind::Order, DBOperation::Create, 1),
+                    (AggregateKind::Order, DBOperation::Create, 3),
+                    (AggregateKind::User, DBOperation::Create, 4),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn a_create_is_not_moved_past_a_later_command_on_its_aggregate() {
+        block_on(async {
+            let (mut uow, executor) = recording();
+            uow.create(Order::new(1)).unwrap();
+            uow.update(Order::new(1)).unwrap();
+            uow.create(User::new(2)).unwrap();
+            uow.create(Order::new(3)).unwrap();
+            uow.create(Shop::new(4)).unwrap();
+            uow.commit().await.unwrap();
+
+            assert_eq!(
+                executor.operations(),
+                [
+                    (AggregateKind::Order, DBOperation::Create, 1),
+                    (AggregateKind::Order, DBOperation::Update, 1),
+                    (AggregateKind::User, DBOperation::Create, 2),
+                    (AggregateKind::Shop, DBOperation::Create, 4),
+                    (AggregateKind::Order, DBOperation::Create, 3),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn best_effort_commits_the_commands_around_a_failing_one() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.commit().await.unwrap();
+
+            let (tx, mut rx) = mpsc::channel(8);
+            let mut uow = client(&conn).with_event_sink(tx);
+            uow.create(User::new(2)).unwrap();
+            uow.create(User::new(1)).unwrap();
+            uow.create(User::new(3)).unwrap();
+            let results = uow.commit_best_effort().await;
+
+            assert!(results[0].is_ok() && results[1].is_err() && results[2].is_ok());
+            assert_eq!(ids(&conn, "users").await, [1, 2, 3]);
+            let published = [rx.recv().await.unwrap(), rx.recv().await.unwrap()];
+            assert_eq!(published.map(|change| change.id), [Some(2), Some(3)]);
+        });
+    }
+
+    #[test]
+    fn best_effort_validates_and_refuses_raw_statements_without_running_anything() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.create(User::new(0)).unwrap();
+            assert!(uow.commit_best_effort().await.iter().all(Result::is_err));
+            assert_eq!(uow.commands().len(), 2);
+
+            uow.clear();
+            uow.create(User::new(1)).unwrap();
+            uow.execute_raw("DELETE FROM users", vec![]).unwrap();
+            assert!(uow.commit_best_effort().await.iter().all(Result::is_err));
+            assert_eq!(uow.pending_len(), 2);
+            assert!(ids(&conn, "users").await.is_empty());
+        });
+    }
+
+    #[test]
+    fn best_effort_writes_nothing_in_dry_run() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow: DatabaseClient = DatabaseClient::builder()
+                .conn(conn.clone())
+                .dry_run(true)
+                .build()
+                .unwrap();
+            uow.create(User::new(1)).unwrap();
+
+            assert!(uow.commit_best_effort().await.iter().all(Result::is_ok));
+            assert!(uow.is_empty());
+            assert!(ids(&conn, "users").await.is_empty());
+        });
+    }
+
+    #[test]
+    fn update_targets_the_row_with_the_aggregate_id() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.create(User::new(2)).unwrap();
+            uow.commit().await.unwrap();
+
+            let mut uow: DatabaseClient = DatabaseClient::builder()
+                .conn(conn.clone())
+                .with_actor(ActorId::new(7))
+                .build()
+                .unwrap();
+            uow.update(User::new(2)).unwrap();
+            assert_eq!(
+                uow.explain(),
+                [r#"UPDATE "users" SET "updated_by" = 7 WHERE "id" = 2"#]
+            );
+            uow.commit().await.unwrap();
+
+            let sql = "SELECT id FROM users WHERE updated_by = 7";
+            let rows = conn
+                .query_all(Statement::from_string(conn.get_database_backend(), sql))
+                .await
+                .unwrap();
+            assert_eq!(rows.len(), 1);
+            assert_eq!(rows[0].try_get::<i64>("", "id").unwrap(), 2);
+        });
+    }
+
+    #[test]
+    fn hooks_learn_the_operation_and_id_of_each_command() {
+        block_on(async {
+            let conn = connect().await;
+            conn.execute_unprepared(
+                "CREATE TABLE counters (n INTEGER NOT NULL); INSERT INTO counters VALUES (0);",
+            )
+            .await
+            .unwrap();
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let mut uow = client(&conn);
+            let recorded = Arc::clone(&seen);
+            uow.register_hook(
+                AggregateKind::User,
+                Box::new(move |txn, operation, id| {
+                    recorded.lock().unwrap().push((operation, id));
+                    Box::pin(async move {
+                        if operation == DBOperation::Create {
+                            txn.execute_unprepared("UPDATE counters SET n = n + 1")
+                                .await?;
+                        }
+                        Ok(())
+                    })
+                }),
+            );
+            uow.create(User::new(1)).unwrap();
+            uow.create(User::new(2)).unwrap();
+            uow.update(User::new(1)).unwrap();
+            uow.create(Shop::new(3)).unwrap();
+            uow.commit().await.unwrap();
+
+            let sql = "SELECT n FROM counters";
+            let row = conn
+                .query_one(Statement::from_string(conn.get_database_backend(), sql))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(row.try_get::<i64>("", "n").unwrap(), 2);
+            assert_eq!(
+                *seen.lock().unwrap(),
+                [
+                    (DBOperation::Create, Some(1)),
+                    (DBOperation::Create, Some(2)),
+                    (DBOperation::Update, Some(1)),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn raw_statements_survive_the_json_round_trip() {
+        block_on(async {
+            let conn = connect().await;
+            conn.execute_unprepared("CREATE TABLE notes (id INTEGER, body TEXT)")
+                .await
+                .unwrap();
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.execute_raw(
+                "INSERT INTO notes (id, body) VALUES ($1, $2)",
+                vec![Value::BigInt(Some(1)), Value::String(None)],
+            )
+            .unwrap();
+            let json = uow.to_json().unwrap();
+
+            let mut restored: DatabaseClient =
+                DatabaseClient::load_from_json(conn.clone(), &json).unwrap();
+            assert_eq!(restored.pending_len(), 2);
+            restored.commit().await.unwrap();
+            assert_eq!(ids(&conn, "users").await, [1]);
+            assert_eq!(ids(&conn, "notes").await, [1]);
+
+            replay(&json, conn.clone()).await.unwrap_err();
+            conn.execute_unprepared("DELETE FROM users").await.unwrap();
+            replay(&json, conn.clone()).await.unwrap();
+            assert_eq!(ids(&conn, "notes").await, [1, 1]);
+        });
+    }
+
+    #[test]
+    fn explain_lists_raw_statements_in_staging_order() {
+        block_on(async {
+            let mut uow = client(&connect().await);
+            uow.execute_raw(
+                "DELETE FROM notes WHERE id = ?",
+                vec![Value::BigInt(Some(4))],
+            )
+            .unwrap();
+            uow.delete(User::new(2)).unwrap();
+
+            let sql = uow.explain();
+            assert_eq!(sql.len(), 2);
+            assert_eq!(sql[0], "DELETE FROM notes WHERE id = 4");
+            assert!(sql[1].starts_with("DELETE FROM \"users\""), "{}", sql[1]);
+        });
+    }
+
+    #[test]
+    fn an_enlisted_client_explains_and_absorbs_with_the_transaction_backend() {
+        block_on(async {
+            let conn = connect().await;
+            let txn = conn.begin().await.unwrap();
+            let mut uow: EnlistedClient<'_> = DatabaseClient::from_txn(&txn);
+            uow.create(User::new(1)).unwrap();
+            let mut other = client(&conn);
+            other.create(User::new(2)).unwrap();
+            uow.absorb(other).unwrap();
+
+            assert_eq!(uow.explain().len(), 2);
+            uow.commit().await.unwrap();
+            drop(uow);
+            txn.commit().await.unwrap();
+            assert_eq!(ids(&conn, "users").await, [1, 2]);
+        });
+    }
+
+    #[test]
+    fn update_if_changed_stages_only_a_changed_aggregate() {
+        let (mut uow, _) = recording();
+        let old = User::new(1);
+        assert!(!uow.update_if_changed(&old, old.clone()).unwrap());
+        assert!(uow.is_empty());
+
+        let new = old.clone().with_tenant(TenantId::new(9));
+        assert!(uow.update_if_changed(&old, new).unwrap());
+        assert!(uow.has_pending(AggregateKind::User, DBOperation::Update));
+    }
+
+    #[test]
+    fn setup_statements_are_counted_only_where_they_run() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow: DatabaseClient = DatabaseClient::builder()
+                .conn(conn.clone())
+                .defer_constraints(true)
+                .statement_timeout_ms(500)
+                .build()
+                .unwrap();
+            uow.create(User::new(1)).unwrap();
+            uow.delete(Shop::new(2)).unwrap();
+
+            let report = uow.commit().await.unwrap();
+            assert_eq!(report.stmt_count, 2);
+        });
+    }
+
+    #[test]
+    fn a_tenant_client_refuses_commits_touching_another_tenant() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow: DatabaseClient =
+                DatabaseClient::for_tenant(conn.clone(), TenantId::new(1));
+            uow.create(User::new(1).with_tenant(TenantId::new(1)))
+                .unwrap();
+            uow.create(User::new(2).with_tenant(TenantId::new(2)))
+                .unwrap();
+            uow.commit().await.unwrap_err();
+            assert!(ids(&conn, "users").await.is_empty());
+
+            let mut uow: DatabaseClient = DatabaseClient::builder()
+                .conn(conn.clone())
+                .tenant(TenantId::new(2))
+                .build()
+                .unwrap();
+            uow.create(User::new(2).with_tenant(TenantId::new(2)))
+                .unwrap();
+            uow.commit().await.unwrap();
+            assert_eq!(ids(&conn, "users").await, [2]);
+        });
+    }
+
+    #[test]
+    fn a_full_event_sink_drops_changes_instead_of_blocking_the_commit() {
+        let capture = Capture::default();
+        capture.run(|| {
+            block_on(async {
+                let conn = connect().await;
+                let (tx, mut rx) = mpsc::channel(1);
+                let mut uow = client(&conn).with_event_sink(tx);
+                uow.create(User::new(1)).unwrap();
+                uow.create(Shop::new(2)).unwrap();
+                uow.commit().await.unwrap();
+
+                assert_eq!(rx.recv().await.unwrap().id, Some(1));
+                assert!(rx.try_recv().is_err());
+            })
+        });
+        assert_eq!(
+            capture.messages(Level::WARN),
+            ["event sink is full; committed change was not published"]
+        );
+    }
+
+    #[test]
+    fn the_actor_is_written_by_the_statements_themselves() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow: DatabaseClient = DatabaseClient::builder()
+                .conn(conn.clone())
+                .with_actor(ActorId::new(7))
+                .build()
+                .unwrap();
+            uow.create(User::new(1)).unwrap();
+            uow.create(User::new(2)).unwrap();
+            uow.upsert(Shop::new(3)).unwrap();
+            let report = uow.commit().await.unwrap();
+            assert_eq!(report.stmt_count, 2);
+
+            let sql = "SELECT id FROM users WHERE created_by = 7 AND updated_by IS NULL";
+            let rows = conn
+                .query_all(Statement::from_string(conn.get_database_backend(), sql))
+                .await
+                .unwrap();
+            assert_eq!(rows.len(), 2);
+            let sql = "SELECT id FROM shops WHERE updated_by = 7";
+            let rows = conn
+                .query_all(Statement::from_string(conn.get_database_backend(), sql))
+                .await
+                .unwrap();
+            assert_eq!(rows.len(), 1);
+        });
+    }
+
+    #[test]
+    fn the_empty_commit_policy_sees_the_queue_before_it_is_optimized() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow: DatabaseClient = DatabaseClient::builder()
+                .conn(conn.clone())
+                .empty_commit_policy(EmptyCommitPolicy::Error)
+                .build()
+                .unwrap();
+            uow.commit_with_changelog().await.unwrap_err();
+
+            uow.create(User::new(1)).unwrap();
+            uow.delete(User::new(1)).unwrap();
+            assert!(uow.commit_with_changelog().await.unwrap().is_empty());
+            assert!(ids(&conn, "users").await.is_empty());
+            uow.commit().await.unwrap_err();
+        });
+    }
+
+    #[test]
+    fn deletes_are_moved_ahead_of_the_creates_of_their_kind_and_priority() {
+        block_on(async {
+            let recording = || {
+                let executor = Arc::new(TestExecutor::default());
+                let uow: DatabaseClient = DatabaseClient::builder()
+                    .conn(DatabaseConnection::Disconnected)
+                    .delete_before_create(true)
+                    .build()
+                    .unwrap()
+                    .with_executor(executor.clone());
+                (uow, executor)
+            };
+            let (mut uow, executor) = recording();
+            uow.create_with_priority(User::new(1), 0).unwrap();
+            uow.delete_with_priority(User::new(2), 1).unwrap();
+            uow.create_with_priority(User::new(3), 1).unwrap();
+            uow.delete_with_priority(User::new(4), 1).unwrap();
+            uow.commit().await.unwrap();
+            assert_eq!(
+                executor.operations(),
+                [
+                    (AggregateKind::User, DBOperation::Create, 1),
+                    (AggregateKind::User, DBOperation::Delete, 2),
+                    (AggregateKind::User, DBOperation::Delete, 4),
+                    (AggregateKind::User, DBOperation::Create, 3),
+                ]
+            );
+
+            let (mut uow, executor) = recording();
+            uow.create(User::new(2)).unwrap();
+            uow.update(Shop::new(5)).unwrap();
+            uow.delete(User::new(1)).unwrap();
+            uow.commit().await.unwrap();
+            assert_eq!(
+                executor.operations(),
+                [
+                    (AggregateKind::User, DBOperation::Delete, 1),
+                    (AggregateKind::User, DBOperation::Create, 2),
+                    (AggregateKind::Shop, DBOperation::Update, 5),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn commit_reports_the_ids_of_the_created_rows_by_staging_index() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.delete(Shop::new(9)).unwrap();
+            uow.create(Order::new(3)).unwrap();
+            uow.create(User::new(2)).unwrap();
+
+            let mut created_ids = uow.commit().await.unwrap().created_ids;
+            created_ids.sort();
+            assert_eq!(created_ids, [(0, 1), (2, 3), (3, 2)]);
+        });
+    }
+
+    #[test]
+    fn pending_len_counts_commands_and_raw_statements() {
+        let (mut uow, _) = recording();
+        assert!(uow.is_empty());
+        assert_eq!(uow.pending_len(), 0);
+
+        uow.create(User::new(1)).unwrap();
+        uow.execute_raw("DELETE FROM notes", vec![]).unwrap();
+        assert!(!uow.is_empty());
+        assert_eq!(uow.pending_len(), 2);
+    }
+
+    #[test]
+    fn clear_discards_the_queue_so_commit_writes_nothing() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.execute_raw("DELETE FROM users", vec![]).unwrap();
+            uow.clear();
+            assert!(uow.is_empty());
+
+            let report = uow.commit().await.unwrap();
+            assert_eq!(report.stmt_count, 0);
+            assert!(ids(&conn, "users").await.is_empty());
+        });
+    }
+
+    #[test]
+    fn products_are_written_to_their_own_table() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(Product::new(1)).unwrap();
+            uow.create(Product::new(2)).unwrap();
+            uow.commit().await.unwrap();
+            assert_eq!(ids(&conn, "products").await, [1, 2]);
+
+            uow.update(Product::new(2)).unwrap();
+            uow.delete(Product::new(1)).unwrap();
+            uow.commit().await.unwrap();
+            assert_eq!(ids(&conn, "products").await, [2]);
+            assert!(ids(&conn, "users").await.is_empty());
+        });
+    }
+
+    #[test]
+    fn consecutive_creates_of_one_kind_are_inserted_in_one_statement() {
+        block_on(async {
+            let mut conn = connect().await;
+            let inserts = Arc::new(Mutex::new(Vec::new()));
+            let seen = inserts.clone();
+            conn.set_metric_callback(move |info| {
+                if info.statement.sql.starts_with("INSERT") {
+                    seen.lock().unwrap().push(info.statement.sql.clone());
+                }
+            });
+            let mut uow = client(&conn);
+            for id in 1..=100 {
+                uow.create(User::new(id)).unwrap();
+            }
+
+            let report = uow.commit().await.unwrap();
+            assert_eq!(inserts.lock().unwrap().len(), 1);
+            assert_eq!(report.changes.len(), 100);
+            assert_eq!(ids(&conn, "users").await, (1..=100).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn a_create_of_another_kind_splits_the_batch() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.create(User::new(2)).unwrap();
+            uow.create(Shop::new(4)).unwrap();
+            uow.create(User::new(5)).unwrap();
+
+            let report = uow.commit().await.unwrap();
+            assert_eq!(report.stmt_count, 3);
+            assert_eq!(ids(&conn, "users").await, [1, 2, 5]);
+        });
+    }
+
+    #[test]
+    fn a_repeated_idempotency_key_runs_only_the_first_command() {
+        block_on(async {
+            let (mut uow, executor) = recording();
+            uow.create_with_key(User::new(1), "signup-1".to_string())
+                .unwrap();
+            uow.create_with_key(User::new(1), "signup-1".to_string())
+                .unwrap();
+            uow.update_with_key(User::new(2), "rename-2".to_string())
+                .unwrap();
+            uow.update(User::new(2)).unwrap();
+            uow.commit().await.unwrap();
+
+            assert_eq!(
+                executor.operations(),
+                [
+                    (AggregateKind::User, DBOperation::Create, 1),
+                    (AggregateKind::User, DBOperation::Update, 2),
+                    (AggregateKind::User, DBOperation::Update, 2),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn domain_events_are_released_only_by_a_successful_commit() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.record_event(DomainEvent::UserCreated);
+            let report = uow.commit().await.unwrap();
+            assert!(matches!(report.events[..], [DomainEvent::UserCreated]));
+
+            uow.create(User::new(1)).unwrap();
+            uow.record_event(DomainEvent::UserCreated);
+            uow.commit().await.unwrap_err();
+            uow.create(User::new(2)).unwrap();
+            assert!(uow.commit().await.unwrap().events.is_empty());
+        });
+    }
+
+    #[test]
+    fn commit_with_retry_gives_up_at_once_on_errors_that_are_not_serialization_failures() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.commit_with_retry(3).await.unwrap();
+            assert!(uow.is_empty());
+
+            uow.create(User::new(1)).unwrap();
+            let err = uow.commit_with_retry(3).await.unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "failed to commit transaction after 1 attempt"
+            );
+            assert_eq!(uow.pending_len(), 1);
+        });
+    }
+
+    /// A hook that keeps every user command busy for `delay`, to hold a commit open.
+    fn stall_users(uow: &mut DatabaseClient, delay: Duration) {
+        uow.register_hook(
+            AggregateKind::User,
+            Box::new(move |_, _, _| {
+                Box::pin(async move {
+                    tokio::time::sleep(delay).await;
+                    Ok(())
+                })
+            }),
+        );
+    }
+
+    #[test]
+    fn a_commit_that_outlives_its_timeout_is_rolled_back_and_kept() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            stall_users(&mut uow, Duration::from_millis(200));
+            uow.create(User::new(1)).unwrap();
+
+            let err = uow
+                .commit_with_timeout(Duration::from_millis(20))
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(UnitOfWorkError::Timeout(_))
+            ));
+            assert_eq!(uow.pending_len(), 1);
+            assert!(ids(&conn, "users").await.is_empty());
+        });
+    }
+
+    #[test]
+    fn provide_async_checks_the_connection_before_handing_out_a_client() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = Context::new(conn.clone()).provide_async().await.unwrap();
+            uow.create(User::new(1)).unwrap();
+            uow.commit().await.unwrap();
+            assert_eq!(ids(&conn, "users").await, [1]);
+
+            let err = Context::new(DatabaseConnection::Disconnected)
+                .provide_async()
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(err.to_string(), "Failed to acquire connection");
+        });
+    }
+
+    #[test]
+    fn connect_builds_a_context_from_a_url() {
+        block_on(async {
+            let context = Context::connect("sqlite::memory:").await.unwrap();
+            context.ensure_schema().await.unwrap();
+            let mut uow = context.provide();
+            uow.create(User::new(1)).unwrap();
+            assert_eq!(uow.commit().await.unwrap().stmt_count, 1);
+
+            let err = Context::connect("nosuchdb://").await.err().unwrap();
+            assert_eq!(err.to_string(), "Failed to connect to database");
+        });
+    }
+
+    #[test]
+    fn a_sharded_context_writes_to_the_tenants_own_database() {
+        block_on(async {
+            let (first, second) = (connect().await, connect().await);
+            let shards = HashMap::from([
+                (TenantId::new(1), first.clone()),
+                (TenantId::new(2), second.clone()),
+            ]);
+            let context = ShardedContext::new(shards);
+
+            let mut uow = context.provide_for(TenantId::new(2)).unwrap();
+            uow.create(User::new(1)).unwrap();
+            uow.commit().await.unwrap();
+            assert!(ids(&first, "users").await.is_empty());
+            assert_eq!(ids(&second, "users").await, [1]);
+
+            let err = context.provide_for(TenantId::new(3)).err().unwrap();
+            assert_eq!(err.to_string(), "Unknown tenant TenantId(3)");
+        });
+    }
+
+    #[test]
+    fn a_failing_validator_rejects_the_batch_and_keeps_it() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.add_validator(Box::new(|commands| {
+                if commands
+                    .iter()
+                    .any(|command| command.db_operation == DBOperation::Delete)
+                {
+                    anyhow::bail!("deletes are not allowed");
+                }
+                Ok(())
+            }));
+            uow.create(User::new(1)).unwrap();
+            uow.delete(Shop::new(2)).unwrap();
+
+            let err = uow.commit().await.unwrap_err();
+            assert_eq!(err.root_cause().to_string(), "deletes are not allowed");
+            assert_eq!(uow.pending_len(), 2);
+            assert!(ids(&conn, "users").await.is_empty());
+        });
+    }
+
+    #[test]
+    fn on_commit_callbacks_run_once_and_only_after_a_commit_lands() {
+        block_on(async {
+            let conn = connect().await;
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let mut uow = client(&conn);
+            for name in ["first", "second"] {
+                let calls = calls.clone();
+                uow.on_commit(Box::new(move || calls.lock().unwrap().push(name)));
+            }
+            uow.create(User::new(1)).unwrap();
+            uow.commit().await.unwrap();
+            uow.create(User::new(2)).unwrap();
+            uow.commit().await.unwrap();
+            assert_eq!(*calls.lock().unwrap(), ["first", "second"]);
+
+            let mut failing = client(&conn);
+            failing.register_hook(
+                AggregateKind::User,
+                Box::new(|_, _, _| Box::pin(async { Err(DbErr::Custom("rejected".to_string())) })),
+            );
+            let called = calls.clone();
+            failing.on_commit(Box::new(move || called.lock().unwrap().push("failed")));
+            failing.create(User::new(3)).unwrap();
+            failing.commit().await.unwrap_err();
+            assert_eq!(*calls.lock().unwrap(), ["first", "second"]);
+        });
+    }
+
+    #[test]
+    fn optimize_cancels_a_deleted_create_and_folds_updates_into_creates() {
+        let (mut uow, _) = recording();
+        uow.create(User::new(1)).unwrap();
+        uow.update(User::new(1)).unwrap();
+        uow.create(Shop::new(2)).unwrap();
+        uow.delete(Shop::new(2)).unwrap();
+        uow.update(Order::new(3)).unwrap();
+
+        uow.optimize();
+
+        let ops = uow
+            .commands()
+            .iter()
+            .map(|command| command.db_operation)
+            .collect::<Vec<_>>();
+        assert_eq!(ops, [DBOperation::Create, DBOperation::Update]);
+        assert!(uow.has_pending(AggregateKind::User, DBOperation::Create));
+        assert!(uow.has_pending(AggregateKind::Order, DBOperation::Update));
+    }
+
+    /// A downstream aggregate that only records how it was dispatched.
+    #[derive(Debug, Clone)]
+    struct Note {
+        id: i64,
+        applied: Arc<Mutex<Vec<(DBOperation, i64)>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Dispatch for Note {
+        async fn apply(
+            self,
+            op: DBOperation,
+            _txn: &DatabaseTransaction,
+        ) -> Result<Option<i64>, DbErr> {
+            self.applied.lock().unwrap().push((op, self.id));
+            Ok(Some(self.id))
+        }
+    }
+
+    #[test]
+    fn a_custom_aggregate_is_dispatched_through_its_own_apply() {
+        block_on(async {
+            let applied = Arc::new(Mutex::new(Vec::new()));
+            let note = |id| Note {
+                id,
+                applied: applied.clone(),
+            };
+            let mut uow: DatabaseClient<Note> = DatabaseClient::builder()
+                .conn(connect().await)
+                .build()
+                .unwrap();
+            uow.create(note(1)).unwrap();
+            uow.delete(note(2)).unwrap();
+            uow.commit().await.unwrap();
+
+            assert_eq!(
+                *applied.lock().unwrap(),
+                [(DBOperation::Create, 1), (DBOperation::Delete, 2)]
+            );
+        });
+    }
+
+    #[test]
+    fn staging_past_the_limit_is_refused() {
+        let (mut uow, _) = recording();
+        uow.set_max_commands(Some(2));
+        uow.create(User::new(1)).unwrap();
+        uow.update(Shop::new(2)).unwrap();
+
+        let err = uow.delete(Order::new(3)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(UnitOfWorkError::QueueFull(2))
+        ));
+        assert_eq!(uow.pending_len(), 2);
+    }
+
+    #[test]
+    fn raw_statements_count_towards_the_limit() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.set_max_commands(Some(2));
+            uow.execute_raw("UPDATE users SET created_by = 1", vec![])
+                .unwrap();
+            uow.create(User::new(1)).unwrap();
+
+            let err = uow
+                .execute_raw("UPDATE users SET created_by = 2", vec![])
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(UnitOfWorkError::QueueFull(2))
+            ));
+            assert!(uow.create(User::new(2)).is_err());
+
+            uow.clear();
+            uow.create(User::new(3)).unwrap();
+            let mut other = client(&conn);
+            other
+                .execute_raw("UPDATE users SET created_by = 3", vec![])
+                .unwrap();
+            uow.absorb(other).unwrap();
+            let mut extra = client(&conn);
+            extra
+                .execute_raw("UPDATE users SET created_by = 4", vec![])
+                .unwrap();
+            assert!(uow.absorb(extra).is_err());
+            assert_eq!(uow.pending_len(), 2);
+        });
+    }
+
+    #[test]
+    fn the_builder_applies_its_settings_and_requires_a_connection() {
+        let mut uow: DatabaseClient = DatabaseClient::builder()
+            .conn(DatabaseConnection::Disconnected)
+            .max_commands(1)
+            .build()
+            .unwrap();
+        uow.create(User::new(1)).unwrap();
+        assert!(uow.create(User::new(2)).is_err());
+
+        let err = DatabaseClient::<Aggregate>::builder()
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "DatabaseClient requires a connection");
+    }
+
+    #[test]
+    fn dry_run_logs_every_command_in_order_and_writes_nothing() {
+        let capture = Capture::default();
+        capture.run(|| {
+            block_on(async {
+                let conn = connect().await;
+                let mut uow: DatabaseClient = DatabaseClient::builder()
+                    .conn(conn.clone())
+                    .dry_run(true)
+                    .build()
+                    .unwrap();
+                uow.create(User::new(1)).unwrap();
+                uow.update(Shop::new(2)).unwrap();
+                uow.delete(Order::new(3)).unwrap();
+                uow.commit().await.unwrap();
+                assert_eq!(uow.pending_len(), 0);
+                assert!(ids(&conn, "users").await.is_empty());
+            })
+        });
+
+        let logged = capture
+            .events()
+            .into_iter()
+            .filter(|event| event.message() == "dry run")
+            .map(|event| {
+                format!(
+                    "{} {}",
+                    event.fields["operation"], event.fields["aggregate"]
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(logged, ["create user", "update shop", "delete order"]);
+    }
+
+    #[test]
+    fn summary_counts_each_operation_and_kind() {
+        let (mut uow, _) = recording();
+        assert_eq!(uow.summary(), "0 commands");
+        uow.create(User::new(1)).unwrap();
+        assert_eq!(uow.summary(), "1 command: 1 create user");
+
+        uow.update(Shop::new(2)).unwrap();
+        uow.create(Order::new(3)).unwrap();
+        uow.create(User::new(4)).unwrap();
+        assert_eq!(
+            uow.summary(),
+            "4 commands: 2 create user, 1 update shop, 1 create order"
+        );
+        assert_eq!(uow.commands().len(), 4);
+    }
+
+    #[test]
+    fn commands_are_dispatched_in_stable_priority_order() {
+        block_on(async {
+            let (mut uow, executor) = recording();
+            uow.delete_with_priority(Order::new(1), 10).unwrap();
+            uow.update(User::new(2)).unwrap();
+            uow.update_with_priority(Shop::new(3), -1).unwrap();
+            uow.update(Order::new(4)).unwrap();
+            uow.commit().await.unwrap();
+
+            assert_eq!(
+                executor.operations(),
+                [
+                    (AggregateKind::Shop, DBOperation::Update, 3),
+                    (AggregateKind::User, DBOperation::Update, 2),
+                    (AggregateKind::Order, DBOperation::Update, 4),
+                    (AggregateKind::Order, DBOperation::Delete, 1),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn staged_commands_survive_the_json_round_trip() {
+        let (mut uow, _) = recording();
+        uow.create(User::new(1)).unwrap();
+        uow.update(Shop::new(2)).unwrap();
+        uow.delete_with_priority(Order::new(3), 4).unwrap();
+        let json = uow.to_json().unwrap();
+
+        let restored: DatabaseClient =
+            DatabaseClient::load_from_json(DatabaseConnection::Disconnected, &json).unwrap();
+        let queue = |uow: &DatabaseClient| {
+            uow.commands()
+                .iter()
+                .map(|command| {
+                    let aggregate = &command.aggregate;
+                    (
+                        aggregate.kind(),
+                        aggregate.id(),
+                        command.db_operation,
+                        command.priority,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(queue(&restored), queue(&uow));
+        assert_eq!(queue(&restored).len(), 3);
+        assert!(DatabaseClient::<Aggregate>::load_from_json(
+            DatabaseConnection::Disconnected,
+            "not json"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn a_failing_command_is_named_in_the_commit_error() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.register_hook(
+                AggregateKind::Order,
+                Box::new(|_, _, _| Box::pin(async { Err(DbErr::Custom("rejected".to_string())) })),
+            );
+            uow.create(User::new(1)).unwrap();
+            uow.update(Shop::new(2)).unwrap();
+            uow.delete(Order::new(3)).unwrap();
+
+            let err = uow.commit().await.unwrap_err();
+            let message = format!("{:#}", err);
+            assert!(
+                message.contains("command 3: delete order failed"),
+                "{}",
+                message
+            );
+            assert!(ids(&conn, "users").await.is_empty());
+        });
+    }
+
+    /// A Postgres error carrying SQLSTATE `.0`, such as a deadlock or a serialization
+    /// failure, which SQLite cannot produce.
+    #[derive(Debug)]
+    struct SqlState(&'static str);
+
+    impl fmt::Display for SqlState {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "database error {}", self.0)
+        }
+    }
+
+    impl StdError for SqlState {}
+
+    impl DatabaseError for SqlState {
+        fn message(&self) -> &str {
+            "database error"
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(self.0.into())
+        }
+
+        fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    /// A hook that fails the first `failures` user commands with SQLSTATE `code`, and
+    /// counts every attempt.
+    fn fail_users(
+        uow: &mut DatabaseClient,
+        code: &'static str,
+        failures: usize,
+    ) -> Arc<Mutex<usize>> {
+        let attempts = Arc::new(Mutex::new(0));
+        let counted = attempts.clone();
+        uow.register_hook(
+            AggregateKind::User,
+            Box::new(move |_, _, _| {
+                let mut attempts = counted.lock().unwrap();
+                *attempts += 1;
+                let failed = *attempts <= failures;
+                Box::pin(async move {
+                    if failed {
+                        let err = sqlx::Error::Database(Box::new(SqlState(code)));
+                        return Err(DbErr::Exec(RuntimeErr::SqlxError(err)));
+                    }
+                    Ok(())
+                })
+            }),
+        );
+        attempts
+    }
+
+    #[test]
+    fn commit_with_retry_replays_the_batch_after_serialization_failures() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            let attempts = fail_users(&mut uow, "40001", 2);
+            uow.create(User::new(1)).unwrap();
+            uow.commit_with_retry(3).await.unwrap();
+            assert_eq!(*attempts.lock().unwrap(), 3);
+            assert_eq!(ids(&conn, "users").await, [1]);
+            assert!(uow.is_empty());
+        });
+    }
+
+    #[test]
+    fn commit_with_deadlock_retry_replays_the_batch_after_a_deadlock() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            let attempts = fail_users(&mut uow, "40P01", 2);
+            uow.create(User::new(1)).unwrap();
+            uow.commit_with_deadlock_retry(3).await.unwrap();
+            assert_eq!(*attempts.lock().unwrap(), 3);
+            assert_eq!(ids(&conn, "users").await, [1]);
+
+            let mut uow = client(&conn);
+            let attempts = fail_users(&mut uow, "40P01", 2);
+            uow.create(User::new(2)).unwrap();
+            let err = uow.commit_with_deadlock_retry(2).await.unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "failed to commit transaction after 2 attempts"
+            );
+            assert_eq!(*attempts.lock().unwrap(), 2);
+            assert_eq!(uow.pending_len(), 1);
+        });
+    }
+
+    #[test]
+    fn committed_commands_are_appended_to_the_event_log() {
+        block_on(async {
+            let conn = connect().await;
+            let logged = |conn: DatabaseConnection| {
+                let mut uow: DatabaseClient = DatabaseClient::builder()
+                    .conn(conn)
+                    .event_store(EventStore::default())
+                    .build()
+                    .unwrap();
+                uow.create(User::new(1)).unwrap();
+                uow.update(Shop::new(2)).unwrap();
+                uow
+            };
+            logged(conn.clone()).commit().await.unwrap_err();
+            assert!(ids(&conn, "users").await.is_empty());
+
+            conn.execute_unprepared(
+                "CREATE TABLE event_log (operation TEXT, aggregate_kind TEXT, payload TEXT)",
+            )
+            .await
+            .unwrap();
+            logged(conn.clone()).commit().await.unwrap();
+            let rows = conn
+                .query_all(Statement::from_string(
+                    conn.get_database_backend(),
+                    "SELECT operation, aggregate_kind FROM event_log",
+                ))
+                .await
+                .unwrap();
+            let rows = rows
+                .iter()
+                .map(|row| {
+                    let operation = row.try_get::<String>("", "operation").unwrap();
+                    let kind = row.try_get::<String>("", "aggregate_kind").unwrap();
+                    format!("{} {}", operation, kind)
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(rows, ["create user", "update shop"]);
+        });
+    }
+
+    #[test]
+    fn create_and_maybe_flush_commits_every_threshold_commands() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            let mut flushes = Vec::new();
+            for id in 1..=5 {
+                flushes.push(uow.create_and_maybe_flush(User::new(id), 2).await.unwrap());
+            }
+
+            assert_eq!(flushes, [false, true, false, true, false]);
+            assert_eq!(uow.pending_len(), 1);
+            assert_eq!(ids(&conn, "users").await, [1, 2, 3, 4]);
+        });
+    }
+
+    #[test]
+    fn cancelling_a_commit_rolls_it_back_and_keeps_the_commands() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            stall_users(&mut uow, Duration::from_millis(200));
+            uow.create(User::new(1)).unwrap();
+
+            let token = CancellationToken::new();
+            let canceller = token.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                canceller.cancel();
+            });
+            let err = uow.commit_with_cancel(token).await.unwrap_err();
+
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(UnitOfWorkError::Cancelled)
+            ));
+            assert_eq!(uow.pending_len(), 1);
+            assert!(ids(&conn, "users").await.is_empty());
+        });
+    }
+
+    #[test]
+    fn a_shared_context_flushes_every_handlers_commands_in_one_commit() {
+        block_on(async {
+            let conn = connect().await;
+            let context = SharedContext::new(&Context::new(conn.clone()));
+            let (first, second) = (context.provide_shared(), context.provide_shared());
+            first.lock().await.create(User::new(1)).unwrap();
+            second.lock().await.create(Shop::new(2)).unwrap();
+
+            let report = context
+                .provide_shared()
+                .lock()
+                .await
+                .commit()
+                .await
+                .unwrap();
+            assert_eq!(report.stmt_count, 2);
+            assert_eq!(ids(&conn, "users").await, [1]);
+            assert_eq!(ids(&conn, "shops").await, [2]);
+            assert!(first.lock().await.is_empty());
+        });
+    }
+
+    /// An aggregate whose dispatch takes `delay`.
+    #[derive(Debug, Clone)]
+    struct Sleepy(Duration);
+
+    #[async_trait::async_trait]
+    impl Dispatch for Sleepy {
+        async fn apply(
+            self,
+            _op: DBOperation,
+            _txn: &DatabaseTransaction,
+        ) -> Result<Option<i64>, DbErr> {
+            tokio::time::sleep(self.0).await;
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn only_commands_slower_than_the_threshold_are_reported() {
+        let capture = Capture::default();
+        capture.run(|| {
+            block_on(async {
+                let mut uow: DatabaseClient<Sleepy> = DatabaseClient::builder()
+                    .conn(connect().await)
+                    .build()
+                    .unwrap();
+                uow.set_slow_threshold(Some(Duration::from_millis(30)));
+                uow.update(Sleepy(Duration::ZERO)).unwrap();
+                uow.update(Sleepy(Duration::from_millis(60))).unwrap();
+                uow.update(Sleepy(Duration::ZERO)).unwrap();
+                uow.commit().await.unwrap();
+            })
+        });
+
+        let slow = capture
+            .events()
+            .into_iter()
+            .filter(|event| event.message() == "slow command")
+            .map(|event| event.fields["index"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(slow, ["1"]);
+    }
+
+    #[test]
+    fn replay_commits_a_log_and_names_unknown_aggregates() {
+        block_on(async {
+            let (mut uow, _) = recording();
+            uow.create(User::new(1)).unwrap();
+            uow.create(Shop::new(2)).unwrap();
+            let json = uow.to_json().unwrap();
+
+            let conn = connect().await;
+            replay(&json, conn.clone()).await.unwrap();
+            assert_eq!(ids(&conn, "users").await, [1]);
+            assert_eq!(ids(&conn, "shops").await, [2]);
+
+            let fresh = connect().await;
+            let err = replay(&json.replace("\"Shop\"", "\"Invoice\""), fresh.clone())
+                .await
+                .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "command 2: unknown aggregate kind `Invoice`"
+            );
+            assert!(ids(&fresh, "users").await.is_empty());
+        });
+    }
+
+    /// A provider whose connections take `delay` to acquire, like a saturated pool.
+    struct SlowProvider {
+        conn: DatabaseConnection,
+        delay: Duration,
+    }
+
+    #[async_trait::async_trait]
+    impl ProvideUnitOfWork for SlowProvider {
+        type UnitOfWork = DatabaseClient;
+
+        fn provide(&self) -> Self::UnitOfWork {
+            client(&self.conn)
+        }
+
+        async fn provide_async(&self) -> anyhow::Result<Self::UnitOfWork> {
+            tokio::time::sleep(self.delay).await;
+            Ok(self.provide())
+        }
+    }
+
+    #[test]
+    fn provide_with_timeout_fails_fast_when_acquisition_is_slow() {
+        block_on(async {
+            let slow = SlowProvider {
+                conn: DatabaseConnection::Disconnected,
+                delay: Duration::from_millis(200),
+            };
+            let err = slow
+                .provide_with_timeout(Duration::from_millis(20))
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(
+                err.downcast_ref(),
+                Some(UnitOfWorkError::AcquireTimeout(_))
+            ));
+
+            let quick = SlowProvider {
+                conn: DatabaseConnection::Disconnected,
+                delay: Duration::ZERO,
+            };
+            assert!(quick
+                .provide_with_timeout(Duration::from_millis(20))
+                .await
+                .is_ok());
+        });
+    }
+
+    #[test]
+    fn a_batched_create_counts_as_one_statement() {
+        block_on(async {
+            let mut uow = client(&connect().await);
+            for id in 1..=3 {
+                uow.create(User::new(id)).unwrap();
+            }
+            uow.update(Shop::new(4)).unwrap();
+
+            assert_eq!(uow.commit().await.unwrap().stmt_count, 2);
+        });
+    }
+
+    #[test]
+    fn the_changelog_lists_only_what_ran_after_optimization() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.delete(User::new(1)).unwrap();
+            assert!(uow.commit_with_changelog().await.unwrap().is_empty());
+
+            uow.create(User::new(2)).unwrap();
+            uow.create(Shop::new(3)).unwrap();
+            uow.delete(Shop::new(3)).unwrap();
+            let changes = uow.commit_with_changelog().await.unwrap();
+            let changes = changes
+                .iter()
+                .map(|change| (change.kind, change.operation, change.id))
+                .collect::<Vec<_>>();
+            assert_eq!(changes, [("user", DBOperation::Create, Some(2))]);
+            assert_eq!(ids(&conn, "users").await, [2]);
+        });
+    }
+
+    #[test]
+    fn commit_stream_reports_each_command_then_commits() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            for id in 1..=5 {
+                uow.create(User::new(id)).unwrap();
+            }
+
+            let changes = uow.commit_stream().await.collect::<Vec<_>>().await;
+            let ids_reported = changes
+                .into_iter()
+                .map(|change| change.unwrap().id)
+                .collect::<Vec<_>>();
+            assert_eq!(ids_reported, [1, 2, 3, 4, 5].map(Some));
+            assert_eq!(ids(&conn, "users").await, [1, 2, 3, 4, 5]);
+        });
+    }
+
+    #[test]
+    fn commit_stream_ends_with_the_failure_and_commits_nothing() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.commit().await.unwrap();
+
+            uow.create(Shop::new(2)).unwrap();
+            uow.create(User::new(1)).unwrap();
+            let changes = uow.commit_stream().await.collect::<Vec<_>>().await;
+            assert!(changes.last().unwrap().is_err());
+            assert!(ids(&conn, "shops").await.is_empty());
+        });
+    }
+
+    #[test]
+    fn dropping_a_commit_stream_early_rolls_the_transaction_back() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            // Holds the transaction open after the user is reported.
+            uow.register_hook(
+                AggregateKind::Shop,
+                Box::new(|_, _, _| Box::pin(std::future::pending())),
+            );
+            uow.create(User::new(1)).unwrap();
+            uow.create(Shop::new(2)).unwrap();
+
+            let mut changes = Box::pin(uow.commit_stream().await);
+            assert_eq!(changes.next().await.unwrap().unwrap().id, Some(1));
+            drop(changes);
+            assert!(ids(&conn, "users").await.is_empty());
+        });
+    }
+
+    #[test]
+    fn explain_previews_a_create_without_running_it() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+
+            let sql = uow.explain();
+            assert_eq!(sql.len(), 1);
+            assert!(sql[0].starts_with("INSERT INTO \"users\""), "{}", sql[0]);
+            assert_eq!(uow.pending_len(), 1);
+            assert!(ids(&conn, "users").await.is_empty());
+        });
+    }
+
+    #[test]
+    fn every_invalid_aggregate_is_reported_before_anything_runs() {
+        block_on(async {
+            let (mut uow, executor) = recording();
+            uow.create(User::new(0)).unwrap();
+            uow.create(Shop::new(2)).unwrap();
+            uow.update(Order::new(-3)).unwrap();
+
+            let err = uow.commit().await.unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "invalid aggregates: command 1: id must be positive; \
+                 command 3: id must be positive"
+            );
+            assert!(executor.operations().is_empty());
+            assert_eq!(uow.pending_len(), 3);
+        });
+    }
+
+    #[test]
+    fn latencies_land_in_their_buckets() {
+        let key = (AggregateKind::Product, DBOperation::Upsert);
+        let bucket = |index: usize| {
+            metrics::latency_buckets()
+                .get(&key)
+                .map_or(0, |buckets| buckets[index])
+        };
+        let before = [1, 4, 7].map(bucket);
+        for elapsed in [3, 4, 70, 2_000] {
+            metrics::record_latency(key.0, key.1, Duration::from_millis(elapsed));
+        }
+
+        // The histograms are global, but no other test upserts products.
+        let after = [1, 4, 7].map(bucket);
+        assert_eq!(after[0] - before[0], 2);
+        assert_eq!(after[1] - before[1], 1);
+        assert_eq!(after[2] - before[2], 1);
+    }
+
+    #[test]
+    fn absorb_appends_the_other_queue_in_order() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            let mut helper = client(&conn);
+            helper.update(Shop::new(2)).unwrap();
+            helper.delete(Order::new(3)).unwrap();
+
+            uow.absorb(helper).unwrap();
+            assert_eq!(uow.pending_len(), 3);
+            assert_eq!(
+                uow.summary(),
+                "3 commands: 1 create user, 1 update shop, 1 delete order"
+            );
+
+            let mut limited = client(&conn);
+            limited.set_max_commands(Some(3));
+            assert!(limited.absorb(uow).is_ok());
+            let mut extra = client(&conn);
+            extra.create(User::new(4)).unwrap();
+            assert!(limited.absorb(extra).is_err());
+            assert_eq!(limited.pending_len(), 3);
+        });
+    }
+
+    #[test]
+    fn absorb_refuses_a_client_of_another_connection() {
+        block_on(async {
+            let context = Context::new(connect().await);
+            let mut uow = context.provide();
+            let mut helper = context.provide();
+            helper.create(User::new(1)).unwrap();
+            uow.absorb(helper).unwrap();
+
+            let mut stranger = Context::new(connect().await).provide();
+            stranger.create(User::new(2)).unwrap();
+            let err = uow.absorb(stranger).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "cannot absorb a client of another connection"
+            );
+            assert_eq!(uow.pending_len(), 1);
+        });
+    }
+
+    #[test]
+    fn has_pending_matches_both_kind_and_operation() {
+        let (mut uow, _) = recording();
+        uow.create(Shop::new(1)).unwrap();
+        uow.update(Order::new(2)).unwrap();
+
+        assert!(uow.has_pending(AggregateKind::Shop, DBOperation::Create));
+        assert!(!uow.has_pending(AggregateKind::Order, DBOperation::Create));
+        assert!(!uow.has_pending(AggregateKind::Shop, DBOperation::Update));
+    }
+
+    #[test]
+    fn ensure_schema_creates_every_table_and_can_run_again() {
+        block_on(async {
+            let conn = Database::connect("sqlite::memory:").await.unwrap();
+            let context = Context::new(conn.clone());
+            context.ensure_schema().await.unwrap();
+            context.ensure_schema().await.unwrap();
+
+            let mut uow = context.provide();
+            uow.create(User::new(1)).unwrap();
+            uow.create(Shop::new(2)).unwrap();
+            uow.create(Order::new(3)).unwrap();
+            uow.create(Product::new(4)).unwrap();
+            uow.commit().await.unwrap();
+            assert_eq!(ids(&conn, "products").await, [4]);
+        });
+    }
+
+    #[test]
+    fn the_write_estimate_counts_raw_statements_conservatively() {
+        let (mut uow, _) = recording();
+        assert_eq!(uow.estimated_write_rows(), 0);
+        uow.create(User::new(1)).unwrap();
+        uow.update(Shop::new(2)).unwrap();
+        uow.delete(Order::new(3)).unwrap();
+        assert_eq!(uow.estimated_write_rows(), 3);
+
+        uow.execute_raw("UPDATE users SET created_by = 1", vec![])
+            .unwrap();
+        assert_eq!(uow.estimated_write_rows(), 103);
+    }
+
+    #[test]
+    fn staging_chains_onto_the_queue_and_stops_at_the_first_failure() {
+        let (mut uow, _) = recording();
+        uow.staging()
+            .create(User::new(1))
+            .update(Shop::new(2))
+            .delete(Order::new(3))
+            .done()
+            .unwrap();
+        assert_eq!(
+            uow.summary(),
+            "3 commands: 1 create user, 1 update shop, 1 delete order"
+        );
+
+        uow.set_max_commands(Some(4));
+        let err = uow
+            .staging()
+            .create(User::new(4))
+            .create(User::new(5))
+            .create(User::new(6))
+            .done()
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(UnitOfWorkError::QueueFull(4))
+        ));
+        assert_eq!(uow.pending_len(), 4);
+    }
+
+    #[test]
+    fn statement_timeout_is_skipped_outside_postgres() {
+        let capture = Capture::default();
+        capture.run(|| {
+            block_on(async {
+                let mut uow: DatabaseClient = DatabaseClient::builder()
+                    .conn(connect().await)
+                    .statement_timeout_ms(10)
+                    .build()
+                    .unwrap();
+                uow.create(User::new(1)).unwrap();
+                assert_eq!(uow.commit().await.unwrap().stmt_count, 1);
+            })
+        });
+
+        assert_eq!(
+            capture.messages(Level::DEBUG),
+            ["statement_timeout is not supported; ignoring"]
+        );
+    }
+
+    #[test]
+    fn restore_puts_back_the_queue_as_it_was_snapshotted() {
+        let (mut uow, _) = recording();
+        uow.create(User::new(1)).unwrap();
+        uow.execute_raw("UPDATE users SET created_by = 1", vec![])
+            .unwrap();
+        let snap = uow.snapshot();
+
+        uow.update(Shop::new(2)).unwrap();
+        uow.delete(Order::new(3)).unwrap();
+        uow.restore(snap);
+
+        assert_eq!(uow.pending_len(), 2);
+        assert_eq!(uow.summary(), "1 command: 1 create user");
+    }
+
+    #[test]
+    fn commit_result_tells_an_empty_commit_from_one_that_ran_commands() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            assert_eq!(uow.commit().await.unwrap().result, CommitResult::Empty);
+
+            uow.create(User::new(1)).unwrap();
+            uow.create(Shop::new(2)).unwrap();
+            assert_eq!(
+                uow.commit().await.unwrap().result,
+                CommitResult::Committed { count: 2 }
+            );
+
+            uow.execute_raw("UPDATE users SET created_by = 1", vec![])
+                .unwrap();
+            assert_eq!(
+                uow.commit().await.unwrap().result,
+                CommitResult::Committed { count: 1 }
+            );
+        });
+    }
+
+    #[test]
+    fn the_test_executor_records_every_command_without_a_database() {
+        block_on(async {
+            let (mut uow, executor) = recording();
+            uow.create(User::new(1)).unwrap();
+            uow.update(Shop::new(2)).unwrap();
+            uow.delete(Order::new(3)).unwrap();
+            let report = uow.commit().await.unwrap();
+
+            assert_eq!(report.changes.len(), 3);
+            assert_eq!(
+                executor.operations(),
+                [
+                    (AggregateKind::User, DBOperation::Create, 1),
+                    (AggregateKind::Shop, DBOperation::Update, 2),
+                    (AggregateKind::Order, DBOperation::Delete, 3),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn the_test_executor_rejects_aggregates_it_cannot_name() {
+        block_on(async {
+            let executor = Arc::new(TestExecutor::default());
+            let mut uow: DatabaseClient<Sleepy> = DatabaseClient::builder()
+                .conn(DatabaseConnection::Disconnected)
+                .build()
+                .unwrap()
+                .with_executor(executor.clone());
+            uow.update(Sleepy(Duration::ZERO)).unwrap();
+
+            let err = uow.commit().await.unwrap_err();
+            assert!(format!("{:#}", err).ends_with("command 1: update aggregate failed"));
+            assert!(executor.operations().is_empty());
+        });
+    }
+
+    #[test]
+    fn command_metadata_is_carried_into_the_changes_and_the_event_sink() {
+        block_on(async {
+            let conn = connect().await;
+            let (tx, mut rx) = mpsc::channel(8);
+            let mut uow = client(&conn).with_event_sink(tx);
+            let source = HashMap::from([("source".to_string(), "import".to_string())]);
+            uow.create_with_metadata(User::new(1), source.clone())
+                .unwrap();
+            uow.create(User::new(2)).unwrap();
+            uow.delete_with_metadata(User::new(2), source.clone())
+                .unwrap();
+            let report = uow.commit().await.unwrap();
+
+            let metadata = report
+                .changes
+                .iter()
+                .map(|change| change.metadata.clone())
+                .collect::<Vec<_>>();
+            assert_eq!(metadata, [source.clone(), HashMap::new(), source.clone()]);
+            assert_eq!(rx.recv().await.unwrap().metadata, source);
+        });
+    }
+
+    #[test]
+    fn a_raw_update_runs_after_the_typed_create_in_the_same_commit() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.execute_raw(
+                "UPDATE users SET created_by = ? WHERE id = ?",
+                vec![9i64.into(), 1i64.into()],
+            )
+            .unwrap();
+            let report = uow.commit().await.unwrap();
+            assert_eq!(report.stmt_count, 2);
+
+            let row = conn
+                .query_one(Statement::from_string(
+                    conn.get_database_backend(),
+                    "SELECT created_by FROM users WHERE id = 1",
+                ))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(row.try_get::<i64>("", "created_by").unwrap(), 9);
+        });
+    }
+
+    #[test]
+    fn a_raw_delete_staged_before_a_typed_create_runs_before_it() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.commit().await.unwrap();
+
+            uow.create(Shop::new(2)).unwrap();
+            uow.execute_raw("DELETE FROM users WHERE id = ?", vec![1i64.into()])
+                .unwrap();
+            // A higher priority would run it first, but never ahead of the raw statement.
+            uow.create_with_priority(User::new(1), -1).unwrap();
+            let report = uow.commit().await.unwrap();
+            assert_eq!(report.raw_count, 1);
+            assert_eq!(ids(&conn, "users").await, [1]);
+            assert_eq!(ids(&conn, "shops").await, [2]);
+        });
+    }
+
+    #[test]
+    fn deferred_constraints_let_a_circular_insert_commit_on_postgres() {
+        block_on(async {
+            let Some(conn) = postgres("uow_defer_constraints").await else {
+                return;
+            };
+            conn.execute_unprepared(
+                "CREATE TABLE users (id BIGINT PRIMARY KEY, created_by BIGINT, updated_by BIGINT); \
+                 CREATE TABLE orders (id BIGINT PRIMARY KEY REFERENCES users (id) \
+                 DEFERRABLE, created_by BIGINT, updated_by BIGINT); \
+                 ALTER TABLE users ADD FOREIGN KEY (id) REFERENCES orders (id) DEFERRABLE;",
+            )
+            .await
+            .unwrap();
+
+            let mut uow = client(&conn);
+            uow.create(User::new(1)).unwrap();
+            uow.create(Order::new(1)).unwrap();
+            uow.commit().await.unwrap_err();
+
+            let mut uow: DatabaseClient = DatabaseClient::builder()
+                .conn(conn.clone())
+                .defer_constraints(true)
+                .build()
+                .unwrap();
+            uow.create(User::new(1)).unwrap();
+            uow.create(Order::new(1)).unwrap();
+            uow.commit().await.unwrap();
+            assert_eq!(ids(&conn, "orders").await, [1]);
+        });
+    }
+
+    #[test]
+    fn statement_timeout_cancels_a_slow_statement_on_postgres() {
+        block_on(async {
+            let Some(conn) = postgres("uow_statement_timeout").await else {
+                return;
+            };
+            Context::new(conn.clone()).ensure_schema().await.unwrap();
+            let mut uow: DatabaseClient = DatabaseClient::builder()
+                .conn(conn.clone())
+                .statement_timeout_ms(50)
+                .build()
+                .unwrap();
+            uow.create(User::new(1)).unwrap();
+            uow.execute_raw("SELECT pg_sleep(5)", vec![]).unwrap();
+
+            let started = Instant::now();
+            uow.commit().await.unwrap_err();
+            assert!(started.elapsed() < Duration::from_secs(5));
+            assert!(ids(&conn, "users").await.is_empty());
+        });
+    }
+
+    /// A linear congruential generator, so the property test below is deterministic and
+    /// needs no dependency.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) % n
+        }
+    }
+
+    fn aggregate_of(kind: AggregateKind, id: i64) -> Aggregate {
+        match kind {
+            AggregateKind::User => User::new(id).into(),
+            AggregateKind::Shop => Shop::new(id).into(),
+            AggregateKind::Order => Order::new(id).into(),
+            AggregateKind::Product => Product::new(id).into(),
+        }
+    }
+
+    #[test]
+    fn random_command_sequences_keep_the_dedup_and_ordering_invariants() {
+        const KINDS: [AggregateKind; 4] = [
+            AggregateKind::User,
+            AggregateKind::Shop,
+            AggregateKind::Order,
+            AggregateKind::Product,
+        ];
+        for seed in 0..200 {
+            let mut rng = Lcg(seed);
+            let (mut uow, executor) = recording();
+            let mut kept = Vec::new();
+            let mut keys = HashSet::new();
+            // Creates insert fresh ids and the other commands target rows from before the
+            // commit, so no create is held behind a command on its own aggregate.
+            let mut next_id = 1;
+            for _ in 0..rng.below(16) {
+                let kind = KINDS[rng.below(4) as usize];
+                let (operation, id) = match rng.below(3) {
+                    0 => {
+                        next_id += 1;
+                        (DBOperation::Create, next_id)
+                    }
+                    1 => (DBOperation::Update, 100 + rng.below(4) as i64),
+                    _ => (DBOperation::Delete, 100 + rng.below(4) as i64),
+                };
+                // Deletes take no idempotency key.
+                let key = (operation != DBOperation::Delete && rng.below(3) == 0)
+                    .then(|| format!("k{}", rng.below(4)));
+                let aggregate = aggregate_of(kind, id);
+                match (operation, key.clone()) {
+                    (DBOperation::Create, Some(key)) => uow.create_with_key(aggregate, key),
+                    (DBOperation::Create, None) => uow.create(aggregate),
+                    (DBOperation::Update, Some(key)) => uow.update_with_key(aggregate, key),
+                    (DBOperation::Update, None) => uow.update(aggregate),
+                    _ => uow.delete(aggregate),
+                }
+                .unwrap();
+                if key.is_none_or(|key| keys.insert(key)) {
+                    kept.push((kind, operation, id));
+                }
+            }
+            block_on(uow.commit()).unwrap();
+            let executed = executor.operations();
+
+            let counts = |operations: &[(AggregateKind, DBOperation, i64)]| {
+                let mut counts = HashMap::new();
+                for operation in operations {
+                    *counts.entry(*operation).or_insert(0) += 1;
+                }
+                counts
+            };
+            assert_eq!(counts(&executed), counts(&kept), "seed {}", seed);
+            for (staged, ran) in kept.iter().zip(&executed) {
+                if staged.1 != DBOperation::Create {
+                    assert_eq!(staged, ran, "seed {}", seed);
+                }
+            }
+            let creates = |operations: &[(AggregateKind, DBOperation, i64)]| {
+                operations
+                    .iter()
+                    .filter(|(_, operation, _)| *operation == DBOperation::Create)
+                    .map(|&(kind, _, id)| (kind, id))
+                    .collect::<Vec<_>>()
+            };
+            let ranks = creates(&executed)
+                .into_iter()
+                .map(|(kind, id)| dependency_rank(&aggregate_of(kind, id)))
+                .collect::<Vec<_>>();
+            assert!(ranks.windows(2).all(|w| w[0] <= w[1]), "seed {}", seed);
+            for kind in KINDS {
+                let of_kind = |creates: Vec<(AggregateKind, i64)>| {
+                    creates
+                        .into_iter()
+                        .filter(|(k, _)| *k == kind)
+                        .collect::<Vec<_>>()
+                };
+                assert_eq!(
+                    of_kind(creates(&executed)),
+                    of_kind(creates(&kept)),
+                    "seed {}",
+                    seed
+                );
+            }
+        }
+    }
+
+    fn with_empty_commit_policy(
+        conn: DatabaseConnection,
+        policy: EmptyCommitPolicy,
+    ) -> DatabaseClient {
+        DatabaseClient::builder()
+            .conn(conn)
+            .empty_commit_policy(policy)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn an_empty_commit_succeeds_quietly_under_the_ok_policy() {
+        let capture = Capture::default();
+        capture.run(|| {
+            block_on(async {
+                let mut uow = with_empty_commit_policy(connect().await, EmptyCommitPolicy::Ok);
+                assert_eq!(uow.commit().await.unwrap().result, CommitResult::Empty);
+            })
+        });
+        assert!(capture.messages(Level::WARN).is_empty());
+    }
+
+    #[test]
+    fn an_empty_commit_fails_under_the_error_policy() {
+        block_on(async {
+            let mut uow = with_empty_commit_policy(connect().await, EmptyCommitPolicy::Error);
+            let err = uow.commit().await.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<UnitOfWorkError>(),
+                Some(UnitOfWorkError::EmptyCommit)
+            ));
+        });
+    }
+
+    #[test]
+    fn an_empty_commit_warns_and_succeeds_under_the_warn_policy() {
+        let capture = Capture::default();
+        capture.run(|| {
+            block_on(async {
+                let mut uow = with_empty_commit_policy(connect().await, EmptyCommitPolicy::Warn);
+                assert_eq!(uow.commit().await.unwrap().result, CommitResult::Empty);
+            })
+        });
+        assert_eq!(
+            capture.messages(Level::WARN),
+            ["committing an empty unit of work"]
+        );
     }
 }
@@ -1,10 +1,16 @@
-mod domain {
+pub mod domain {
+    use std::{error::Error as StdError, fmt};
+
     use anyhow::Result;
     use async_trait::async_trait;
     use derive_new::new;
+    use thiserror::Error;
 
-    #[derive(Debug, new)]
-    pub struct User;
+    #[derive(Debug, Clone, new)]
+    pub struct User {
+        pub id: i64,
+        pub name: String,
+    }
 
     impl User {
         pub fn is_valid(&self) -> bool {
@@ -12,185 +18,3702 @@ mod domain {
         }
     }
 
-    #[derive(Debug, new)]
-    pub struct Shop;
+    /// Criteria left as `None` are not applied; an empty filter matches every user.
+    #[derive(Debug, Default, Clone)]
+    pub struct UserFilter {
+        pub id: Option<i64>,
+        pub name: Option<String>,
+    }
+
+    impl UserFilter {
+        pub fn matches(&self, user: &User) -> bool {
+            self.id.is_none_or(|id| user.id == id)
+                && self.name.as_ref().is_none_or(|name| user.name == *name)
+        }
+    }
+
+    /// Columns left as `None` keep their current value.
+    #[derive(Debug, Default, Clone)]
+    pub struct UserChanges {
+        pub name: Option<String>,
+    }
+
+    impl UserChanges {
+        pub fn is_empty(&self) -> bool {
+            self.name.is_none()
+        }
+
+        pub fn apply(&self, user: &mut User) {
+            if let Some(name) = &self.name {
+                user.name = name.clone();
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, new)]
+    pub struct Shop {
+        pub id: i64,
+    }
+
+    #[derive(Debug, Clone, new)]
+    pub struct Order {
+        pub id: i64,
+    }
+
+    #[derive(Debug, Clone, new)]
+    pub struct Invoice {
+        pub id: i64,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum IsolationLevel {
+        ReadUncommitted,
+        ReadCommitted,
+        RepeatableRead,
+        Serializable,
+    }
+
+    #[derive(Debug, Error)]
+    pub enum UnitOfWorkError {
+        #[error("Transaction is already started")]
+        AlreadyStarted,
+        #[error("Transaction is not started")]
+        NotStarted,
+        #[error("Transaction is already committed")]
+        AlreadyCommitted,
+        #[error("Transaction is already rolled back")]
+        AlreadyRolledBack,
+        #[error("Failed to begin transaction")]
+        Begin(#[source] DbError),
+        #[error("Failed to commit transaction")]
+        Commit(#[source] DbError),
+        #[error("Failed to rollback transaction")]
+        Rollback(#[source] DbError),
+        #[error("Failed to create savepoint {0}")]
+        Savepoint(String, #[source] DbError),
+        #[error("Savepoint {0} does not exist")]
+        UnknownSavepoint(String),
+        #[error("Savepoint name {0:?} is not a plain SQL identifier")]
+        InvalidSavepointName(String),
+        #[error("Failed to rollback to savepoint {0}")]
+        RollbackToSavepoint(String, #[source] DbError),
+        #[error("Failed to release savepoint {0}")]
+        ReleaseSavepoint(String, #[source] DbError),
+        #[error("Database connection is not healthy")]
+        Unhealthy(#[source] DbError),
+        #[error("Advisory locks require Postgres")]
+        AdvisoryLockUnsupported,
+        #[error("Failed to acquire advisory lock {0}")]
+        AdvisoryLock(i64, #[source] DbError),
+    }
+
+    /// Lets callers of the repositories tell a missing row from a conflicting write, which
+    /// an opaque `anyhow::Error` cannot.
+    #[derive(Debug, Error)]
+    pub enum RepositoryError {
+        #[error("Record was not found")]
+        NotFound,
+        #[error("Record conflicts with an existing one: {0}")]
+        Conflict(String),
+        #[error("Mutation attempted in read-only transaction")]
+        ReadOnly,
+        #[error(transparent)]
+        UnitOfWork(#[from] UnitOfWorkError),
+        #[error("Database error")]
+        Db(#[source] DbError),
+    }
+
+    /// A failure of whichever database backend is in use, so the domain does not depend on
+    /// sea_orm or sqlx. It displays as the underlying error and keeps its SQLSTATE, if any.
+    #[derive(Debug)]
+    pub struct DbError {
+        code: Option<String>,
+        inner: Box<dyn StdError + Send + Sync>,
+    }
+
+    impl DbError {
+        pub fn new(
+            code: Option<String>,
+            inner: impl Into<Box<dyn StdError + Send + Sync>>,
+        ) -> Self {
+            Self {
+                code,
+                inner: inner.into(),
+            }
+        }
+
+        /// The SQLSTATE the database reported, e.g. `40001`.
+        pub fn code(&self) -> Option<&str> {
+            self.code.as_deref()
+        }
+
+        /// Whether rerunning the whole unit of work may succeed: the database reported a
+        /// serialization failure (SQLSTATE 40001) or a deadlock (40P01).
+        pub fn is_retryable(&self) -> bool {
+            matches!(self.code(), Some("40001" | "40P01"))
+        }
+
+        /// The backend's own error, e.g. a `sea_orm::DbErr` or a `sqlx::Error`.
+        pub fn get_ref(&self) -> &(dyn StdError + Send + Sync + 'static) {
+            &*self.inner
+        }
+    }
+
+    impl fmt::Display for DbError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.inner.fmt(f)
+        }
+    }
+
+    impl StdError for DbError {
+        fn source(&self) -> Option<&(dyn StdError + 'static)> {
+            self.inner.source()
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum UnitOfWorkState {
+        #[default]
+        Idle,
+        Active,
+        Committed,
+        RolledBack,
+    }
+
+    impl UnitOfWorkError {
+        /// Whether the commit failed in a way that `DbError::is_retryable` accepts.
+        pub fn is_retryable(&self) -> bool {
+            matches!(self, Self::Commit(e) if e.is_retryable())
+        }
+    }
+
+    /// Savepoint names are spliced into the SQL, so only `[A-Za-z_][A-Za-z0-9_]*` is accepted.
+    pub fn check_savepoint_name(name: &str) -> Result<(), UnitOfWorkError> {
+        let mut chars = name.chars();
+        let valid = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(UnitOfWorkError::InvalidSavepointName(name.to_string()));
+        }
+        Ok(())
+    }
+
+    impl UnitOfWorkState {
+        /// The error for finishing a transaction that is not active, naming why it is not.
+        pub fn not_active_error(self) -> UnitOfWorkError {
+            match self {
+                Self::Committed => UnitOfWorkError::AlreadyCommitted,
+                Self::RolledBack => UnitOfWorkError::AlreadyRolledBack,
+                Self::Idle | Self::Active => UnitOfWorkError::NotStarted,
+            }
+        }
+    }
 
-    #[derive(Debug, new)]
-    pub struct Order;
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum CommitOutcome {
+        Committed,
+        RolledBack,
+    }
 
     #[async_trait]
-    pub trait UnitOfWork {
-        async fn begin(&mut self) -> Result<()>;
-        async fn commit(&mut self) -> Result<()>;
-        async fn rollback(&mut self) -> Result<()>;
+    pub trait UnitOfWork: Send {
+        async fn begin(&mut self) -> Result<(), UnitOfWorkError>;
+        async fn begin_with_isolation(
+            &mut self,
+            level: IsolationLevel,
+        ) -> Result<(), UnitOfWorkError>;
+        async fn begin_read_only(&mut self) -> Result<(), UnitOfWorkError>;
+        async fn commit(&mut self) -> Result<(), UnitOfWorkError>;
+        async fn rollback(&mut self) -> Result<(), UnitOfWorkError>;
+        async fn savepoint(&mut self, name: &str) -> Result<(), UnitOfWorkError>;
+        async fn rollback_to(&mut self, name: &str) -> Result<(), UnitOfWorkError>;
+        fn is_transaction_active(&self) -> bool;
+
+        async fn commit_if<F>(&mut self, predicate: F) -> Result<CommitOutcome, UnitOfWorkError>
+        where
+            F: Fn() -> bool + Send,
+        {
+            if predicate() {
+                self.commit().await?;
+                Ok(CommitOutcome::Committed)
+            } else {
+                self.rollback().await?;
+                Ok(CommitOutcome::RolledBack)
+            }
+        }
+    }
+
+    /// Commits `first` and, only if that succeeds, `second`. Each side is begun here unless
+    /// the caller already opened it to stage work. These are two separate transactions: a
+    /// failure in `second` does not roll back `first`.
+    pub async fn chain<A, B>(first: &mut A, second: &mut B) -> Result<(), UnitOfWorkError>
+    where
+        A: UnitOfWork,
+        B: UnitOfWork,
+    {
+        finish(first).await?;
+        finish(second).await
+    }
+
+    async fn finish<U: UnitOfWork>(uow: &mut U) -> Result<(), UnitOfWorkError> {
+        if !uow.is_transaction_active() {
+            uow.begin().await?;
+        }
+        uow.commit().await
     }
 
     #[async_trait]
     pub trait UserRepository {
-        async fn create_user(&self, user: User) -> Result<User>;
-        async fn update_user(&self, user: User) -> Result<User>;
-        async fn delete_user(&self, user: User) -> Result<()>;
+        async fn find_user(&self, id: i64) -> Result<Option<User>>;
+        async fn create_user(&self, user: User) -> Result<User, RepositoryError>;
+        async fn update_user(&self, user: User) -> Result<User, RepositoryError>;
+        async fn delete_user(&self, user: User) -> Result<(), RepositoryError>;
+        async fn delete_users_where(&self, filter: UserFilter) -> Result<u64, RepositoryError>;
+        /// Counts through the active transaction, so uncommitted writes are included.
+        async fn count_users(&self, filter: UserFilter) -> Result<u64>;
+        async fn update_user_where(
+            &self,
+            changes: UserChanges,
+            filter: UserFilter,
+        ) -> Result<u64, RepositoryError>;
     }
 
     #[async_trait]
     pub trait ShopRepository {
-        async fn create_shop(&self, shop: Shop) -> Result<Shop>;
-        async fn update_shop(&self, shop: Shop) -> Result<Shop>;
-        async fn delete_shop(&self, shop: Shop) -> Result<()>;
+        async fn find_shop(&self, id: i64) -> Result<Option<Shop>>;
+        async fn create_shop(&self, shop: Shop) -> Result<Shop, RepositoryError>;
+        async fn update_shop(&self, shop: Shop) -> Result<Shop, RepositoryError>;
+        async fn delete_shop(&self, shop: Shop) -> Result<(), RepositoryError>;
     }
 
     #[async_trait]
     pub trait OrderRepository {
-        async fn create_order(&self, order: Order) -> Result<Order>;
-        async fn update_order(&self, order: Order) -> Result<Order>;
-        async fn delete_order(&self, order: Order) -> Result<()>;
+        async fn find_order(&self, id: i64) -> Result<Option<Order>>;
+        async fn create_order(&self, order: Order) -> Result<Order, RepositoryError>;
+        async fn update_order(&self, order: Order) -> Result<Order, RepositoryError>;
+        async fn delete_order(&self, order: Order) -> Result<(), RepositoryError>;
+    }
+
+    #[async_trait]
+    pub trait InvoiceRepository {
+        async fn create_invoice(&self, invoice: Invoice) -> Result<Invoice, RepositoryError>;
+        async fn update_invoice(&self, invoice: Invoice) -> Result<Invoice, RepositoryError>;
+        async fn delete_invoice(&self, invoice: Invoice) -> Result<(), RepositoryError>;
     }
 }
 
-mod infrastructure {
-    use super::domain::{
-        Order, OrderRepository, Shop, ShopRepository, UnitOfWork, User, UserRepository,
+/// The same `UnitOfWork` contract declared with native `async fn` in traits, avoiding the
+/// per-call boxing of `#[async_trait]`. `domain` is kept because a trait with native
+/// `async fn` is not object safe, and `dyn UnitOfWork` needs the boxed futures.
+pub mod domain_native {
+    use std::future::Future;
+
+    use super::domain::{IsolationLevel, UnitOfWorkError};
+
+    pub trait UnitOfWork {
+        fn begin(&mut self) -> impl Future<Output = Result<(), UnitOfWorkError>> + Send;
+        fn begin_with_isolation(
+            &mut self,
+            level: IsolationLevel,
+        ) -> impl Future<Output = Result<(), UnitOfWorkError>> + Send;
+        fn begin_read_only(&mut self) -> impl Future<Output = Result<(), UnitOfWorkError>> + Send;
+        fn commit(&mut self) -> impl Future<Output = Result<(), UnitOfWorkError>> + Send;
+        fn rollback(&mut self) -> impl Future<Output = Result<(), UnitOfWorkError>> + Send;
+        fn savepoint(
+            &mut self,
+            name: &str,
+        ) -> impl Future<Output = Result<(), UnitOfWorkError>> + Send;
+        fn rollback_to(
+            &mut self,
+            name: &str,
+        ) -> impl Future<Output = Result<(), UnitOfWorkError>> + Send;
+        fn is_transaction_active(&self) -> bool;
+    }
+}
+
+pub mod metrics {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// Every unit of work counts into `GLOBAL`. One given its own `Metrics` with
+    /// `UnitOfWorkImpl::with_metrics` counts there too, so a service or a test can read
+    /// its own counters without the rest of the process in them.
+    #[derive(Debug, Default)]
+    pub struct Metrics {
+        commits: AtomicU64,
+        rollbacks: AtomicU64,
+        commit_errors: AtomicU64,
+    }
+
+    pub static GLOBAL: Metrics = Metrics::new();
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MetricsSnapshot {
+        pub commits: u64,
+        pub rollbacks: u64,
+        pub commit_errors: u64,
+    }
+
+    // Only `UnitOfWorkImpl` records anything.
+    #[cfg_attr(not(feature = "backend-sea-orm"), allow(dead_code))]
+    impl Metrics {
+        pub const fn new() -> Self {
+            Self {
+                commits: AtomicU64::new(0),
+                rollbacks: AtomicU64::new(0),
+                commit_errors: AtomicU64::new(0),
+            }
+        }
+
+        pub fn snapshot(&self) -> MetricsSnapshot {
+            MetricsSnapshot {
+                commits: self.commits.load(Ordering::Relaxed),
+                rollbacks: self.rollbacks.load(Ordering::Relaxed),
+                commit_errors: self.commit_errors.load(Ordering::Relaxed),
+            }
+        }
+
+        pub(crate) fn record_commit(&self, succeeded: bool) {
+            let counter = if succeeded {
+                &self.commits
+            } else {
+                &self.commit_errors
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub(crate) fn record_rollback(&self) {
+            self.rollbacks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot() -> MetricsSnapshot {
+        GLOBAL.snapshot()
+    }
+}
+
+#[cfg(feature = "backend-sea-orm")]
+pub mod infrastructure {
+    use super::{
+        domain::{
+            check_savepoint_name, DbError, Invoice, InvoiceRepository, IsolationLevel, Order,
+            OrderRepository, RepositoryError, Shop, ShopRepository, UnitOfWork, UnitOfWorkError,
+            UnitOfWorkState, User, UserChanges, UserFilter, UserRepository,
+        },
+        domain_native,
+        metrics::{self, Metrics},
     };
 
-    use anyhow::{bail, Context};
+    use std::{
+        any::Any,
+        future::Future,
+        ops::{Deref, DerefMut},
+        panic::{self, AssertUnwindSafe},
+        pin::Pin,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        task::{Context as TaskContext, Poll},
+        thread,
+        time::Instant,
+    };
+
+    use anyhow::anyhow;
     use async_trait::async_trait;
     use derive_new::new;
-    use sea_orm::{prelude::DatabaseConnection, DatabaseTransaction, TransactionTrait};
+    use sea_orm::{
+        prelude::{DatabaseConnection, DbErr, Expr},
+        AccessMode, ColumnTrait, Condition, ConnectionTrait, DatabaseBackend, DatabaseTransaction,
+        EntityTrait, NotSet, PaginatorTrait, QueryFilter, QueryTrait, RuntimeErr, Select, Set,
+        SqlErr, Statement, TransactionTrait,
+    };
+    use tracing::{field::Empty, Span};
+
+    mod users {
+        use sea_orm::entity::prelude::*;
+
+        #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+        #[sea_orm(table_name = "users")]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub id: i64,
+            pub name: String,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    mod shops {
+        use sea_orm::entity::prelude::*;
+
+        #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+        #[sea_orm(table_name = "shops")]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub id: i64,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    mod orders {
+        use sea_orm::entity::prelude::*;
+
+        #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+        #[sea_orm(table_name = "orders")]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub id: i64,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    mod invoices {
+        use sea_orm::entity::prelude::*;
+
+        #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+        #[sea_orm(table_name = "invoices")]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub id: i64,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    fn user_condition(filter: UserFilter) -> Condition {
+        Condition::all()
+            .add_option(filter.id.map(|id| users::Column::Id.eq(id)))
+            .add_option(filter.name.map(|name| users::Column::Name.eq(name)))
+    }
+
+    impl From<DbErr> for DbError {
+        fn from(err: DbErr) -> Self {
+            let code = match &err {
+                DbErr::Conn(RuntimeErr::SqlxError(e))
+                | DbErr::Exec(RuntimeErr::SqlxError(e))
+                | DbErr::Query(RuntimeErr::SqlxError(e)) => e
+                    .as_database_error()
+                    .and_then(|e| e.code())
+                    .map(|code| code.into_owned()),
+                _ => None,
+            };
+            DbError::new(code, err)
+        }
+    }
+
+    impl From<DbErr> for RepositoryError {
+        fn from(err: DbErr) -> Self {
+            if let Some(SqlErr::UniqueConstraintViolation(message)) = err.sql_err() {
+                return Self::Conflict(message);
+            }
+            match err {
+                DbErr::RecordNotFound(_) | DbErr::RecordNotUpdated => Self::NotFound,
+                err => Self::Db(err.into()),
+            }
+        }
+    }
+
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+    fn next_id() -> u64 {
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn record_outcome<T, E>(result: &Result<T, E>) {
+        Span::current().record("outcome", if result.is_ok() { "ok" } else { "err" });
+    }
 
     #[derive(new)]
     pub struct UnitOfWorkImpl {
+        #[new(value = "next_id()")]
+        id: u64,
         conn: DatabaseConnection,
         txn: Option<DatabaseTransaction>,
+        #[new(default)]
+        savepoints: Vec<String>,
+        #[new(default)]
+        read_only: bool,
+        #[new(default)]
+        check_health: bool,
+        #[new(default)]
+        state: UnitOfWorkState,
+        #[new(default)]
+        correlation_id: Option<String>,
+        #[new(default)]
+        non_transactional: bool,
+        #[new(default)]
+        non_transactional_fallback: Option<fn(&DbErr) -> bool>,
+        #[new(default)]
+        metrics: Option<Arc<Metrics>>,
     }
 
-    #[async_trait]
-    impl UnitOfWork for UnitOfWorkImpl {
-        async fn begin(&mut self) -> anyhow::Result<()> {
-            if self.txn.is_none() {
-                let txn = self
+    impl UnitOfWorkImpl {
+        pub fn state(&self) -> UnitOfWorkState {
+            self.state
+        }
+
+        /// Also counts commits and rollbacks into `metrics`, besides `metrics::GLOBAL`.
+        pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+            self.metrics = Some(metrics);
+            self
+        }
+
+        fn metrics(&self) -> impl Iterator<Item = &Metrics> {
+            std::iter::once(&metrics::GLOBAL).chain(self.metrics.as_deref())
+        }
+
+        /// Tags every span of this unit of work with `correlation_id` and, on Postgres, sets
+        /// it as the transaction's `application_name` so it also shows in `pg_stat_activity`.
+        pub fn with_correlation_id(mut self, correlation_id: impl Into<String>) -> Self {
+            self.correlation_id = Some(correlation_id.into());
+            self
+        }
+
+        /// Lets `begin` carry on without a transaction, every write then committing on its
+        /// own, when `is_unsupported` says the begin error means the backend has none. sea_orm
+        /// has no dedicated error for that, so the caller decides. Never applied when
+        /// `RepeatableRead` or `Serializable` was requested, as those cannot be honoured.
+        pub fn with_non_transactional_fallback(
+            mut self,
+            is_unsupported: fn(&DbErr) -> bool,
+        ) -> Self {
+            self.non_transactional_fallback = Some(is_unsupported);
+            self
+        }
+
+        fn falls_back(&self, level: Option<IsolationLevel>, err: &DbErr) -> bool {
+            let strict = matches!(
+                level,
+                Some(IsolationLevel::RepeatableRead | IsolationLevel::Serializable)
+            );
+            !strict
+                && self
+                    .non_transactional_fallback
+                    .is_some_and(|is_unsupported| is_unsupported(err))
+        }
+
+        /// Makes every `begin` run `ensure_connected` first, so a stale pooled connection
+        /// fails with `Unhealthy` instead of an opaque begin error.
+        pub fn with_health_check(mut self, enabled: bool) -> Self {
+            self.check_health = enabled;
+            self
+        }
+
+        pub async fn ensure_connected(&self) -> Result<(), UnitOfWorkError> {
+            self.conn
+                .execute_unprepared("SELECT 1")
+                .await
+                .map(|_| ())
+                .map_err(|e| UnitOfWorkError::Unhealthy(e.into()))
+        }
+
+        #[tracing::instrument(
+            name = "begin",
+            skip(self),
+            fields(
+                uow.id = self.id,
+                correlation_id = self.correlation_id.as_deref(),
+                outcome = Empty
+            )
+        )]
+        async fn begin_with_config(
+            &mut self,
+            level: Option<IsolationLevel>,
+            access_mode: Option<AccessMode>,
+        ) -> Result<(), UnitOfWorkError> {
+            let health = if self.check_health {
+                self.ensure_connected().await
+            } else {
+                Ok(())
+            };
+            let result = if let Err(e) = health {
+                Err(e)
+            } else if self.txn.is_none() && !self.non_transactional {
+                match self
                     .conn
-                    .begin()
+                    .begin_with_config(level.map(Into::into), access_mode)
+                    .await
+                {
+                    Ok(txn) => match self.tag_application_name(&txn).await {
+                        Ok(()) => {
+                            self.txn = Some(txn);
+                            self.read_only = access_mode == Some(AccessMode::ReadOnly);
+                            self.state = UnitOfWorkState::Active;
+                            Ok(())
+                        }
+                        Err(e) => Err(UnitOfWorkError::Begin(e.into())),
+                    },
+                    Err(e) if self.falls_back(level, &e) => {
+                        tracing::warn!(
+                            uow.id = self.id,
+                            error = %e,
+                            "transactions are unsupported; continuing without one"
+                        );
+                        self.non_transactional = true;
+                        self.read_only = access_mode == Some(AccessMode::ReadOnly);
+                        self.state = UnitOfWorkState::Active;
+                        Ok(())
+                    }
+                    Err(e) => Err(UnitOfWorkError::Begin(e.into())),
+                }
+            } else {
+                Err(UnitOfWorkError::AlreadyStarted)
+            };
+            record_outcome(&result);
+            result
+        }
+
+        /// `set_config(.., true)` scopes the name to the transaction, so it does not leak to
+        /// the next user of the pooled connection. Unlike `SET LOCAL` it takes the id as a
+        /// bound parameter.
+        async fn tag_application_name(&self, txn: &DatabaseTransaction) -> Result<(), DbErr> {
+            match &self.correlation_id {
+                Some(id) if txn.get_database_backend() == DatabaseBackend::Postgres => txn
+                    .execute(application_name_statement(id))
+                    .await
+                    .map(|_| ()),
+                _ => Ok(()),
+            }
+        }
+
+        /// Joins the active transaction through a savepoint, or starts a new transaction
+        /// when there is none, so a service can own its unit of work either way.
+        pub async fn nested(&mut self) -> Result<NestedUnitOfWork<'_>, UnitOfWorkError> {
+            if self.txn.is_none() {
+                self.begin().await?;
+                return Ok(NestedUnitOfWork {
+                    parent: self,
+                    savepoint: None,
+                });
+            }
+            let name = format!("nested_{}", self.savepoints.len() + 1);
+            self.savepoint(&name).await?;
+            Ok(NestedUnitOfWork {
+                parent: self,
+                savepoint: Some(name),
+            })
+        }
+
+        pub async fn transaction(&mut self) -> Result<TransactionGuard<'_>, UnitOfWorkError> {
+            self.begin().await?;
+            Ok(TransactionGuard {
+                parent: self,
+                finished: false,
+            })
+        }
+
+        /// Like `transaction`, but also survives a panic in `f`: the panic is caught, the
+        /// transaction is rolled back explicitly and the panic is returned as an error.
+        pub async fn run_txn<T, F>(&mut self, f: F) -> anyhow::Result<T>
+        where
+            F: for<'a> FnOnce(
+                &'a mut Self,
+            )
+                -> Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>,
+        {
+            self.begin().await?;
+            match CatchUnwind(f(self)).await {
+                Ok(Ok(value)) => {
+                    self.commit().await?;
+                    Ok(value)
+                }
+                Ok(Err(err)) => match self.rollback().await {
+                    Ok(()) => Err(err),
+                    Err(rollback) => {
+                        Err(err.context(format!("rollback also failed: {}", rollback)))
+                    }
+                },
+                Err(payload) => {
+                    let err = anyhow!("transaction panicked: {}", panic_message(&*payload));
+                    match self.rollback().await {
+                        Ok(()) => Err(err),
+                        Err(rollback) => {
+                            Err(err.context(format!("rollback also failed: {}", rollback)))
+                        }
+                    }
+                }
+            }
+        }
+
+        /// Runs `f` inside a savepoint of the active transaction. If `f` fails, only its
+        /// writes are rolled back and the error is returned; the outer transaction stays
+        /// open, so a caller can skip one invalid operation and still commit the others.
+        pub async fn try_with_savepoint<T, F>(&mut self, f: F) -> anyhow::Result<T>
+        where
+            F: for<'a> FnOnce(
+                &'a mut Self,
+            )
+                -> Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>,
+        {
+            let name = format!("attempt_{}", self.savepoints.len() + 1);
+            self.create_savepoint(&name).await?;
+            match f(self).await {
+                Ok(value) => {
+                    self.release(&name).await?;
+                    Ok(value)
+                }
+                Err(err) => {
+                    if let Err(rollback) = self.rollback_to_savepoint(&name).await {
+                        return Err(err.context(format!("rollback also failed: {}", rollback)));
+                    }
+                    if let Err(release) = self.release(&name).await {
+                        return Err(err.context(format!("release also failed: {}", release)));
+                    }
+                    Err(err)
+                }
+            }
+        }
+
+        fn end_non_transactional(&mut self, state: UnitOfWorkState) {
+            self.non_transactional = false;
+            self.read_only = false;
+            self.state = state;
+        }
+
+        /// Writes go to the open transaction, or straight to the connection when `begin`
+        /// fell back to non-transactional mode.
+        async fn execute(&self, stmt: Statement) -> Result<u64, RepositoryError> {
+            let result = match &self.txn {
+                Some(txn) => txn.execute(stmt).await,
+                None if self.non_transactional => self.conn.execute(stmt).await,
+                None => return Err(UnitOfWorkError::NotStarted.into()),
+            };
+            Ok(result?.rows_affected())
+        }
+
+        /// Reads go through the open transaction when there is one, so staged writes are
+        /// visible before commit.
+        async fn one<E: EntityTrait>(&self, select: Select<E>) -> Result<Option<E::Model>, DbErr> {
+            match &self.txn {
+                Some(txn) => select.one(txn).await,
+                None => select.one(&self.conn).await,
+            }
+        }
+
+        async fn count<E: EntityTrait>(&self, select: Select<E>) -> Result<u64, DbErr>
+        where
+            E::Model: Sync,
+        {
+            match &self.txn {
+                Some(txn) => select.count(txn).await,
+                None => select.count(&self.conn).await,
+            }
+        }
+
+        async fn release(&mut self, name: &str) -> Result<(), UnitOfWorkError> {
+            let Some(txn) = self.txn.as_ref() else {
+                return Err(UnitOfWorkError::NotStarted);
+            };
+            let Some(position) = self.savepoints.iter().rposition(|s| s == name) else {
+                return Err(UnitOfWorkError::UnknownSavepoint(name.to_string()));
+            };
+            txn.execute_unprepared(&format!("RELEASE SAVEPOINT {}", name))
+                .await
+                .map_err(|e| UnitOfWorkError::ReleaseSavepoint(name.to_string(), e.into()))?;
+            self.savepoints.truncate(position);
+            Ok(())
+        }
+
+        #[tracing::instrument(
+            name = "commit",
+            skip(self),
+            fields(
+                uow.id = self.id,
+                correlation_id = self.correlation_id.as_deref(),
+                outcome = Empty,
+                elapsed_ms = Empty
+            )
+        )]
+        async fn commit_txn(&mut self) -> Result<(), UnitOfWorkError> {
+            let started = Instant::now();
+            let result = if let Some(txn) = self.txn.take() {
+                self.savepoints.clear();
+                self.read_only = false;
+                let result = txn
+                    .commit()
+                    .await
+                    .map_err(|e| UnitOfWorkError::Commit(e.into()));
+                self.state = match result {
+                    Ok(_) => UnitOfWorkState::Committed,
+                    Err(_) => UnitOfWorkState::Idle,
+                };
+                result
+            } else if self.non_transactional {
+                tracing::warn!(
+                    uow.id = self.id,
+                    "no transaction to commit; writes were applied as they ran"
+                );
+                self.end_non_transactional(UnitOfWorkState::Committed);
+                Ok(())
+            } else {
+                Err(self.state.not_active_error())
+            };
+            Span::current().record("elapsed_ms", started.elapsed().as_millis() as u64);
+            record_outcome(&result);
+            for metrics in self.metrics() {
+                metrics.record_commit(result.is_ok());
+            }
+            result
+        }
+
+        #[tracing::instrument(
+            name = "rollback",
+            skip(self),
+            fields(
+                uow.id = self.id,
+                correlation_id = self.correlation_id.as_deref(),
+                outcome = Empty
+            )
+        )]
+        async fn rollback_txn(&mut self) -> Result<(), UnitOfWorkError> {
+            let result = if let Some(txn) = self.txn.take() {
+                self.savepoints.clear();
+                self.read_only = false;
+                let result = txn
+                    .rollback()
                     .await
-                    .with_context(|| "Failed to begin transaction")?;
-                self.txn = Some(txn);
+                    .map_err(|e| UnitOfWorkError::Rollback(e.into()));
+                self.state = match result {
+                    Ok(_) => UnitOfWorkState::RolledBack,
+                    Err(_) => UnitOfWorkState::Idle,
+                };
+                result
+            } else if self.non_transactional {
+                tracing::warn!(
+                    uow.id = self.id,
+                    "no transaction to roll back; writes were already applied"
+                );
+                self.end_non_transactional(UnitOfWorkState::RolledBack);
                 Ok(())
             } else {
-                bail!("Transaction is already started")
+                Err(self.state.not_active_error())
+            };
+            record_outcome(&result);
+            if result.is_ok() {
+                self.metrics().for_each(Metrics::record_rollback);
+            }
+            result
+        }
+
+        /// Blocks until the transaction-scoped Postgres advisory lock `key` is held. Postgres
+        /// releases it on commit or rollback, so there is no unlock.
+        pub async fn advisory_lock(&self, key: i64) -> Result<(), UnitOfWorkError> {
+            let Some(txn) = self.txn.as_ref() else {
+                return Err(UnitOfWorkError::NotStarted);
+            };
+            if txn.get_database_backend() != DatabaseBackend::Postgres {
+                return Err(UnitOfWorkError::AdvisoryLockUnsupported);
             }
+            let stmt = Statement::from_sql_and_values(
+                DatabaseBackend::Postgres,
+                "SELECT pg_advisory_xact_lock($1)",
+                [key.into()],
+            );
+            txn.execute(stmt)
+                .await
+                .map(|_| ())
+                .map_err(|e| UnitOfWorkError::AdvisoryLock(key, e.into()))
         }
 
-        async fn commit(&mut self) -> anyhow::Result<()> {
-            if let Some(txn) = self.txn.take() {
-                txn.commit()
+        /// Only names that passed `check_savepoint_name` here ever reach the stack, so
+        /// `rollback_to_savepoint` and `release`, which look the name up first, can splice it
+        /// into their SQL as well.
+        async fn create_savepoint(&mut self, name: &str) -> Result<(), UnitOfWorkError> {
+            check_savepoint_name(name)?;
+            if let Some(txn) = self.txn.as_ref() {
+                txn.execute_unprepared(&format!("SAVEPOINT {}", name))
                     .await
-                    .with_context(|| "Failed to commit transaction")?;
+                    .map_err(|e| UnitOfWorkError::Savepoint(name.to_string(), e.into()))?;
+                self.savepoints.push(name.to_string());
                 Ok(())
             } else {
-                bail!("Transaction is not started")
+                Err(UnitOfWorkError::NotStarted)
             }
         }
 
-        async fn rollback(&mut self) -> anyhow::Result<()> {
-            if let Some(txn) = self.txn.take() {
-                txn.rollback()
+        async fn rollback_to_savepoint(&mut self, name: &str) -> Result<(), UnitOfWorkError> {
+            if let Some(txn) = self.txn.as_ref() {
+                let Some(position) = self.savepoints.iter().rposition(|s| s == name) else {
+                    return Err(UnitOfWorkError::UnknownSavepoint(name.to_string()));
+                };
+                txn.execute_unprepared(&format!("ROLLBACK TO SAVEPOINT {}", name))
                     .await
-                    .with_context(|| "Failed to rollback transaction")?;
+                    .map_err(|e| {
+                        UnitOfWorkError::RollbackToSavepoint(name.to_string(), e.into())
+                    })?;
+                self.savepoints.truncate(position + 1);
                 Ok(())
             } else {
-                bail!("Transaction is not started")
+                Err(UnitOfWorkError::NotStarted)
             }
         }
+
+        fn ensure_writable(&self) -> Result<(), RepositoryError> {
+            if self.read_only {
+                return Err(RepositoryError::ReadOnly);
+            }
+            Ok(())
+        }
     }
 
-    #[async_trait]
-    impl UserRepository for UnitOfWorkImpl {
-        async fn create_user(&self, _user: User) -> anyhow::Result<User> {
-            unimplemented!()
+    pub(super) fn application_name_statement(id: &str) -> Statement {
+        Statement::from_sql_and_values(
+            DatabaseBackend::Postgres,
+            "SELECT set_config('application_name', $1, true)",
+            [id.into()],
+        )
+    }
+
+    pub struct NestedUnitOfWork<'a> {
+        parent: &'a mut UnitOfWorkImpl,
+        savepoint: Option<String>,
+    }
+
+    impl NestedUnitOfWork<'_> {
+        pub async fn commit(self) -> Result<(), UnitOfWorkError> {
+            match &self.savepoint {
+                Some(name) => self.parent.release(name).await,
+                None => self.parent.commit().await,
+            }
         }
 
-        async fn update_user(&self, _user: User) -> anyhow::Result<User> {
-            unimplemented!()
+        pub async fn rollback(self) -> Result<(), UnitOfWorkError> {
+            match &self.savepoint {
+                Some(name) => {
+                    self.parent.rollback_to(name).await?;
+                    self.parent.release(name).await
+                }
+                None => self.parent.rollback().await,
+            }
         }
+    }
 
-        async fn delete_user(&self, _user: User) -> anyhow::Result<()> {
-            unimplemented!()
+    impl Deref for NestedUnitOfWork<'_> {
+        type Target = UnitOfWorkImpl;
+
+        fn deref(&self) -> &Self::Target {
+            self.parent
         }
     }
 
-    #[async_trait]
-    impl ShopRepository for UnitOfWorkImpl {
-        async fn create_shop(&self, _shop: Shop) -> anyhow::Result<Shop> {
-            unimplemented!()
+    impl DerefMut for NestedUnitOfWork<'_> {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            self.parent
         }
+    }
 
-        async fn update_shop(&self, _shop: Shop) -> anyhow::Result<Shop> {
-            unimplemented!()
+    /// Rolls back unless `commit` is called. Async drop is unavailable, so callers are
+    /// expected to end the guard with `commit` or `finish`; a plain drop still rolls back
+    /// through `DatabaseTransaction`'s own `Drop`, but is logged as an error.
+    pub struct TransactionGuard<'a> {
+        parent: &'a mut UnitOfWorkImpl,
+        finished: bool,
+    }
+
+    impl TransactionGuard<'_> {
+        pub async fn commit(mut self) -> Result<(), UnitOfWorkError> {
+            self.finished = true;
+            self.parent.commit().await
         }
 
-        async fn delete_shop(&self, _shop: Shop) -> anyhow::Result<()> {
-            unimplemented!()
+        pub async fn finish(mut self) -> Result<(), UnitOfWorkError> {
+            self.finished = true;
+            self.parent.rollback().await
         }
     }
 
-    #[async_trait]
-    impl OrderRepository for UnitOfWorkImpl {
-        async fn create_order(&self, _order: Order) -> anyhow::Result<Order> {
-            unimplemented!()
+    impl Deref for TransactionGuard<'_> {
+        type Target = UnitOfWorkImpl;
+
+        fn deref(&self) -> &Self::Target {
+            self.parent
         }
+    }
 
-        async fn update_order(&self, _order: Order) -> anyhow::Result<Order> {
-            unimplemented!()
+    impl DerefMut for TransactionGuard<'_> {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            self.parent
         }
+    }
 
-        async fn delete_order(&self, _order: Order) -> anyhow::Result<()> {
-            unimplemented!()
+    impl Drop for TransactionGuard<'_> {
+        fn drop(&mut self) {
+            if !self.finished && self.parent.txn.is_some() {
+                if thread::panicking() {
+                    tracing::error!(
+                        uow.id = self.parent.id,
+                        "panic while a transaction was active; rolling back"
+                    );
+                } else {
+                    tracing::error!(
+                        uow.id = self.parent.id,
+                        "TransactionGuard dropped without commit or finish; rolling back"
+                    );
+                }
+                self.parent.savepoints.clear();
+                self.parent.read_only = false;
+                self.parent.state = UnitOfWorkState::RolledBack;
+                drop(self.parent.txn.take());
+            }
         }
     }
-}
 
-mod context {
-    use sea_orm::DatabaseConnection;
+    /// Polls the inner future inside `catch_unwind`, so a panic in the closure passed to
+    /// `run_txn` surfaces as an `Err` instead of unwinding through the unit of work.
+    struct CatchUnwind<F>(F);
 
-    use super::infrastructure::UnitOfWorkImpl;
+    impl<F: Future + Unpin> Future for CatchUnwind<F> {
+        type Output = thread::Result<F::Output>;
 
-    pub trait ProvideUnitOfWork {
-        type UnitOfWork: super::domain::UnitOfWork + Send + Sync;
-        fn provide(&self) -> Self::UnitOfWork;
+        fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+            let inner = &mut self.get_mut().0;
+            match panic::catch_unwind(AssertUnwindSafe(|| Pin::new(inner).poll(cx))) {
+                Ok(poll) => poll.map(Ok),
+                Err(payload) => Poll::Ready(Err(payload)),
+            }
+        }
     }
 
-    pub struct Context {
-        conn: DatabaseConnection,
+    fn panic_message(payload: &(dyn Any + Send)) -> &str {
+        payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic")
     }
 
-    impl ProvideUnitOfWork for Context {
-        type UnitOfWork = super::infrastructure::UnitOfWorkImpl;
+    /// Spans two databases with one transaction each. This is NOT atomic: `commit` commits
+    /// `first` and then `second`, and if the second commit fails the first has already
+    /// landed. No two-phase commit is attempted; the failure is only logged as a warning so
+    /// the caller (or an operator) can compensate.
+    #[derive(new)]
+    pub struct MultiDbUnitOfWork {
+        first_conn: DatabaseConnection,
+        second_conn: DatabaseConnection,
+        #[new(default)]
+        first: Option<DatabaseTransaction>,
+        #[new(default)]
+        second: Option<DatabaseTransaction>,
+    }
 
-        fn provide(&self) -> Self::UnitOfWork {
-            UnitOfWorkImpl::new(self.conn.clone(), None)
+    impl MultiDbUnitOfWork {
+        /// The open transaction on the first database, for staging work on it.
+        pub fn first(&self) -> Option<&DatabaseTransaction> {
+            self.first.as_ref()
         }
-    }
-}
 
-mod use_case {
-    use super::{
-        context::{Context, ProvideUnitOfWork},
-        domain::{Order, OrderRepository, Shop, ShopRepository, UnitOfWork, User, UserRepository},
-    };
+        pub fn second(&self) -> Option<&DatabaseTransaction> {
+            self.second.as_ref()
+        }
 
-    #[allow(unused)]
-    async fn use_case(context: Context) -> anyhow::Result<()> {
-        let mut uow = context.provide();
+        pub async fn begin(&mut self) -> Result<(), UnitOfWorkError> {
+            if self.first.is_some() || self.second.is_some() {
+                return Err(UnitOfWorkError::AlreadyStarted);
+            }
+            let first = self
+                .first_conn
+                .begin()
+                .await
+                .map_err(|e| UnitOfWorkError::Begin(e.into()))?;
+            let second = self
+                .second_conn
+                .begin()
+                .await
+                .map_err(|e| UnitOfWorkError::Begin(e.into()))?;
+            self.first = Some(first);
+            self.second = Some(second);
+            Ok(())
+        }
 
-        uow.begin().await?;
-        let user = uow.update_user(User::new()).await?;
-        let shop = uow.update_shop(Shop::new()).await?;
-        let order = uow.update_order(Order::new()).await?;
-        if user.is_valid() {
-            uow.commit().await?;
-        } else {
-            uow.rollback().await?;
+        pub async fn commit(&mut self) -> Result<(), UnitOfWorkError> {
+            let (Some(first), Some(second)) = (self.first.take(), self.second.take()) else {
+                return Err(UnitOfWorkError::NotStarted);
+            };
+            if let Err(e) = first.commit().await {
+                if let Err(rollback) = second.rollback().await {
+                    tracing::warn!(
+                        error = %rollback,
+                        "second database failed to roll back after the first failed to commit"
+                    );
+                }
+                return Err(UnitOfWorkError::Commit(e.into()));
+            }
+            second.commit().await.map_err(|e| {
+                tracing::warn!(
+                    error = %e,
+                    "second database failed to commit after the first committed; compensation required"
+                );
+                UnitOfWorkError::Commit(e.into())
+            })
         }
 
-        Ok(())
+        pub async fn rollback(&mut self) -> Result<(), UnitOfWorkError> {
+            let (Some(first), Some(second)) = (self.first.take(), self.second.take()) else {
+                return Err(UnitOfWorkError::NotStarted);
+            };
+            let first = first.rollback().await;
+            let second = second.rollback().await;
+            first
+                .and(second)
+                .map_err(|e| UnitOfWorkError::Rollback(e.into()))
+        }
+    }
+
+    impl From<IsolationLevel> for sea_orm::IsolationLevel {
+        fn from(level: IsolationLevel) -> Self {
+            match level {
+                IsolationLevel::ReadUncommitted => Self::ReadUncommitted,
+                IsolationLevel::ReadCommitted => Self::ReadCommitted,
+                IsolationLevel::RepeatableRead => Self::RepeatableRead,
+                IsolationLevel::Serializable => Self::Serializable,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl UnitOfWork for UnitOfWorkImpl {
+        async fn begin(&mut self) -> Result<(), UnitOfWorkError> {
+            self.begin_with_isolation(IsolationLevel::ReadCommitted)
+                .await
+        }
+
+        async fn begin_with_isolation(
+            &mut self,
+            level: IsolationLevel,
+        ) -> Result<(), UnitOfWorkError> {
+            self.begin_with_config(Some(level), None).await
+        }
+
+        async fn begin_read_only(&mut self) -> Result<(), UnitOfWorkError> {
+            self.begin_with_config(None, Some(AccessMode::ReadOnly))
+                .await
+        }
+
+        async fn commit(&mut self) -> Result<(), UnitOfWorkError> {
+            self.commit_txn().await
+        }
+
+        async fn rollback(&mut self) -> Result<(), UnitOfWorkError> {
+            self.rollback_txn().await
+        }
+
+        async fn savepoint(&mut self, name: &str) -> Result<(), UnitOfWorkError> {
+            self.create_savepoint(name).await
+        }
+
+        async fn rollback_to(&mut self, name: &str) -> Result<(), UnitOfWorkError> {
+            self.rollback_to_savepoint(name).await
+        }
+
+        fn is_transaction_active(&self) -> bool {
+            self.txn.is_some() || self.non_transactional
+        }
+    }
+
+    /// Calls the inherent methods directly, so no future is boxed on this path.
+    impl domain_native::UnitOfWork for UnitOfWorkImpl {
+        async fn begin(&mut self) -> Result<(), UnitOfWorkError> {
+            self.begin_with_config(Some(IsolationLevel::ReadCommitted), None)
+                .await
+        }
+
+        async fn begin_with_isolation(
+            &mut self,
+            level: IsolationLevel,
+        ) -> Result<(), UnitOfWorkError> {
+            self.begin_with_config(Some(level), None).await
+        }
+
+        async fn begin_read_only(&mut self) -> Result<(), UnitOfWorkError> {
+            self.begin_with_config(None, Some(AccessMode::ReadOnly))
+                .await
+        }
+
+        async fn commit(&mut self) -> Result<(), UnitOfWorkError> {
+            self.commit_txn().await
+        }
+
+        async fn rollback(&mut self) -> Result<(), UnitOfWorkError> {
+            self.rollback_txn().await
+        }
+
+        async fn savepoint(&mut self, name: &str) -> Result<(), UnitOfWorkError> {
+            self.create_savepoint(name).await
+        }
+
+        async fn rollback_to(&mut self, name: &str) -> Result<(), UnitOfWorkError> {
+            self.rollback_to_savepoint(name).await
+        }
+
+        fn is_transaction_active(&self) -> bool {
+            self.txn.is_some() || self.non_transactional
+        }
+    }
+
+    /// Async drop is not available, so the actual rollback of an abandoned transaction is
+    /// left to `DatabaseTransaction`'s own `Drop`. This impl only makes the leak observable.
+    impl Drop for UnitOfWorkImpl {
+        fn drop(&mut self) {
+            if self.txn.is_some() && thread::panicking() {
+                tracing::error!(
+                    uow.id = self.id,
+                    "panic while a transaction was active; rolling back"
+                );
+            } else if self.txn.is_some() {
+                tracing::warn!(
+                    uow.id = self.id,
+                    "Uncommitted transaction was abandoned and will be rolled back"
+                );
+            }
+        }
+    }
+
+    #[async_trait]
+    impl UserRepository for UnitOfWorkImpl {
+        async fn find_user(&self, id: i64) -> anyhow::Result<Option<User>> {
+            Ok(self
+                .one(users::Entity::find_by_id(id))
+                .await?
+                .map(|model| User::new(model.id, model.name)))
+        }
+
+        async fn create_user(&self, user: User) -> Result<User, RepositoryError> {
+            self.ensure_writable()?;
+            let model = users::ActiveModel {
+                id: Set(user.id),
+                name: Set(user.name.clone()),
+            };
+            let stmt = users::Entity::insert(model).build(self.conn.get_database_backend());
+            self.execute(stmt).await?;
+            Ok(user)
+        }
+
+        async fn update_user(&self, user: User) -> Result<User, RepositoryError> {
+            self.ensure_writable()?;
+            let model = users::ActiveModel {
+                name: Set(user.name.clone()),
+                ..Default::default()
+            };
+            let stmt = users::Entity::update_many()
+                .set(model)
+                .filter(users::Column::Id.eq(user.id))
+                .build(self.conn.get_database_backend());
+            match self.execute(stmt).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(user),
+            }
+        }
+
+        async fn delete_user(&self, user: User) -> Result<(), RepositoryError> {
+            self.ensure_writable()?;
+            let stmt = users::Entity::delete_by_id(user.id).build(self.conn.get_database_backend());
+            match self.execute(stmt).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(()),
+            }
+        }
+
+        async fn delete_users_where(&self, filter: UserFilter) -> Result<u64, RepositoryError> {
+            self.ensure_writable()?;
+            let stmt = users::Entity::delete_many()
+                .filter(user_condition(filter))
+                .build(self.conn.get_database_backend());
+            self.execute(stmt).await
+        }
+
+        async fn count_users(&self, filter: UserFilter) -> anyhow::Result<u64> {
+            Ok(self
+                .count(users::Entity::find().filter(user_condition(filter)))
+                .await?)
+        }
+
+        /// Compiles to a single `UPDATE users SET ... WHERE ...`; with no changes nothing is
+        /// sent and no rows are reported.
+        async fn update_user_where(
+            &self,
+            changes: UserChanges,
+            filter: UserFilter,
+        ) -> Result<u64, RepositoryError> {
+            self.ensure_writable()?;
+            if !self.is_transaction_active() {
+                return Err(UnitOfWorkError::NotStarted.into());
+            }
+            if changes.is_empty() {
+                return Ok(0);
+            }
+            let model = users::ActiveModel {
+                name: changes.name.map_or(NotSet, Set),
+                ..Default::default()
+            };
+            let stmt = users::Entity::update_many()
+                .set(model)
+                .filter(user_condition(filter))
+                .build(self.conn.get_database_backend());
+            self.execute(stmt).await
+        }
+    }
+
+    #[async_trait]
+    impl ShopRepository for UnitOfWorkImpl {
+        async fn find_shop(&self, id: i64) -> anyhow::Result<Option<Shop>> {
+            Ok(self
+                .one(shops::Entity::find_by_id(id))
+                .await?
+                .map(|model| Shop::new(model.id)))
+        }
+
+        async fn create_shop(&self, shop: Shop) -> Result<Shop, RepositoryError> {
+            self.ensure_writable()?;
+            let model = shops::ActiveModel { id: Set(shop.id) };
+            let stmt = shops::Entity::insert(model).build(self.conn.get_database_backend());
+            self.execute(stmt).await?;
+            Ok(shop)
+        }
+
+        /// There is no column besides the id to write, so this only checks the row exists.
+        async fn update_shop(&self, shop: Shop) -> Result<Shop, RepositoryError> {
+            self.ensure_writable()?;
+            let stmt = shops::Entity::update_many()
+                .col_expr(shops::Column::Id, Expr::col(shops::Column::Id).into())
+                .filter(shops::Column::Id.eq(shop.id))
+                .build(self.conn.get_database_backend());
+            match self.execute(stmt).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(shop),
+            }
+        }
+
+        async fn delete_shop(&self, shop: Shop) -> Result<(), RepositoryError> {
+            self.ensure_writable()?;
+            let stmt = shops::Entity::delete_by_id(shop.id).build(self.conn.get_database_backend());
+            match self.execute(stmt).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl OrderRepository for UnitOfWorkImpl {
+        async fn find_order(&self, id: i64) -> anyhow::Result<Option<Order>> {
+            Ok(self
+                .one(orders::Entity::find_by_id(id))
+                .await?
+                .map(|model| Order::new(model.id)))
+        }
+
+        async fn create_order(&self, order: Order) -> Result<Order, RepositoryError> {
+            self.ensure_writable()?;
+            let model = orders::ActiveModel { id: Set(order.id) };
+            let stmt = orders::Entity::insert(model).build(self.conn.get_database_backend());
+            self.execute(stmt).await?;
+            Ok(order)
+        }
+
+        /// There is no column besides the id to write, so this only checks the row exists.
+        async fn update_order(&self, order: Order) -> Result<Order, RepositoryError> {
+            self.ensure_writable()?;
+            let stmt = orders::Entity::update_many()
+                .col_expr(orders::Column::Id, Expr::col(orders::Column::Id).into())
+                .filter(orders::Column::Id.eq(order.id))
+                .build(self.conn.get_database_backend());
+            match self.execute(stmt).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(order),
+            }
+        }
+
+        async fn delete_order(&self, order: Order) -> Result<(), RepositoryError> {
+            self.ensure_writable()?;
+            let stmt =
+                orders::Entity::delete_by_id(order.id).build(self.conn.get_database_backend());
+            match self.execute(stmt).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl InvoiceRepository for UnitOfWorkImpl {
+        async fn create_invoice(&self, invoice: Invoice) -> Result<Invoice, RepositoryError> {
+            self.ensure_writable()?;
+            let model = invoices::ActiveModel {
+                id: Set(invoice.id),
+            };
+            let stmt = invoices::Entity::insert(model).build(self.conn.get_database_backend());
+            self.execute(stmt).await?;
+            Ok(invoice)
+        }
+
+        /// There is no column besides the id to write, so this only checks the row exists.
+        async fn update_invoice(&self, invoice: Invoice) -> Result<Invoice, RepositoryError> {
+            self.ensure_writable()?;
+            let stmt = invoices::Entity::update_many()
+                .col_expr(invoices::Column::Id, Expr::col(invoices::Column::Id).into())
+                .filter(invoices::Column::Id.eq(invoice.id))
+                .build(self.conn.get_database_backend());
+            match self.execute(stmt).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(invoice),
+            }
+        }
+
+        async fn delete_invoice(&self, invoice: Invoice) -> Result<(), RepositoryError> {
+            self.ensure_writable()?;
+            let stmt =
+                invoices::Entity::delete_by_id(invoice.id).build(self.conn.get_database_backend());
+            match self.execute(stmt).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+/// Alternative backend for callers that do not want sea_orm, running on `sqlx::Any` so the
+/// same code serves Postgres and SQLite. sqlx errors become the domain's `DbError`, keeping
+/// their SQLSTATE.
+#[cfg(feature = "backend-sqlx")]
+pub mod sqlx_infrastructure {
+    use super::domain::{
+        check_savepoint_name, DbError, Invoice, InvoiceRepository, IsolationLevel, Order,
+        OrderRepository, RepositoryError, Shop, ShopRepository, UnitOfWork, UnitOfWorkError,
+        UnitOfWorkState, User, UserChanges, UserFilter, UserRepository,
+    };
+
+    use async_trait::async_trait;
+    use derive_new::new;
+    use sqlx::{
+        any::{AnyArguments, AnyRow},
+        query::Query,
+        Any, AnyPool, Row, Transaction,
+    };
+    use tokio::sync::Mutex;
+
+    impl From<sqlx::Error> for DbError {
+        fn from(err: sqlx::Error) -> Self {
+            let code = err
+                .as_database_error()
+                .and_then(|e| e.code())
+                .map(|code| code.into_owned());
+            DbError::new(code, err)
+        }
+    }
+
+    impl From<sqlx::Error> for RepositoryError {
+        fn from(err: sqlx::Error) -> Self {
+            match &err {
+                sqlx::Error::RowNotFound => Self::NotFound,
+                sqlx::Error::Database(e) if e.is_unique_violation() => {
+                    Self::Conflict(e.message().to_string())
+                }
+                _ => Self::Db(err.into()),
+            }
+        }
+    }
+
+    fn isolation_sql(level: IsolationLevel) -> &'static str {
+        match level {
+            IsolationLevel::ReadUncommitted => "ISOLATION LEVEL READ UNCOMMITTED",
+            IsolationLevel::ReadCommitted => "ISOLATION LEVEL READ COMMITTED",
+            IsolationLevel::RepeatableRead => "ISOLATION LEVEL REPEATABLE READ",
+            IsolationLevel::Serializable => "ISOLATION LEVEL SERIALIZABLE",
+        }
+    }
+
+    /// Placeholders are written `$1`, `$2`, ..., which both Postgres and SQLite accept.
+    fn user_where(filter: &UserFilter, first: usize) -> String {
+        let mut conditions = Vec::new();
+        if filter.id.is_some() {
+            conditions.push(format!("id = ${}", first + conditions.len()));
+        }
+        if filter.name.is_some() {
+            conditions.push(format!("name = ${}", first + conditions.len()));
+        }
+        if conditions.is_empty() {
+            return String::new();
+        }
+        format!(" WHERE {}", conditions.join(" AND "))
+    }
+
+    fn bind_user_filter<'q>(
+        mut query: Query<'q, Any, AnyArguments<'q>>,
+        filter: UserFilter,
+    ) -> Query<'q, Any, AnyArguments<'q>> {
+        if let Some(id) = filter.id {
+            query = query.bind(id);
+        }
+        if let Some(name) = filter.name {
+            query = query.bind(name);
+        }
+        query
+    }
+
+    /// Call `sqlx::any::install_default_drivers` before connecting the pool. The
+    /// transaction sits behind a `Mutex` because sqlx needs `&mut` to run a query on it,
+    /// while the repository traits take `&self`.
+    #[derive(new)]
+    pub struct SqlxUnitOfWork {
+        pool: AnyPool,
+        #[new(default)]
+        txn: Mutex<Option<Transaction<'static, Any>>>,
+        /// `Active` exactly while `txn` is set, so it can be read without taking the lock.
+        #[new(default)]
+        state: UnitOfWorkState,
+        #[new(default)]
+        savepoints: Vec<String>,
+        #[new(default)]
+        read_only: bool,
+    }
+
+    impl SqlxUnitOfWork {
+        pub fn state(&self) -> UnitOfWorkState {
+            self.state
+        }
+
+        /// `setting` is only applied on Postgres; SQLite transactions are always
+        /// serializable and have no read-only mode, so `read_only` is also enforced here on
+        /// every write, whatever the backend.
+        async fn begin_with(
+            &mut self,
+            setting: &str,
+            read_only: bool,
+        ) -> Result<(), UnitOfWorkError> {
+            let slot = self.txn.get_mut();
+            if slot.is_some() {
+                return Err(UnitOfWorkError::AlreadyStarted);
+            }
+            let mut txn = self
+                .pool
+                .begin()
+                .await
+                .map_err(|e| UnitOfWorkError::Begin(e.into()))?;
+            if txn.backend_name() == "PostgreSQL" {
+                sqlx::query(&format!("SET TRANSACTION {}", setting))
+                    .execute(&mut *txn)
+                    .await
+                    .map_err(|e| UnitOfWorkError::Begin(e.into()))?;
+            }
+            *slot = Some(txn);
+            self.read_only = read_only;
+            self.state = UnitOfWorkState::Active;
+            Ok(())
+        }
+
+        /// Clears what only lives as long as the transaction that was just taken from `txn`.
+        fn finish(&mut self) {
+            self.savepoints.clear();
+            self.read_only = false;
+        }
+
+        /// Writes need the open, writable transaction; there is no autocommit fallback.
+        async fn execute<'q>(
+            &self,
+            query: Query<'q, Any, AnyArguments<'q>>,
+        ) -> Result<u64, RepositoryError> {
+            if self.read_only {
+                return Err(RepositoryError::ReadOnly);
+            }
+            let mut txn = self.txn.lock().await;
+            let Some(txn) = txn.as_mut() else {
+                return Err(UnitOfWorkError::NotStarted.into());
+            };
+            Ok(query.execute(&mut **txn).await?.rows_affected())
+        }
+
+        /// Reads go through the open transaction when there is one, so staged writes are
+        /// visible before commit.
+        async fn fetch_optional<'q>(
+            &self,
+            query: Query<'q, Any, AnyArguments<'q>>,
+        ) -> Result<Option<AnyRow>, sqlx::Error> {
+            match self.txn.lock().await.as_mut() {
+                Some(txn) => query.fetch_optional(&mut **txn).await,
+                None => query.fetch_optional(&self.pool).await,
+            }
+        }
+
+        async fn exists(&self, table: &str, id: i64) -> anyhow::Result<bool> {
+            let sql = format!("SELECT id FROM {} WHERE id = $1", table);
+            Ok(self
+                .fetch_optional(sqlx::query(&sql).bind(id))
+                .await?
+                .is_some())
+        }
+
+        async fn insert_id(&self, table: &str, id: i64) -> Result<(), RepositoryError> {
+            let sql = format!("INSERT INTO {} (id) VALUES ($1)", table);
+            self.execute(sqlx::query(&sql).bind(id)).await?;
+            Ok(())
+        }
+
+        /// The row has no column besides the id to write, so this only checks it exists.
+        async fn touch_id(&self, table: &str, id: i64) -> Result<(), RepositoryError> {
+            let sql = format!("UPDATE {} SET id = id WHERE id = $1", table);
+            match self.execute(sqlx::query(&sql).bind(id)).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(()),
+            }
+        }
+
+        async fn delete_id(&self, table: &str, id: i64) -> Result<(), RepositoryError> {
+            let sql = format!("DELETE FROM {} WHERE id = $1", table);
+            match self.execute(sqlx::query(&sql).bind(id)).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl UnitOfWork for SqlxUnitOfWork {
+        async fn begin(&mut self) -> Result<(), UnitOfWorkError> {
+            self.begin_with_isolation(IsolationLevel::ReadCommitted)
+                .await
+        }
+
+        async fn begin_with_isolation(
+            &mut self,
+            level: IsolationLevel,
+        ) -> Result<(), UnitOfWorkError> {
+            self.begin_with(isolation_sql(level), false).await
+        }
+
+        async fn begin_read_only(&mut self) -> Result<(), UnitOfWorkError> {
+            self.begin_with("READ ONLY", true).await
+        }
+
+        async fn commit(&mut self) -> Result<(), UnitOfWorkError> {
+            let Some(txn) = self.txn.get_mut().take() else {
+                return Err(self.state.not_active_error());
+            };
+            self.finish();
+            let result = txn
+                .commit()
+                .await
+                .map_err(|e| UnitOfWorkError::Commit(e.into()));
+            self.state = match result {
+                Ok(_) => UnitOfWorkState::Committed,
+                Err(_) => UnitOfWorkState::Idle,
+            };
+            result
+        }
+
+        async fn rollback(&mut self) -> Result<(), UnitOfWorkError> {
+            let Some(txn) = self.txn.get_mut().take() else {
+                return Err(self.state.not_active_error());
+            };
+            self.finish();
+            let result = txn
+                .rollback()
+                .await
+                .map_err(|e| UnitOfWorkError::Rollback(e.into()));
+            self.state = match result {
+                Ok(_) => UnitOfWorkState::RolledBack,
+                Err(_) => UnitOfWorkState::Idle,
+            };
+            result
+        }
+
+        async fn savepoint(&mut self, name: &str) -> Result<(), UnitOfWorkError> {
+            check_savepoint_name(name)?;
+            let Some(txn) = self.txn.get_mut().as_mut() else {
+                return Err(UnitOfWorkError::NotStarted);
+            };
+            sqlx::query(&format!("SAVEPOINT {}", name))
+                .execute(&mut **txn)
+                .await
+                .map_err(|e| UnitOfWorkError::Savepoint(name.to_string(), e.into()))?;
+            self.savepoints.push(name.to_string());
+            Ok(())
+        }
+
+        async fn rollback_to(&mut self, name: &str) -> Result<(), UnitOfWorkError> {
+            let Some(txn) = self.txn.get_mut().as_mut() else {
+                return Err(UnitOfWorkError::NotStarted);
+            };
+            let Some(position) = self.savepoints.iter().rposition(|s| s == name) else {
+                return Err(UnitOfWorkError::UnknownSavepoint(name.to_string()));
+            };
+            sqlx::query(&format!("ROLLBACK TO SAVEPOINT {}", name))
+                .execute(&mut **txn)
+                .await
+                .map_err(|e| UnitOfWorkError::RollbackToSavepoint(name.to_string(), e.into()))?;
+            self.savepoints.truncate(position + 1);
+            Ok(())
+        }
+
+        fn is_transaction_active(&self) -> bool {
+            self.state == UnitOfWorkState::Active
+        }
+    }
+
+    #[async_trait]
+    impl UserRepository for SqlxUnitOfWork {
+        async fn find_user(&self, id: i64) -> anyhow::Result<Option<User>> {
+            let query = sqlx::query("SELECT id, name FROM users WHERE id = $1").bind(id);
+            let Some(row) = self.fetch_optional(query).await? else {
+                return Ok(None);
+            };
+            Ok(Some(User::new(row.try_get("id")?, row.try_get("name")?)))
+        }
+
+        async fn create_user(&self, user: User) -> Result<User, RepositoryError> {
+            let query = sqlx::query("INSERT INTO users (id, name) VALUES ($1, $2)")
+                .bind(user.id)
+                .bind(user.name.clone());
+            self.execute(query).await?;
+            Ok(user)
+        }
+
+        async fn update_user(&self, user: User) -> Result<User, RepositoryError> {
+            let query = sqlx::query("UPDATE users SET name = $1 WHERE id = $2")
+                .bind(user.name.clone())
+                .bind(user.id);
+            match self.execute(query).await? {
+                0 => Err(RepositoryError::NotFound),
+                _ => Ok(user),
+            }
+        }
+
+        async fn delete_user(&self, user: User) -> Result<(), RepositoryError> {
+            self.delete_id("users", user.id).await
+        }
+
+        async fn delete_users_where(&self, filter: UserFilter) -> Result<u64, RepositoryError> {
+            let sql = format!("DELETE FROM users{}", user_where(&filter, 1));
+            self.execute(bind_user_filter(sqlx::query(&sql), filter))
+                .await
+        }
+
+        async fn count_users(&self, filter: UserFilter) -> anyhow::Result<u64> {
+            let sql = format!(
+                "SELECT COUNT(*) AS count FROM users{}",
+                user_where(&filter, 1)
+            );
+            let row = self
+                .fetch_optional(bind_user_filter(sqlx::query(&sql), filter))
+                .await?
+                .ok_or(sqlx::Error::RowNotFound)?;
+            Ok(row.try_get::<i64, _>("count")? as u64)
+        }
+
+        /// Compiles to a single `UPDATE users SET ... WHERE ...`; with no changes nothing is
+        /// sent and no rows are reported.
+        async fn update_user_where(
+            &self,
+            changes: UserChanges,
+            filter: UserFilter,
+        ) -> Result<u64, RepositoryError> {
+            if !self.is_transaction_active() {
+                return Err(UnitOfWorkError::NotStarted.into());
+            }
+            let Some(name) = changes.name else {
+                return Ok(0);
+            };
+            let sql = format!("UPDATE users SET name = $1{}", user_where(&filter, 2));
+            self.execute(bind_user_filter(sqlx::query(&sql).bind(name), filter))
+                .await
+        }
+    }
+
+    #[async_trait]
+    impl ShopRepository for SqlxUnitOfWork {
+        async fn find_shop(&self, id: i64) -> anyhow::Result<Option<Shop>> {
+            Ok(self.exists("shops", id).await?.then(|| Shop::new(id)))
+        }
+
+        async fn create_shop(&self, shop: Shop) -> Result<Shop, RepositoryError> {
+            self.insert_id("shops", shop.id).await?;
+            Ok(shop)
+        }
+
+        async fn update_shop(&self, shop: Shop) -> Result<Shop, RepositoryError> {
+            self.touch_id("shops", shop.id).await?;
+            Ok(shop)
+        }
+
+        async fn delete_shop(&self, shop: Shop) -> Result<(), RepositoryError> {
+            self.delete_id("shops", shop.id).await
+        }
+    }
+
+    #[async_trait]
+    impl OrderRepository for SqlxUnitOfWork {
+        async fn find_order(&self, id: i64) -> anyhow::Result<Option<Order>> {
+            Ok(self.exists("orders", id).await?.then(|| Order::new(id)))
+        }
+
+        async fn create_order(&self, order: Order) -> Result<Order, RepositoryError> {
+            self.insert_id("orders", order.id).await?;
+            Ok(order)
+        }
+
+        async fn update_order(&self, order: Order) -> Result<Order, RepositoryError> {
+            self.touch_id("orders", order.id).await?;
+            Ok(order)
+        }
+
+        async fn delete_order(&self, order: Order) -> Result<(), RepositoryError> {
+            self.delete_id("orders", order.id).await
+        }
+    }
+
+    #[async_trait]
+    impl InvoiceRepository for SqlxUnitOfWork {
+        async fn create_invoice(&self, invoice: Invoice) -> Result<Invoice, RepositoryError> {
+            self.insert_id("invoices", invoice.id).await?;
+            Ok(invoice)
+        }
+
+        async fn update_invoice(&self, invoice: Invoice) -> Result<Invoice, RepositoryError> {
+            self.touch_id("invoices", invoice.id).await?;
+            Ok(invoice)
+        }
+
+        async fn delete_invoice(&self, invoice: Invoice) -> Result<(), RepositoryError> {
+            self.delete_id("invoices", invoice.id).await
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::SqlxUnitOfWork;
+        use crate::{
+            test_support::block_on,
+            unit_of_work_practical_design::{
+                context::{with_unit_of_work, ProvideUnitOfWork, SqlxContext},
+                domain::{
+                    RepositoryError, Shop, ShopRepository, UnitOfWork, UnitOfWorkError,
+                    UnitOfWorkState, User, UserChanges, UserFilter, UserRepository,
+                },
+            },
+        };
+
+        use sqlx::{any::AnyPoolOptions, AnyPool, Executor};
+
+        /// One connection, so every transaction sees the same in-memory database.
+        async fn connect() -> SqlxUnitOfWork {
+            SqlxUnitOfWork::new(pool().await)
+        }
+
+        async fn pool() -> AnyPool {
+            sqlx::any::install_default_drivers();
+            let pool = AnyPoolOptions::new()
+                .max_connections(1)
+                .connect("sqlite::memory:")
+                .await
+                .unwrap();
+            pool.execute(
+                "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT NOT NULL); \
+                 CREATE TABLE shops (id INTEGER PRIMARY KEY);",
+            )
+            .await
+            .unwrap();
+            pool
+        }
+
+        #[test]
+        fn repositories_write_through_the_transaction() {
+            block_on(async {
+                let mut uow = connect().await;
+                assert!(matches!(
+                    uow.create_shop(Shop::new(1)).await,
+                    Err(RepositoryError::UnitOfWork(UnitOfWorkError::NotStarted))
+                ));
+
+                uow.begin().await.unwrap();
+                uow.create_user(User::new(1, "a".to_string()))
+                    .await
+                    .unwrap();
+                uow.create_user(User::new(2, "b".to_string()))
+                    .await
+                    .unwrap();
+                uow.create_shop(Shop::new(1)).await.unwrap();
+                let renamed = UserChanges {
+                    name: Some("c".to_string()),
+                };
+                let filter = UserFilter {
+                    id: Some(2),
+                    ..Default::default()
+                };
+                assert_eq!(uow.update_user_where(renamed, filter).await.unwrap(), 1);
+                assert_eq!(uow.find_user(2).await.unwrap().unwrap().name, "c");
+                assert!(matches!(
+                    uow.create_shop(Shop::new(1)).await,
+                    Err(RepositoryError::Conflict(_))
+                ));
+                uow.commit().await.unwrap();
+
+                assert_eq!(uow.count_users(UserFilter::default()).await.unwrap(), 2);
+                assert!(uow.find_shop(1).await.unwrap().is_some());
+            });
+        }
+
+        #[test]
+        fn rollback_discards_the_writes() {
+            block_on(async {
+                let mut uow = connect().await;
+                uow.begin().await.unwrap();
+                uow.create_user(User::new(1, "a".to_string()))
+                    .await
+                    .unwrap();
+                uow.savepoint("sp").await.unwrap();
+                uow.delete_user(User::new(1, "a".to_string()))
+                    .await
+                    .unwrap();
+                uow.rollback_to("sp").await.unwrap();
+                assert!(uow.find_user(1).await.unwrap().is_some());
+                uow.rollback().await.unwrap();
+
+                assert!(uow.find_user(1).await.unwrap().is_none());
+            });
+        }
+
+        #[test]
+        fn finishing_twice_reports_how_the_transaction_ended() {
+            block_on(async {
+                let mut uow = connect().await;
+                assert!(matches!(
+                    uow.commit().await,
+                    Err(UnitOfWorkError::NotStarted)
+                ));
+
+                uow.begin().await.unwrap();
+                uow.commit().await.unwrap();
+                assert_eq!(uow.state(), UnitOfWorkState::Committed);
+                assert!(matches!(
+                    uow.commit().await,
+                    Err(UnitOfWorkError::AlreadyCommitted)
+                ));
+                assert!(matches!(
+                    uow.rollback().await,
+                    Err(UnitOfWorkError::AlreadyCommitted)
+                ));
+
+                uow.begin().await.unwrap();
+                uow.rollback().await.unwrap();
+                assert_eq!(uow.state(), UnitOfWorkState::RolledBack);
+                assert!(matches!(
+                    uow.rollback().await,
+                    Err(UnitOfWorkError::AlreadyRolledBack)
+                ));
+            });
+        }
+
+        #[test]
+        fn read_only_rejects_writes_on_sqlite() {
+            block_on(async {
+                let mut uow = connect().await;
+                uow.begin_read_only().await.unwrap();
+                assert!(matches!(
+                    uow.create_user(User::new(1, "a".to_string())).await,
+                    Err(RepositoryError::ReadOnly)
+                ));
+                assert!(matches!(
+                    uow.create_shop(Shop::new(1)).await,
+                    Err(RepositoryError::ReadOnly)
+                ));
+                assert_eq!(uow.count_users(UserFilter::default()).await.unwrap(), 0);
+                uow.commit().await.unwrap();
+
+                uow.begin().await.unwrap();
+                uow.create_user(User::new(1, "a".to_string()))
+                    .await
+                    .unwrap();
+                uow.commit().await.unwrap();
+            });
+        }
+
+        #[test]
+        fn context_commits_through_sqlx() {
+            block_on(async {
+                let ctx = SqlxContext::from_pool(pool().await);
+                with_unit_of_work(&ctx, |uow| {
+                    Box::pin(async move {
+                        uow.create_user(User::new(1, "a".to_string())).await?;
+                        Ok(())
+                    })
+                })
+                .await
+                .unwrap();
+
+                let uow = ctx.provide_async().await.unwrap();
+                assert!(uow.find_user(1).await.unwrap().is_some());
+            });
+        }
+    }
+}
+
+/// A backend without any database, for offline development and fast tests. Each table is
+/// a `HashMap` behind one `Mutex`; `begin` snapshots every table and `rollback` restores the
+/// snapshot. As with the database backends, writes need an open transaction.
+pub mod in_memory {
+    use super::domain::{
+        Invoice, InvoiceRepository, IsolationLevel, Order, OrderRepository, RepositoryError, Shop,
+        ShopRepository, UnitOfWork, UnitOfWorkError, User, UserChanges, UserFilter, UserRepository,
+    };
+
+    use std::{
+        collections::HashMap,
+        sync::{Mutex, MutexGuard},
+    };
+
+    use async_trait::async_trait;
+
+    #[derive(Debug, Default, Clone)]
+    struct Tables {
+        users: HashMap<i64, User>,
+        shops: HashMap<i64, Shop>,
+        orders: HashMap<i64, Order>,
+        invoices: HashMap<i64, Invoice>,
+    }
+
+    #[derive(Default)]
+    pub struct InMemoryUnitOfWork {
+        tables: Mutex<Tables>,
+        snapshot: Option<Tables>,
+        savepoints: Vec<(String, Tables)>,
+        read_only: bool,
+    }
+
+    impl InMemoryUnitOfWork {
+        fn tables(&self) -> MutexGuard<'_, Tables> {
+            self.tables.lock().unwrap()
+        }
+
+        fn writable_tables(&self) -> Result<MutexGuard<'_, Tables>, RepositoryError> {
+            if self.read_only {
+                return Err(RepositoryError::ReadOnly);
+            }
+            if self.snapshot.is_none() {
+                return Err(UnitOfWorkError::NotStarted.into());
+            }
+            Ok(self.tables())
+        }
+
+        fn start(&mut self, read_only: bool) -> Result<(), UnitOfWorkError> {
+            if self.snapshot.is_some() {
+                return Err(UnitOfWorkError::AlreadyStarted);
+            }
+            let snapshot = self.tables().clone();
+            self.snapshot = Some(snapshot);
+            self.read_only = read_only;
+            Ok(())
+        }
+
+        fn end(&mut self) -> Result<Tables, UnitOfWorkError> {
+            let snapshot = self.snapshot.take().ok_or(UnitOfWorkError::NotStarted)?;
+            self.savepoints.clear();
+            self.read_only = false;
+            Ok(snapshot)
+        }
+    }
+
+    fn insert<T>(table: &mut HashMap<i64, T>, id: i64, row: T) -> Result<T, RepositoryError>
+    where
+        T: Clone,
+    {
+        if table.contains_key(&id) {
+            return Err(RepositoryError::Conflict(format!(
+                "id {} already exists",
+                id
+            )));
+        }
+        table.insert(id, row.clone());
+        Ok(row)
+    }
+
+    fn replace<T>(table: &mut HashMap<i64, T>, id: i64, row: T) -> Result<T, RepositoryError>
+    where
+        T: Clone,
+    {
+        let stored = table.get_mut(&id).ok_or(RepositoryError::NotFound)?;
+        *stored = row.clone();
+        Ok(row)
+    }
+
+    fn remove<T>(table: &mut HashMap<i64, T>, id: i64) -> Result<(), RepositoryError> {
+        table
+            .remove(&id)
+            .map(|_| ())
+            .ok_or(RepositoryError::NotFound)
+    }
+
+    #[async_trait]
+    impl UnitOfWork for InMemoryUnitOfWork {
+        async fn begin(&mut self) -> Result<(), UnitOfWorkError> {
+            self.start(false)
+        }
+
+        /// Every transaction here is serial, so the isolation level is irrelevant.
+        async fn begin_with_isolation(
+            &mut self,
+            _level: IsolationLevel,
+        ) -> Result<(), UnitOfWorkError> {
+            self.start(false)
+        }
+
+        async fn begin_read_only(&mut self) -> Result<(), UnitOfWorkError> {
+            self.start(true)
+        }
+
+        async fn commit(&mut self) -> Result<(), UnitOfWorkError> {
+            self.end().map(|_| ())
+        }
+
+        async fn rollback(&mut self) -> Result<(), UnitOfWorkError> {
+            let snapshot = self.end()?;
+            *self.tables() = snapshot;
+            Ok(())
+        }
+
+        async fn savepoint(&mut self, name: &str) -> Result<(), UnitOfWorkError> {
+            if self.snapshot.is_none() {
+                return Err(UnitOfWorkError::NotStarted);
+            }
+            let tables = self.tables().clone();
+            self.savepoints.push((name.to_string(), tables));
+            Ok(())
+        }
+
+        async fn rollback_to(&mut self, name: &str) -> Result<(), UnitOfWorkError> {
+            let Some(position) = self.savepoints.iter().rposition(|(s, _)| s == name) else {
+                return Err(UnitOfWorkError::UnknownSavepoint(name.to_string()));
+            };
+            self.savepoints.truncate(position + 1);
+            *self.tables.get_mut().unwrap() = self.savepoints[position].1.clone();
+            Ok(())
+        }
+
+        fn is_transaction_active(&self) -> bool {
+            self.snapshot.is_some()
+        }
+    }
+
+    #[async_trait]
+    impl UserRepository for InMemoryUnitOfWork {
+        async fn find_user(&self, id: i64) -> anyhow::Result<Option<User>> {
+            Ok(self.tables().users.get(&id).cloned())
+        }
+
+        async fn create_user(&self, user: User) -> Result<User, RepositoryError> {
+            insert(&mut self.writable_tables()?.users, user.id, user)
+        }
+
+        async fn update_user(&self, user: User) -> Result<User, RepositoryError> {
+            replace(&mut self.writable_tables()?.users, user.id, user)
+        }
+
+        async fn delete_user(&self, user: User) -> Result<(), RepositoryError> {
+            remove(&mut self.writable_tables()?.users, user.id)
+        }
+
+        async fn delete_users_where(&self, filter: UserFilter) -> Result<u64, RepositoryError> {
+            let users = &mut self.writable_tables()?.users;
+            let before = users.len();
+            users.retain(|_, user| !filter.matches(user));
+            Ok((before - users.len()) as u64)
+        }
+
+        async fn count_users(&self, filter: UserFilter) -> anyhow::Result<u64> {
+            let users = &self.tables().users;
+            Ok(users.values().filter(|user| filter.matches(user)).count() as u64)
+        }
+
+        async fn update_user_where(
+            &self,
+            changes: UserChanges,
+            filter: UserFilter,
+        ) -> Result<u64, RepositoryError> {
+            let users = &mut self.writable_tables()?.users;
+            if changes.is_empty() {
+                return Ok(0);
+            }
+            let mut count = 0;
+            for user in users.values_mut().filter(|user| filter.matches(user)) {
+                changes.apply(user);
+                count += 1;
+            }
+            Ok(count)
+        }
+    }
+
+    #[async_trait]
+    impl ShopRepository for InMemoryUnitOfWork {
+        async fn find_shop(&self, id: i64) -> anyhow::Result<Option<Shop>> {
+            Ok(self.tables().shops.get(&id).cloned())
+        }
+
+        async fn create_shop(&self, shop: Shop) -> Result<Shop, RepositoryError> {
+            insert(&mut self.writable_tables()?.shops, shop.id, shop)
+        }
+
+        async fn update_shop(&self, shop: Shop) -> Result<Shop, RepositoryError> {
+            replace(&mut self.writable_tables()?.shops, shop.id, shop)
+        }
+
+        async fn delete_shop(&self, shop: Shop) -> Result<(), RepositoryError> {
+            remove(&mut self.writable_tables()?.shops, shop.id)
+        }
+    }
+
+    #[async_trait]
+    impl OrderRepository for InMemoryUnitOfWork {
+        async fn find_order(&self, id: i64) -> anyhow::Result<Option<Order>> {
+            Ok(self.tables().orders.get(&id).cloned())
+        }
+
+        async fn create_order(&self, order: Order) -> Result<Order, RepositoryError> {
+            insert(&mut self.writable_tables()?.orders, order.id, order)
+        }
+
+        async fn update_order(&self, order: Order) -> Result<Order, RepositoryError> {
+            replace(&mut self.writable_tables()?.orders, order.id, order)
+        }
+
+        async fn delete_order(&self, order: Order) -> Result<(), RepositoryError> {
+            remove(&mut self.writable_tables()?.orders, order.id)
+        }
+    }
+
+    #[async_trait]
+    impl InvoiceRepository for InMemoryUnitOfWork {
+        async fn create_invoice(&self, invoice: Invoice) -> Result<Invoice, RepositoryError> {
+            insert(&mut self.writable_tables()?.invoices, invoice.id, invoice)
+        }
+
+        async fn update_invoice(&self, invoice: Invoice) -> Result<Invoice, RepositoryError> {
+            replace(&mut self.writable_tables()?.invoices, invoice.id, invoice)
+        }
+
+        async fn delete_invoice(&self, invoice: Invoice) -> Result<(), RepositoryError> {
+            remove(&mut self.writable_tables()?.invoices, invoice.id)
+        }
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+pub mod mock {
+    use std::sync::Mutex;
+
+    use super::domain::{
+        DbError, Invoice, InvoiceRepository, IsolationLevel, Order, OrderRepository,
+        RepositoryError, Shop, ShopRepository, UnitOfWork, UnitOfWorkError, User, UserChanges,
+        UserFilter, UserRepository,
+    };
+
+    use async_trait::async_trait;
+
+    #[derive(Default)]
+    pub struct MockUnitOfWork {
+        calls: Mutex<Vec<String>>,
+        active: bool,
+        commit_error: Option<String>,
+    }
+
+    impl MockUnitOfWork {
+        /// A mock whose `commit` fails with a database error carrying SQLSTATE `code`, e.g.
+        /// `40001` for a serialization failure.
+        pub fn failing_commit(code: &str) -> Self {
+            Self {
+                commit_error: Some(code.to_string()),
+                ..Default::default()
+            }
+        }
+
+        /// The calls made so far, in order.
+        pub fn calls(&mut self) -> &[String] {
+            self.calls.get_mut().unwrap()
+        }
+
+        fn record(&self, call: &str) {
+            self.calls.lock().unwrap().push(call.to_string());
+        }
+    }
+
+    #[async_trait]
+    impl UnitOfWork for MockUnitOfWork {
+        async fn begin(&mut self) -> Result<(), UnitOfWorkError> {
+            self.record("begin");
+            self.active = true;
+            Ok(())
+        }
+
+        async fn begin_with_isolation(
+            &mut self,
+            _level: IsolationLevel,
+        ) -> Result<(), UnitOfWorkError> {
+            self.record("begin_with_isolation");
+            self.active = true;
+            Ok(())
+        }
+
+        async fn begin_read_only(&mut self) -> Result<(), UnitOfWorkError> {
+            self.record("begin_read_only");
+            self.active = true;
+            Ok(())
+        }
+
+        async fn commit(&mut self) -> Result<(), UnitOfWorkError> {
+            self.record("commit");
+            self.active = false;
+            match &self.commit_error {
+                Some(code) => Err(UnitOfWorkError::Commit(DbError::new(
+                    Some(code.clone()),
+                    format!("commit failed with SQLSTATE {}", code),
+                ))),
+                None => Ok(()),
+            }
+        }
+
+        async fn rollback(&mut self) -> Result<(), UnitOfWorkError> {
+            self.record("rollback");
+            self.active = false;
+            Ok(())
+        }
+
+        async fn savepoint(&mut self, _name: &str) -> Result<(), UnitOfWorkError> {
+            self.record("savepoint");
+            Ok(())
+        }
+
+        async fn rollback_to(&mut self, _name: &str) -> Result<(), UnitOfWorkError> {
+            self.record("rollback_to");
+            Ok(())
+        }
+
+        fn is_transaction_active(&self) -> bool {
+            self.active
+        }
+    }
+
+    #[async_trait]
+    impl UserRepository for MockUnitOfWork {
+        async fn find_user(&self, _id: i64) -> anyhow::Result<Option<User>> {
+            self.record("find_user");
+            Ok(None)
+        }
+
+        async fn create_user(&self, user: User) -> Result<User, RepositoryError> {
+            self.record("create_user");
+            Ok(user)
+        }
+
+        async fn update_user(&self, user: User) -> Result<User, RepositoryError> {
+            self.record("update_user");
+            Ok(user)
+        }
+
+        async fn delete_user(&self, _user: User) -> Result<(), RepositoryError> {
+            self.record("delete_user");
+            Ok(())
+        }
+
+        async fn delete_users_where(&self, _filter: UserFilter) -> Result<u64, RepositoryError> {
+            self.record("delete_users_where");
+            Ok(0)
+        }
+
+        async fn count_users(&self, _filter: UserFilter) -> anyhow::Result<u64> {
+            self.record("count_users");
+            Ok(0)
+        }
+
+        async fn update_user_where(
+            &self,
+            _changes: UserChanges,
+            _filter: UserFilter,
+        ) -> Result<u64, RepositoryError> {
+            self.record("update_user_where");
+            Ok(0)
+        }
+    }
+
+    #[async_trait]
+    impl ShopRepository for MockUnitOfWork {
+        async fn find_shop(&self, _id: i64) -> anyhow::Result<Option<Shop>> {
+            self.record("find_shop");
+            Ok(None)
+        }
+
+        async fn create_shop(&self, shop: Shop) -> Result<Shop, RepositoryError> {
+            self.record("create_shop");
+            Ok(shop)
+        }
+
+        async fn update_shop(&self, shop: Shop) -> Result<Shop, RepositoryError> {
+            self.record("update_shop");
+            Ok(shop)
+        }
+
+        async fn delete_shop(&self, _shop: Shop) -> Result<(), RepositoryError> {
+            self.record("delete_shop");
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl OrderRepository for MockUnitOfWork {
+        async fn find_order(&self, _id: i64) -> anyhow::Result<Option<Order>> {
+            self.record("find_order");
+            Ok(None)
+        }
+
+        async fn create_order(&self, order: Order) -> Result<Order, RepositoryError> {
+            self.record("create_order");
+            Ok(order)
+        }
+
+        async fn update_order(&self, order: Order) -> Result<Order, RepositoryError> {
+            self.record("update_order");
+            Ok(order)
+        }
+
+        async fn delete_order(&self, _order: Order) -> Result<(), RepositoryError> {
+            self.record("delete_order");
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl InvoiceRepository for MockUnitOfWork {
+        async fn create_invoice(&self, invoice: Invoice) -> Result<Invoice, RepositoryError> {
+            self.record("create_invoice");
+            Ok(invoice)
+        }
+
+        async fn update_invoice(&self, invoice: Invoice) -> Result<Invoice, RepositoryError> {
+            self.record("update_invoice");
+            Ok(invoice)
+        }
+
+        async fn delete_invoice(&self, _invoice: Invoice) -> Result<(), RepositoryError> {
+            self.record("delete_invoice");
+            Ok(())
+        }
+    }
+}
+
+/// The helpers only need the `domain::UnitOfWork` trait; `Context` and `SqlxContext` plug in
+/// whichever backends the crate was built with.
+pub mod context {
+    use std::{future::Future, pin::Pin, time::Duration};
+
+    #[cfg(any(feature = "backend-sea-orm", feature = "backend-sqlx"))]
+    use anyhow::Context as _;
+    use anyhow::Result;
+    use async_trait::async_trait;
+    #[cfg(feature = "backend-sea-orm")]
+    use sea_orm::{Database, DatabaseConnection};
+    #[cfg(feature = "backend-sqlx")]
+    use sqlx::AnyPool;
+
+    use super::domain::{DbError, UnitOfWork};
+    #[cfg(feature = "backend-sea-orm")]
+    use super::infrastructure::UnitOfWorkImpl;
+    #[cfg(feature = "backend-sqlx")]
+    use super::sqlx_infrastructure::SqlxUnitOfWork;
+
+    #[async_trait]
+    pub trait ProvideUnitOfWork {
+        type UnitOfWork: UnitOfWork + Send + Sync;
+        fn provide(&self) -> Self::UnitOfWork;
+
+        async fn provide_async(&self) -> Result<Self::UnitOfWork> {
+            Ok(self.provide())
+        }
+    }
+
+    #[cfg(feature = "backend-sea-orm")]
+    pub struct Context {
+        conn: DatabaseConnection,
+    }
+
+    #[cfg(feature = "backend-sea-orm")]
+    impl Context {
+        pub async fn connect(url: &str) -> Result<Self> {
+            let conn = Database::connect(url)
+                .await
+                .with_context(|| "Failed to connect to database")?;
+            Ok(Self { conn })
+        }
+    }
+
+    #[cfg(feature = "backend-sea-orm")]
+    #[async_trait]
+    impl ProvideUnitOfWork for Context {
+        type UnitOfWork = UnitOfWorkImpl;
+
+        fn provide(&self) -> Self::UnitOfWork {
+            UnitOfWorkImpl::new(self.conn.clone(), None)
+        }
+
+        async fn provide_async(&self) -> Result<Self::UnitOfWork> {
+            self.conn
+                .ping()
+                .await
+                .with_context(|| "Failed to acquire connection")?;
+            Ok(self.provide())
+        }
+    }
+
+    #[cfg(feature = "backend-sqlx")]
+    pub struct SqlxContext {
+        pool: AnyPool,
+    }
+
+    #[cfg(feature = "backend-sqlx")]
+    impl SqlxContext {
+        /// Installs sqlx's default drivers, so `url` may name any backend sqlx was built with.
+        pub async fn connect(url: &str) -> Result<Self> {
+            sqlx::any::install_default_drivers();
+            let pool = AnyPool::connect(url)
+                .await
+                .with_context(|| "Failed to connect to database")?;
+            Ok(Self { pool })
+        }
+
+        pub fn from_pool(pool: AnyPool) -> Self {
+            Self { pool }
+        }
+    }
+
+    #[cfg(feature = "backend-sqlx")]
+    #[async_trait]
+    impl ProvideUnitOfWork for SqlxContext {
+        type UnitOfWork = SqlxUnitOfWork;
+
+        fn provide(&self) -> Self::UnitOfWork {
+            SqlxUnitOfWork::new(self.pool.clone())
+        }
+
+        async fn provide_async(&self) -> Result<Self::UnitOfWork> {
+            self.pool
+                .acquire()
+                .await
+                .with_context(|| "Failed to acquire connection")?;
+            Ok(self.provide())
+        }
+    }
+
+    /// Begins a unit of work, hands it to `f`, and commits when `f` succeeds or rolls back
+    /// when it fails, in the style of sea_orm's `transaction`.
+    pub async fn with_unit_of_work<C, F, T>(ctx: &C, f: F) -> Result<T>
+    where
+        C: ProvideUnitOfWork + Sync,
+        F: for<'a> FnOnce(
+            &'a mut C::UnitOfWork,
+        ) -> Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>,
+    {
+        let mut uow = ctx.provide_async().await?;
+        uow.begin().await?;
+        match f(&mut uow).await {
+            Ok(value) => {
+                uow.commit().await?;
+                Ok(value)
+            }
+            Err(err) => Err(roll_back(&mut uow, err).await),
+        }
+    }
+
+    /// Like `with_unit_of_work`, but reruns `f` in a fresh unit of work, with exponential
+    /// backoff, when the commit or a statement run by `f` fails on a serialization failure
+    /// or deadlock, up to `attempts` runs in total. `f` is called once per attempt, so it
+    /// must stage all of its work again rather than rely on anything a failed attempt did.
+    pub async fn with_unit_of_work_retry<C, F, T>(ctx: &C, attempts: u32, mut f: F) -> Result<T>
+    where
+        C: ProvideUnitOfWork + Sync,
+        F: for<'a> FnMut(
+            &'a mut C::UnitOfWork,
+        ) -> Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>,
+    {
+        let mut delay = Duration::from_millis(10);
+        let mut attempt = 1;
+        loop {
+            let mut uow = ctx.provide_async().await?;
+            uow.begin().await?;
+            let err = match f(&mut uow).await {
+                Ok(value) => match uow.commit().await {
+                    Ok(()) => return Ok(value),
+                    Err(err) => anyhow::Error::new(err).context(format!(
+                        "failed to commit unit of work after {} attempt{}",
+                        attempt,
+                        if attempt == 1 { "" } else { "s" }
+                    )),
+                },
+                Err(err) => roll_back(&mut uow, err).await,
+            };
+            if attempt >= attempts || !is_retryable(&err) {
+                return Err(err);
+            }
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// Whether a database error anywhere in `err`'s chain is worth rerunning the unit of
+    /// work for.
+    fn is_retryable(err: &anyhow::Error) -> bool {
+        err.chain().any(|e| {
+            e.downcast_ref::<DbError>()
+                .is_some_and(DbError::is_retryable)
+        })
+    }
+
+    async fn roll_back<U: UnitOfWork>(uow: &mut U, err: anyhow::Error) -> anyhow::Error {
+        match uow.rollback().await {
+            Ok(()) => err,
+            Err(rollback) => err.context(format!("rollback also failed: {}", rollback)),
+        }
+    }
+}
+
+#[cfg(feature = "backend-sea-orm")]
+mod use_case {
+    use super::{
+        context::{Context, ProvideUnitOfWork},
+        domain::{Order, OrderRepository, Shop, ShopRepository, UnitOfWork, User, UserRepository},
+    };
+
+    #[allow(unused)]
+    async fn use_case(context: Context) -> anyhow::Result<()> {
+        update_all(&mut context.provide()).await
+    }
+
+    /// The body of `use_case`, generic over the unit of work so tests can run it against
+    /// `MockUnitOfWork`.
+    pub(super) async fn update_all<U>(uow: &mut U) -> anyhow::Result<()>
+    where
+        U: UnitOfWork + UserRepository + ShopRepository + OrderRepository + Sync,
+    {
+        uow.begin().await?;
+        let user = uow.update_user(User::new(1, "user".to_string())).await?;
+        uow.update_shop(Shop::new(1)).await?;
+        uow.update_order(Order::new(1)).await?;
+        uow.commit_if(|| user.is_valid()).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "backend-sea-orm"))]
+mod tests {
+    use super::{
+        context::{with_unit_of_work, with_unit_of_work_retry, ProvideUnitOfWork},
+        domain::{
+            chain, CommitOutcome, DbError, Invoice, InvoiceRepository, IsolationLevel, Order,
+            OrderRepository, RepositoryError, Shop, ShopRepository, UnitOfWork, UnitOfWorkError,
+            User, UserChanges, UserFilter, UserRepository,
+        },
+        domain_native,
+        in_memory::InMemoryUnitOfWork,
+        infrastructure::{self, MultiDbUnitOfWork, UnitOfWorkImpl},
+        metrics::{self, Metrics},
+        mock::MockUnitOfWork,
+        use_case,
+    };
+    use crate::test_support::{block_on, postgres, Capture};
+
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use sea_orm::{ConnectionTrait, Database, DatabaseConnection, DbErr, Statement, Values};
+    use tracing::Level;
+
+    /// sea_orm keeps a single connection for an in-memory SQLite database, so the schema
+    /// created here is the one every unit of work on `conn` sees.
+    async fn connect() -> DatabaseConnection {
+        let conn = Database::connect("sqlite::memory:").await.unwrap();
+        conn.execute_unprepared(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT NOT NULL); \
+             CREATE TABLE shops (id INTEGER PRIMARY KEY); \
+             CREATE TABLE orders (id INTEGER PRIMARY KEY); \
+             CREATE TABLE invoices (id INTEGER PRIMARY KEY);",
+        )
+        .await
+        .unwrap();
+        conn
+    }
+
+    fn user(id: i64) -> User {
+        User::new(id, format!("user {}", id))
+    }
+
+    #[test]
+    fn rollback_to_savepoint_undoes_only_the_later_writes() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None);
+            uow.begin().await.unwrap();
+            uow.create_user(user(1)).await.unwrap();
+            uow.savepoint("before_second").await.unwrap();
+            uow.create_user(user(2)).await.unwrap();
+            uow.rollback_to("before_second").await.unwrap();
+
+            assert!(uow.find_user(1).await.unwrap().is_some());
+            assert!(uow.find_user(2).await.unwrap().is_none());
+            uow.commit().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn savepoints_need_an_active_transaction_and_a_known_plain_name() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None);
+            assert!(matches!(
+                uow.savepoint("sp").await,
+                Err(UnitOfWorkError::NotStarted)
+            ));
+
+            uow.begin().await.unwrap();
+            assert!(matches!(
+                uow.rollback_to("missing").await,
+                Err(UnitOfWorkError::UnknownSavepoint(name)) if name == "missing"
+            ));
+            assert!(matches!(
+                uow.savepoint("sp; DROP TABLE users").await,
+                Err(UnitOfWorkError::InvalidSavepointName(_))
+            ));
+            uow.rollback().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn use_case_runs_against_the_mock_in_order() {
+        block_on(async {
+            let mut uow = MockUnitOfWork::default();
+            use_case::update_all(&mut uow).await.unwrap();
+
+            assert_eq!(
+                uow.calls(),
+                [
+                    "begin",
+                    "update_user",
+                    "update_shop",
+                    "update_order",
+                    "commit"
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn finders_see_the_writes_of_the_open_transaction() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None);
+            uow.begin().await.unwrap();
+            uow.create_user(user(1)).await.unwrap();
+            uow.create_shop(Shop::new(2)).await.unwrap();
+            uow.create_order(Order::new(3)).await.unwrap();
+
+            assert_eq!(uow.find_user(1).await.unwrap().unwrap().name, "user 1");
+            assert_eq!(uow.find_shop(2).await.unwrap().unwrap().id, 2);
+            assert_eq!(uow.find_order(3).await.unwrap().unwrap().id, 3);
+            uow.rollback().await.unwrap();
+
+            assert!(uow.find_user(1).await.unwrap().is_none());
+            assert!(uow.find_shop(2).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn a_failed_second_commit_leaves_the_first_committed_and_warns() {
+        let capture = Capture::default();
+        capture.run(|| {
+            block_on(async {
+                let first = connect().await;
+                let second = Database::connect("sqlite::memory:").await.unwrap();
+                second
+                    .execute_unprepared(
+                        "CREATE TABLE parents (id INTEGER PRIMARY KEY); \
+                         CREATE TABLE children (id INTEGER PRIMARY KEY, parent_id INTEGER \
+                         REFERENCES parents (id) DEFERRABLE INITIALLY DEFERRED);",
+                    )
+                    .await
+                    .unwrap();
+                let mut uow = MultiDbUnitOfWork::new(first.clone(), second);
+                assert!(uow.first().is_none());
+
+                uow.begin().await.unwrap();
+                uow.first()
+                    .unwrap()
+                    .execute_unprepared("INSERT INTO shops (id) VALUES (1)")
+                    .await
+                    .unwrap();
+                // Only checked at commit, so the second commit fails after the first landed.
+                uow.second()
+                    .unwrap()
+                    .execute_unprepared("INSERT INTO children (id, parent_id) VALUES (1, 99)")
+                    .await
+                    .unwrap();
+
+                assert!(matches!(
+                    uow.commit().await,
+                    Err(UnitOfWorkError::Commit(_))
+                ));
+                let shop = UnitOfWorkImpl::new(first, None).find_shop(1).await.unwrap();
+                assert!(shop.is_some());
+            })
+        });
+        assert_eq!(
+            capture.messages(Level::WARN),
+            ["second database failed to commit after the first committed; compensation required"]
+        );
+    }
+
+    #[test]
+    fn a_failed_first_commit_rolls_back_the_second_and_reports_the_commit() {
+        block_on(async {
+            let first = Database::connect("sqlite::memory:").await.unwrap();
+            first
+                .execute_unprepared(
+                    "CREATE TABLE parents (id INTEGER PRIMARY KEY); \
+                     CREATE TABLE children (id INTEGER PRIMARY KEY, parent_id INTEGER \
+                     REFERENCES parents (id) DEFERRABLE INITIALLY DEFERRED);",
+                )
+                .await
+                .unwrap();
+            let second = connect().await;
+            let mut uow = MultiDbUnitOfWork::new(first, second.clone());
+
+            uow.begin().await.unwrap();
+            uow.first()
+                .unwrap()
+                .execute_unprepared("INSERT INTO children (id, parent_id) VALUES (1, 99)")
+                .await
+                .unwrap();
+            uow.second()
+                .unwrap()
+                .execute_unprepared("INSERT INTO shops (id) VALUES (1)")
+                .await
+                .unwrap();
+
+            assert!(matches!(
+                uow.commit().await,
+                Err(UnitOfWorkError::Commit(_))
+            ));
+            let shop = UnitOfWorkImpl::new(second, None)
+                .find_shop(1)
+                .await
+                .unwrap();
+            assert!(shop.is_none());
+        });
+    }
+
+    #[test]
+    fn only_serialization_failures_and_deadlocks_on_commit_are_retryable() {
+        let commit =
+            |code: &str| UnitOfWorkError::Commit(DbError::new(Some(code.into()), "failed"));
+        assert!(commit("40001").is_retryable());
+        assert!(commit("40P01").is_retryable());
+        assert!(!commit("23505").is_retryable());
+        assert!(
+            !UnitOfWorkError::Begin(DbError::new(Some("40001".into()), "failed")).is_retryable()
+        );
+    }
+
+    #[test]
+    fn native_trait_begins_and_commits() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None);
+            domain_native::UnitOfWork::begin(&mut uow).await.unwrap();
+            assert!(domain_native::UnitOfWork::is_transaction_active(&uow));
+            uow.create_user(user(1)).await.unwrap();
+            domain_native::UnitOfWork::commit(&mut uow).await.unwrap();
+
+            assert!(!domain_native::UnitOfWork::is_transaction_active(&uow));
+            assert!(uow.find_user(1).await.unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn the_correlation_id_is_bound_rather_than_spliced_into_the_sql() {
+        let stmt = infrastructure::application_name_statement("it's-42");
+        assert_eq!(stmt.sql, "SELECT set_config('application_name', $1, true)");
+        assert_eq!(stmt.values, Some(Values(vec!["it's-42".into()])));
+    }
+
+    #[test]
+    fn the_in_memory_backend_needs_a_transaction_to_write() {
+        block_on(async {
+            let mut uow = InMemoryUnitOfWork::default();
+            assert!(matches!(
+                uow.create_user(user(1)).await,
+                Err(RepositoryError::UnitOfWork(UnitOfWorkError::NotStarted))
+            ));
+
+            uow.begin().await.unwrap();
+            uow.create_user(user(1)).await.unwrap();
+            let filter = UserFilter::default();
+            let changes = UserChanges::default();
+            assert_eq!(uow.update_user_where(changes, filter).await.unwrap(), 0);
+            uow.commit().await.unwrap();
+            assert_eq!(uow.count_users(UserFilter::default()).await.unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn the_non_transactional_fallback_is_opt_in_and_never_weakens_isolation() {
+        let capture = Capture::default();
+        capture.run(|| {
+            block_on(async {
+                let mut uow = UnitOfWorkImpl::new(DatabaseConnection::Disconnected, None);
+                assert!(matches!(uow.begin().await, Err(UnitOfWorkError::Begin(_))));
+
+                let is_unsupported: fn(&DbErr) -> bool = |err| matches!(err, DbErr::Conn(_));
+                let mut uow = UnitOfWorkImpl::new(DatabaseConnection::Disconnected, None)
+                    .with_non_transactional_fallback(is_unsupported);
+                assert!(matches!(
+                    uow.begin_with_isolation(IsolationLevel::Serializable).await,
+                    Err(UnitOfWorkError::Begin(_))
+                ));
+                uow.begin().await.unwrap();
+                assert!(uow.is_transaction_active());
+                assert!(matches!(uow.commit().await, Ok(())));
+                assert!(!uow.is_transaction_active());
+
+                uow.begin().await.unwrap();
+                uow.rollback().await.unwrap();
+            })
+        });
+        assert_eq!(
+            capture.messages(Level::WARN),
+            [
+                "transactions are unsupported; continuing without one",
+                "no transaction to commit; writes were applied as they ran",
+                "transactions are unsupported; continuing without one",
+                "no transaction to roll back; writes were already applied"
+            ]
+        );
+    }
+
+    #[test]
+    fn dropping_an_active_unit_of_work_rolls_it_back_and_warns() {
+        let capture = Capture::default();
+        capture.run(|| {
+            block_on(async {
+                let conn = connect().await;
+                let mut uow = UnitOfWorkImpl::new(conn.clone(), None);
+                uow.begin().await.unwrap();
+                uow.create_user(user(1)).await.unwrap();
+                drop(uow);
+
+                let uow = UnitOfWorkImpl::new(conn, None);
+                assert!(uow.find_user(1).await.unwrap().is_none());
+            })
+        });
+        assert_eq!(
+            capture.messages(Level::WARN),
+            ["Uncommitted transaction was abandoned and will be rolled back"]
+        );
+        let warning = capture
+            .events()
+            .into_iter()
+            .find(|event| event.message().starts_with("Uncommitted transaction"))
+            .unwrap();
+        assert!(warning.fields.contains_key("uow.id"));
+    }
+
+    #[test]
+    fn isolation_levels_map_onto_sea_orm_and_begin_a_transaction() {
+        assert_eq!(
+            sea_orm::IsolationLevel::from(IsolationLevel::Serializable),
+            sea_orm::IsolationLevel::Serializable
+        );
+        assert_eq!(
+            sea_orm::IsolationLevel::from(IsolationLevel::ReadUncommitted),
+            sea_orm::IsolationLevel::ReadUncommitted
+        );
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None);
+            uow.begin_with_isolation(IsolationLevel::Serializable)
+                .await
+                .unwrap();
+            assert!(matches!(
+                uow.begin_with_isolation(IsolationLevel::ReadCommitted)
+                    .await,
+                Err(UnitOfWorkError::AlreadyStarted)
+            ));
+            uow.create_user(user(1)).await.unwrap();
+            uow.commit().await.unwrap();
+            assert!(uow.find_user(1).await.unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn a_read_only_transaction_refuses_writes_but_reads() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None);
+            uow.begin_read_only().await.unwrap();
+            assert!(matches!(
+                uow.create_user(user(1)).await,
+                Err(RepositoryError::ReadOnly)
+            ));
+            assert!(uow.find_user(1).await.unwrap().is_none());
+            uow.commit().await.unwrap();
+
+            let mut uow = InMemoryUnitOfWork::default();
+            uow.begin_read_only().await.unwrap();
+            assert!(matches!(
+                uow.create_shop(Shop::new(1)).await,
+                Err(RepositoryError::ReadOnly)
+            ));
+            uow.rollback().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn unit_of_work_errors_name_the_step_and_keep_the_database_error_as_source() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(DatabaseConnection::Disconnected, None);
+            let err = uow.commit().await.unwrap_err();
+            assert!(matches!(err, UnitOfWorkError::NotStarted));
+            assert_eq!(err.to_string(), "Transaction is not started");
+
+            let err = uow.begin().await.unwrap_err();
+            assert_eq!(err.to_string(), "Failed to begin transaction");
+            let source = std::error::Error::source(&err).unwrap();
+            assert!(source.to_string().contains("Disconnected"), "{}", source);
+        });
+    }
+
+    #[test]
+    fn begin_and_commit_are_traced_with_their_outcome() {
+        let capture = Capture::default();
+        capture.run(|| {
+            block_on(async {
+                let mut uow = UnitOfWorkImpl::new(connect().await, None);
+                uow.begin().await.unwrap();
+                uow.commit().await.unwrap();
+            })
+        });
+
+        let begin = capture.span("begin").unwrap();
+        let commit = capture.span("commit").unwrap();
+        assert_eq!(begin.fields["outcome"], "ok");
+        assert_eq!(commit.fields["outcome"], "ok");
+        assert!(commit.fields.contains_key("elapsed_ms"));
+        assert_eq!(begin.fields["uow.id"], commit.fields["uow.id"]);
+    }
+
+    #[test]
+    fn commits_and_rollbacks_are_counted() {
+        block_on(async {
+            let conn = connect().await;
+            // The global counters are bumped by other tests concurrently, so these count
+            // only this test's units of work.
+            let counted = Arc::new(Metrics::new());
+            let before = counted.snapshot();
+            let global_before = metrics::snapshot();
+            let mut uow = UnitOfWorkImpl::new(conn.clone(), None).with_metrics(counted.clone());
+            uow.begin().await.unwrap();
+            uow.commit().await.unwrap();
+            let mut uow = UnitOfWorkImpl::new(conn, None).with_metrics(counted.clone());
+            uow.begin().await.unwrap();
+            uow.rollback().await.unwrap();
+
+            let after = counted.snapshot();
+            assert_eq!(after.commits - before.commits, 1);
+            assert_eq!(after.rollbacks - before.rollbacks, 1);
+            assert_eq!(after.commit_errors, before.commit_errors);
+            let global_after = metrics::snapshot();
+            assert!(global_after.commits > global_before.commits);
+            assert!(global_after.rollbacks > global_before.rollbacks);
+        });
+    }
+
+    #[test]
+    fn nested_units_of_work_roll_back_only_their_own_writes() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = UnitOfWorkImpl::new(conn.clone(), None);
+            uow.begin().await.unwrap();
+            uow.create_user(user(1)).await.unwrap();
+
+            let nested = uow.nested().await.unwrap();
+            nested.create_user(user(2)).await.unwrap();
+            nested.rollback().await.unwrap();
+            let nested = uow.nested().await.unwrap();
+            nested.create_user(user(3)).await.unwrap();
+            nested.commit().await.unwrap();
+            uow.commit().await.unwrap();
+
+            let reader = UnitOfWorkImpl::new(conn, None);
+            assert!(reader.find_user(1).await.unwrap().is_some());
+            assert!(reader.find_user(2).await.unwrap().is_none());
+            assert!(reader.find_user(3).await.unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn a_nested_unit_of_work_without_an_outer_one_is_a_transaction() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = UnitOfWorkImpl::new(conn.clone(), None);
+            let nested = uow.nested().await.unwrap();
+            nested.create_user(user(1)).await.unwrap();
+            nested.commit().await.unwrap();
+
+            let mut uow = UnitOfWorkImpl::new(conn.clone(), None);
+            let nested = uow.nested().await.unwrap();
+            nested.create_user(user(2)).await.unwrap();
+            nested.rollback().await.unwrap();
+
+            let reader = UnitOfWorkImpl::new(conn, None);
+            assert!(reader.find_user(1).await.unwrap().is_some());
+            assert!(reader.find_user(2).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn commit_if_commits_or_rolls_back_on_the_predicate() {
+        block_on(async {
+            let mut uow = MockUnitOfWork::default();
+            uow.begin().await.unwrap();
+            assert_eq!(
+                uow.commit_if(|| true).await.unwrap(),
+                CommitOutcome::Committed
+            );
+            uow.begin().await.unwrap();
+            assert_eq!(
+                uow.commit_if(|| false).await.unwrap(),
+                CommitOutcome::RolledBack
+            );
+
+            assert_eq!(uow.calls(), ["begin", "commit", "begin", "rollback"]);
+        });
+    }
+
+    #[test]
+    fn invoices_are_written_in_the_transaction() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = UnitOfWorkImpl::new(conn.clone(), None);
+            uow.begin().await.unwrap();
+            uow.create_invoice(Invoice { id: 1 }).await.unwrap();
+            uow.create_invoice(Invoice { id: 2 }).await.unwrap();
+            uow.delete_invoice(Invoice { id: 2 }).await.unwrap();
+            assert!(matches!(
+                uow.update_invoice(Invoice { id: 3 }).await,
+                Err(RepositoryError::NotFound)
+            ));
+            uow.commit().await.unwrap();
+
+            let rows = conn
+                .query_all(Statement::from_string(
+                    conn.get_database_backend(),
+                    "SELECT id FROM invoices",
+                ))
+                .await
+                .unwrap();
+            let ids = rows
+                .iter()
+                .map(|row| row.try_get::<i64>("", "id").unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(ids, [1]);
+        });
+    }
+
+    #[test]
+    fn a_transaction_guard_commits_explicitly_and_rolls_back_when_dropped() {
+        let capture = Capture::default();
+        capture.run(|| {
+            block_on(async {
+                let conn = connect().await;
+                let mut uow = UnitOfWorkImpl::new(conn.clone(), None);
+                let guard = uow.transaction().await.unwrap();
+                guard.create_user(user(1)).await.unwrap();
+                guard.commit().await.unwrap();
+
+                let mut uow = UnitOfWorkImpl::new(conn.clone(), None);
+                let guard = uow.transaction().await.unwrap();
+                guard.create_user(user(2)).await.unwrap();
+                drop(guard);
+                assert!(!uow.is_transaction_active());
+
+                let reader = UnitOfWorkImpl::new(conn, None);
+                assert!(reader.find_user(1).await.unwrap().is_some());
+                assert!(reader.find_user(2).await.unwrap().is_none());
+            })
+        });
+
+        assert_eq!(
+            capture.messages(Level::ERROR),
+            ["TransactionGuard dropped without commit or finish; rolling back"]
+        );
+    }
+
+    #[test]
+    fn delete_users_where_removes_only_the_matching_users() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None);
+            uow.begin().await.unwrap();
+            uow.create_user(User::new(1, "ann".to_string()))
+                .await
+                .unwrap();
+            uow.create_user(User::new(2, "ann".to_string()))
+                .await
+                .unwrap();
+            uow.create_user(User::new(3, "bob".to_string()))
+                .await
+                .unwrap();
+
+            let filter = UserFilter {
+                name: Some("ann".to_string()),
+                ..UserFilter::default()
+            };
+            assert_eq!(uow.delete_users_where(filter).await.unwrap(), 2);
+            uow.commit().await.unwrap();
+
+            assert!(uow.find_user(1).await.unwrap().is_none());
+            assert!(uow.find_user(2).await.unwrap().is_none());
+            assert!(uow.find_user(3).await.unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn is_transaction_active_follows_begin_and_commit() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None);
+            assert!(!uow.is_transaction_active());
+            uow.begin().await.unwrap();
+            assert!(uow.is_transaction_active());
+            uow.commit().await.unwrap();
+            assert!(!uow.is_transaction_active());
+
+            let mut uow = InMemoryUnitOfWork::default();
+            assert!(!uow.is_transaction_active());
+            uow.begin().await.unwrap();
+            assert!(uow.is_transaction_active());
+            uow.rollback().await.unwrap();
+            assert!(!uow.is_transaction_active());
+        });
+    }
+
+    #[test]
+    fn chain_commits_the_second_unit_of_work_after_the_first() {
+        block_on(async {
+            let (mut first, mut second) = (MockUnitOfWork::default(), MockUnitOfWork::default());
+            first.begin().await.unwrap();
+            first.update_user(user(1)).await.unwrap();
+            chain(&mut first, &mut second).await.unwrap();
+
+            assert_eq!(first.calls(), ["begin", "update_user", "commit"]);
+            assert_eq!(second.calls(), ["begin", "commit"]);
+        });
+    }
+
+    #[test]
+    fn the_health_check_passes_on_a_live_connection_and_guards_begin() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None).with_health_check(true);
+            uow.ensure_connected().await.unwrap();
+            uow.begin().await.unwrap();
+            assert!(uow.is_transaction_active());
+
+            let mut uow =
+                UnitOfWorkImpl::new(DatabaseConnection::Disconnected, None).with_health_check(true);
+            assert!(matches!(
+                uow.begin().await,
+                Err(UnitOfWorkError::Unhealthy(_))
+            ));
+            assert!(!uow.is_transaction_active());
+        });
+    }
+
+    #[test]
+    fn finishing_twice_reports_how_the_transaction_ended() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = UnitOfWorkImpl::new(conn.clone(), None);
+            assert!(matches!(
+                uow.commit().await,
+                Err(UnitOfWorkError::NotStarted)
+            ));
+            uow.begin().await.unwrap();
+            uow.commit().await.unwrap();
+            assert!(matches!(
+                uow.commit().await,
+                Err(UnitOfWorkError::AlreadyCommitted)
+            ));
+
+            let mut uow = UnitOfWorkImpl::new(conn, None);
+            uow.begin().await.unwrap();
+            uow.rollback().await.unwrap();
+            assert!(matches!(
+                uow.commit().await,
+                Err(UnitOfWorkError::AlreadyRolledBack)
+            ));
+        });
+    }
+
+    /// Provides units of work on a connection that already has the test schema.
+    struct Provider(DatabaseConnection);
+
+    #[async_trait::async_trait]
+    impl ProvideUnitOfWork for Provider {
+        type UnitOfWork = UnitOfWorkImpl;
+
+        fn provide(&self) -> Self::UnitOfWork {
+            UnitOfWorkImpl::new(self.0.clone(), None)
+        }
+    }
+
+    #[test]
+    fn with_unit_of_work_commits_on_ok_and_rolls_back_on_err() {
+        block_on(async {
+            let provider = Provider(connect().await);
+            let created = with_unit_of_work(&provider, |uow| {
+                Box::pin(async move { Ok(uow.create_user(user(1)).await?) })
+            })
+            .await
+            .unwrap();
+            assert_eq!(created.id, 1);
+
+            let err = with_unit_of_work::<_, _, ()>(&provider, |uow| {
+                Box::pin(async move {
+                    uow.create_user(user(2)).await?;
+                    anyhow::bail!("handler failed")
+                })
+            })
+            .await
+            .unwrap_err();
+            assert_eq!(err.to_string(), "handler failed");
+
+            let reader = provider.provide();
+            assert!(reader.find_user(1).await.unwrap().is_some());
+            assert!(reader.find_user(2).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn a_panic_inside_run_txn_is_rolled_back_and_returned_as_an_error() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = UnitOfWorkImpl::new(conn.clone(), None);
+            let err = uow
+                .run_txn::<(), _>(|uow| {
+                    Box::pin(async move {
+                        uow.create_user(user(1)).await?;
+                        panic!("handler blew up")
+                    })
+                })
+                .await
+                .unwrap_err();
+
+            assert_eq!(err.to_string(), "transaction panicked: handler blew up");
+            assert!(!uow.is_transaction_active());
+            let reader = UnitOfWorkImpl::new(conn, None);
+            assert!(reader.find_user(1).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn the_correlation_id_is_recorded_on_the_commit_span() {
+        let capture = Capture::default();
+        capture.run(|| {
+            block_on(async {
+                let mut uow =
+                    UnitOfWorkImpl::new(connect().await, None).with_correlation_id("req-42");
+                uow.begin().await.unwrap();
+                uow.commit().await.unwrap();
+            })
+        });
+
+        let commit = capture.span("commit").unwrap();
+        assert_eq!(commit.fields["correlation_id"], "req-42");
+    }
+
+    #[test]
+    fn repository_errors_tell_a_missing_row_from_a_conflict() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None);
+            uow.begin().await.unwrap();
+            uow.create_user(user(1)).await.unwrap();
+
+            assert!(matches!(
+                uow.create_user(user(1)).await,
+                Err(RepositoryError::Conflict(_))
+            ));
+            assert!(matches!(
+                uow.update_user(user(2)).await,
+                Err(RepositoryError::NotFound)
+            ));
+            assert!(matches!(
+                uow.delete_user(user(2)).await,
+                Err(RepositoryError::NotFound)
+            ));
+            uow.rollback().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn update_user_where_changes_only_the_matching_users() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None);
+            uow.begin().await.unwrap();
+            uow.create_user(User::new(1, "inactive".to_string()))
+                .await
+                .unwrap();
+            uow.create_user(User::new(2, "inactive".to_string()))
+                .await
+                .unwrap();
+            uow.create_user(User::new(3, "active".to_string()))
+                .await
+                .unwrap();
+
+            let changes = UserChanges {
+                name: Some("archived".to_string()),
+            };
+            let filter = UserFilter {
+                name: Some("inactive".to_string()),
+                ..UserFilter::default()
+            };
+            assert_eq!(uow.update_user_where(changes, filter).await.unwrap(), 2);
+            uow.commit().await.unwrap();
+
+            let names = [
+                uow.find_user(1).await.unwrap().unwrap().name,
+                uow.find_user(2).await.unwrap().unwrap().name,
+                uow.find_user(3).await.unwrap().unwrap().name,
+            ];
+            assert_eq!(names, ["archived", "archived", "active"]);
+        });
+    }
+
+    #[test]
+    fn advisory_locks_need_a_postgres_transaction() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None);
+            assert!(matches!(
+                uow.advisory_lock(7).await,
+                Err(UnitOfWorkError::NotStarted)
+            ));
+            uow.begin().await.unwrap();
+            assert!(matches!(
+                uow.advisory_lock(7).await,
+                Err(UnitOfWorkError::AdvisoryLockUnsupported)
+            ));
+            uow.rollback().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn a_failed_savepoint_step_is_skipped_and_the_rest_commits() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = UnitOfWorkImpl::new(conn.clone(), None);
+            uow.begin().await.unwrap();
+            for id in 1..=3 {
+                let result = uow
+                    .try_with_savepoint(|uow| {
+                        Box::pin(async move {
+                            uow.create_user(user(id)).await?;
+                            if id == 2 {
+                                anyhow::bail!("user 2 is invalid");
+                            }
+                            Ok(())
+                        })
+                    })
+                    .await;
+                assert_eq!(result.is_ok(), id != 2);
+            }
+            uow.commit().await.unwrap();
+
+            let reader = UnitOfWorkImpl::new(conn, None);
+            assert!(reader.find_user(1).await.unwrap().is_some());
+            assert!(reader.find_user(2).await.unwrap().is_none());
+            assert!(reader.find_user(3).await.unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn a_failed_savepoint_cleanup_keeps_the_closures_error() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None);
+            uow.begin().await.unwrap();
+            let err = uow
+                .try_with_savepoint::<(), _>(|uow| {
+                    Box::pin(async move {
+                        uow.rollback().await?;
+                        anyhow::bail!("user 2 is invalid");
+                    })
+                })
+                .await
+                .unwrap_err();
+
+            assert_eq!(err.root_cause().to_string(), "user 2 is invalid");
+            assert!(err.to_string().starts_with("rollback also failed"));
+        });
+    }
+
+    #[test]
+    fn count_users_sees_the_rows_of_the_open_transaction() {
+        block_on(async {
+            let mut uow = UnitOfWorkImpl::new(connect().await, None);
+            uow.begin().await.unwrap();
+            uow.create_user(user(1)).await.unwrap();
+            uow.create_user(user(2)).await.unwrap();
+            assert_eq!(uow.count_users(UserFilter::default()).await.unwrap(), 2);
+            let named = UserFilter {
+                name: Some("user 2".to_string()),
+                ..Default::default()
+            };
+            assert_eq!(uow.count_users(named).await.unwrap(), 1);
+
+            uow.rollback().await.unwrap();
+            assert_eq!(uow.count_users(UserFilter::default()).await.unwrap(), 0);
+        });
+    }
+
+    /// Hands out mocks whose commit fails with SQLSTATE `code` for the first `failures`
+    /// units of work.
+    struct FlakyProvider {
+        code: &'static str,
+        failures: usize,
+        provided: Mutex<usize>,
+    }
+
+    impl FlakyProvider {
+        fn new(code: &'static str, failures: usize) -> Self {
+            Self {
+                code,
+                failures,
+                provided: Default::default(),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ProvideUnitOfWork for FlakyProvider {
+        type UnitOfWork = MockUnitOfWork;
+
+        fn provide(&self) -> Self::UnitOfWork {
+            let mut provided = self.provided.lock().unwrap();
+            *provided += 1;
+            if *provided <= self.failures {
+                MockUnitOfWork::failing_commit(self.code)
+            } else {
+                MockUnitOfWork::default()
+            }
+        }
+    }
+
+    #[test]
+    fn with_unit_of_work_retry_reruns_the_closure_after_a_commit_conflict() {
+        block_on(async {
+            let provider = FlakyProvider::new("40001", 1);
+            let mut runs = 0;
+            let value = with_unit_of_work_retry(&provider, 3, |uow| {
+                runs += 1;
+                let run = runs;
+                Box::pin(async move {
+                    uow.create_user(user(1)).await?;
+                    Ok(run)
+                })
+            })
+            .await
+            .unwrap();
+            assert_eq!((runs, value), (2, 2));
+
+            let provider = FlakyProvider::new("23505", 1);
+            let err = with_unit_of_work_retry(&provider, 3, |_| Box::pin(async { Ok(()) }))
+                .await
+                .unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "failed to commit unit of work after 1 attempt"
+            );
+        });
+    }
+
+    #[test]
+    fn with_unit_of_work_retry_reruns_the_closure_after_a_deadlocked_statement() {
+        block_on(async {
+            let provider = FlakyProvider::new("40001", 0);
+            let mut runs = 0;
+            let value = with_unit_of_work_retry(&provider, 3, |uow| {
+                runs += 1;
+                let run = runs;
+                Box::pin(async move {
+                    if run == 1 {
+                        let deadlock = DbError::new(Some("40P01".to_string()), "deadlock detected");
+                        return Err(RepositoryError::Db(deadlock).into());
+                    }
+                    uow.create_user(user(1)).await?;
+                    Ok(run)
+                })
+            })
+            .await
+            .unwrap();
+            assert_eq!((runs, value), (2, 2));
+        });
+    }
+
+    #[test]
+    fn rolling_back_the_in_memory_backend_restores_its_snapshot() {
+        block_on(async {
+            let mut uow = InMemoryUnitOfWork::default();
+            uow.begin().await.unwrap();
+            uow.create_user(user(1)).await.unwrap();
+            uow.commit().await.unwrap();
+
+            uow.begin().await.unwrap();
+            uow.create_user(user(2)).await.unwrap();
+            assert!(uow.find_user(2).await.unwrap().is_some());
+            uow.rollback().await.unwrap();
+
+            assert!(uow.find_user(2).await.unwrap().is_none());
+            assert!(uow.find_user(1).await.unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn advisory_locks_serialize_units_of_work_on_postgres() {
+        block_on(async {
+            let Some(conn) = postgres("uow_advisory_lock").await else {
+                return;
+            };
+            let mut first = UnitOfWorkImpl::new(conn.clone(), None);
+            let mut second = UnitOfWorkImpl::new(conn, None);
+            first.begin().await.unwrap();
+            second.begin().await.unwrap();
+            first.advisory_lock(7).await.unwrap();
+
+            let order = Arc::new(Mutex::new(Vec::new()));
+            let waiter = tokio::spawn({
+                let order = order.clone();
+                async move {
+                    second.advisory_lock(7).await.unwrap();
+                    order.lock().unwrap().push("second locks");
+                    second.commit().await.unwrap();
+                }
+            });
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            order.lock().unwrap().push("first commits");
+            first.commit().await.unwrap();
+            waiter.await.unwrap();
+            assert_eq!(*order.lock().unwrap(), ["first commits", "second locks"]);
+        });
     }
 }
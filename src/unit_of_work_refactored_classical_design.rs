@@ -1,17 +1,93 @@
-mod domain {
+pub mod domain {
     use anyhow::Result;
     use async_trait::async_trait;
+    use chrono::{DateTime, Utc};
     use derive_new::new;
+    use thiserror::Error;
 
     #[derive(Debug, new)]
-    pub struct User;
+    pub struct User {
+        pub id: i64,
+        pub name: String,
+        pub version: u64,
+    }
+
+    /// The columns of a user to overwrite; `None` leaves the stored value untouched.
+    #[derive(Debug, Default)]
+    pub struct UserPatch {
+        pub id: i64,
+        pub name: Option<String>,
+    }
+
+    /// Builds a partial update of a user, e.g. `UpdateBuilder::user(1).name("x").build()`.
+    #[derive(Debug)]
+    pub struct UpdateBuilder {
+        patch: UserPatch,
+    }
+
+    impl UpdateBuilder {
+        pub fn user(id: i64) -> Self {
+            Self {
+                patch: UserPatch {
+                    id,
+                    ..Default::default()
+                },
+            }
+        }
+
+        pub fn name(mut self, name: impl Into<String>) -> Self {
+            self.patch.name = Some(name.into());
+            self
+        }
+
+        pub fn build(self) -> Command {
+            Command::new(Aggregate::UserPatch(self.patch), DBOperation::PartialUpdate)
+        }
+    }
+
+    #[derive(Debug, Error)]
+    #[error("user {id} was modified concurrently (expected version {version})")]
+    pub struct ConflictError {
+        pub id: i64,
+        pub version: u64,
+    }
 
     #[derive(Debug, new)]
     pub struct Shop;
 
+    pub trait Clock: Send + Sync {
+        fn now(&self) -> DateTime<Utc>;
+    }
+
+    #[derive(Debug, Default)]
+    pub struct SystemClock;
+
+    impl Clock for SystemClock {
+        fn now(&self) -> DateTime<Utc> {
+            Utc::now()
+        }
+    }
+
+    #[derive(Debug, new)]
+    pub struct FixedClock(DateTime<Utc>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
     #[async_trait]
     pub trait UnitOfWork {
-        async fn commit(&mut self) -> Result<()>;
+        async fn commit(&mut self) -> Result<CommitReport>;
+    }
+
+    /// Pairs the staging index of each update and delete with the number of rows it
+    /// touched, so callers can tell a no-op (e.g. a missing id) from a real change. A user
+    /// update whose version no longer matches fails the commit with `ConflictError` instead.
+    #[derive(Debug, Default)]
+    pub struct CommitReport {
+        pub affected_rows: Vec<(usize, u64)>,
     }
 
     #[derive(Debug, new)]
@@ -23,6 +99,7 @@ mod domain {
     #[derive(Debug)]
     pub enum Aggregate {
         User(User),
+        UserPatch(UserPatch),
         Shop(Shop),
     }
 
@@ -30,6 +107,9 @@ mod domain {
     pub enum DBOperation {
         Create,
         Update,
+        /// Writes only the columns set on a `UserPatch`, without the optimistic version
+        /// check. Whole aggregates have no patch to write and reject it.
+        PartialUpdate,
         Delete,
     }
 
@@ -45,154 +125,343 @@ mod domain {
         }
     }
 
+    pub trait Repository<T> {
+        fn create(&mut self, aggregate: T) -> Result<()>;
+        fn update(&mut self, aggregate: T) -> Result<()>;
+        fn delete(&mut self, aggregate: T) -> Result<()>;
+    }
+
+    /// Trait aliases are unstable, so the per-aggregate traits are kept as thin wrappers that
+    /// every `Repository<User>` gets for free.
     pub trait UserRepository {
         fn create_user(&mut self, user: User) -> Result<()>;
         fn update_user(&mut self, user: User) -> Result<()>;
         fn delete_user(&mut self, user: User) -> Result<()>;
     }
 
+    impl<R: Repository<User>> UserRepository for R {
+        fn create_user(&mut self, user: User) -> Result<()> {
+            self.create(user)
+        }
+
+        fn update_user(&mut self, user: User) -> Result<()> {
+            self.update(user)
+        }
+
+        fn delete_user(&mut self, user: User) -> Result<()> {
+            self.delete(user)
+        }
+    }
+
     pub trait ShopRepository {
         fn create_shop(&mut self, shop: Shop) -> Result<()>;
         fn update_shop(&mut self, shop: Shop) -> Result<()>;
         fn delete_shop(&mut self, shop: Shop) -> Result<()>;
     }
+
+    impl<R: Repository<Shop>> ShopRepository for R {
+        fn create_shop(&mut self, shop: Shop) -> Result<()> {
+            self.create(shop)
+        }
+
+        fn update_shop(&mut self, shop: Shop) -> Result<()> {
+            self.update(shop)
+        }
+
+        fn delete_shop(&mut self, shop: Shop) -> Result<()> {
+            self.delete(shop)
+        }
+    }
 }
 
-mod infrastructure {
+pub mod infrastructure {
     use super::domain::{
-        Aggregate, Command, DBOperation, Shop, ShopRepository, UnitOfWork, User, UserRepository,
+        Aggregate, Clock, Command, CommitReport, ConflictError, DBOperation, Repository, Shop,
+        SystemClock, UnitOfWork, User, UserPatch,
     };
 
+    use std::sync::Arc;
+
     use anyhow::{Context, Result};
     use async_trait::async_trait;
+    use chrono::{DateTime, Utc};
     use derive_new::new;
     use sea_orm::{
-        prelude::{DatabaseConnection, DbErr},
-        DatabaseTransaction, TransactionTrait,
+        prelude::{DatabaseConnection, DbErr, Expr},
+        ActiveModelTrait, ColumnTrait, DatabaseTransaction, EntityTrait, NotSet, QueryFilter, Set,
+        TransactionError, TransactionTrait,
     };
+    use thiserror::Error;
+
+    mod users {
+        use sea_orm::entity::prelude::*;
+
+        #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+        #[sea_orm(table_name = "users")]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub id: i64,
+            pub name: String,
+            pub version: i64,
+            pub deleted_at: Option<DateTimeUtc>,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    #[derive(Debug, Error)]
+    enum CommitError {
+        #[error(transparent)]
+        Db(#[from] DbErr),
+        #[error(transparent)]
+        Conflict(#[from] ConflictError),
+    }
 
     #[derive(new)]
     pub struct DatabaseClient {
         conn: DatabaseConnection,
         commands: Vec<Command>,
+        #[new(value = "Arc::new(SystemClock)")]
+        clock: Arc<dyn Clock>,
+    }
+
+    impl DatabaseClient {
+        pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+            self.clock = clock;
+            self
+        }
+
+        pub fn clear(&mut self) {
+            self.commands.clear();
+        }
+
+        pub fn take_commands(&mut self) -> Vec<Command> {
+            self.commands.drain(..).collect()
+        }
+
+        /// Stages a prebuilt command, such as one from `UpdateBuilder`.
+        pub fn stage(&mut self, command: Command) {
+            self.commands.push(command);
+        }
     }
 
     #[async_trait]
     impl UnitOfWork for DatabaseClient {
-        async fn commit(&mut self) -> Result<()> {
+        async fn commit(&mut self) -> Result<CommitReport> {
             let commands = self.commands.drain(..).collect::<Vec<_>>();
+            let now = self.clock.now();
             self.conn
-                .transaction::<_, (), DbErr>(|txn| {
+                .transaction::<_, CommitReport, CommitError>(|txn| {
                     Box::pin(async move {
-                        for command in commands {
-                            match command.aggregate {
+                        let mut report = CommitReport::default();
+                        for (index, command) in commands.into_iter().enumerate() {
+                            let affected = match command.aggregate {
                                 Aggregate::User(user) => match command.db_operation {
-                                    DBOperation::Create => create_user(user, txn).await,
-                                    DBOperation::Update => update_user(user, txn).await,
-                                    DBOperation::Delete => delete_user(user, txn).await,
+                                    DBOperation::Create => {
+                                        create_user(user, txn).await?;
+                                        None
+                                    }
+                                    DBOperation::Update => Some(update_user(user, txn).await?),
+                                    DBOperation::Delete => Some(delete_user(user, now, txn).await?),
+                                    DBOperation::PartialUpdate => {
+                                        return Err(DbErr::Custom(
+                                            "a user cannot be used for PartialUpdate; stage a user patch"
+                                                .to_string(),
+                                        )
+                                        .into())
+                                    }
+                                },
+                                Aggregate::UserPatch(patch) => match command.db_operation {
+                                    DBOperation::Update | DBOperation::PartialUpdate => {
+                                        Some(patch_user(patch, txn).await?)
+                                    }
+                                    operation => {
+                                        return Err(DbErr::Custom(format!(
+                                            "a user patch cannot be used for {:?}",
+                                            operation
+                                        ))
+                                        .into())
+                                    }
                                 },
                                 Aggregate::Shop(shop) => match command.db_operation {
-                                    DBOperation::Create => create_shop(shop, txn).await,
-                                    DBOperation::Update => update_shop(shop, txn).await,
-                                    DBOperation::Delete => delete_shop(shop, txn).await,
+                                    DBOperation::Create => {
+                                        create_shop(shop, txn).await?;
+                                        None
+                                    }
+                                    DBOperation::Update => Some(update_shop(shop, txn).await?),
+                                    DBOperation::Delete => Some(delete_shop(shop, txn).await?),
+                                    DBOperation::PartialUpdate => {
+                                        return Err(DbErr::Custom(
+                                            "a shop cannot be used for PartialUpdate".to_string(),
+                                        )
+                                        .into())
+                                    }
                                 },
-                            }?;
+                            };
+                            if let Some(rows) = affected {
+                                report.affected_rows.push((index, rows));
+                            }
                         }
 
-                        Ok(())
+                        Ok(report)
                     })
                 })
                 .await
-                .with_context(|| format!("failed to commit transaction"))?;
-            Ok(())
+                .map_err(|err| match err {
+                    TransactionError::Transaction(CommitError::Conflict(conflict)) => {
+                        anyhow::Error::new(conflict)
+                    }
+                    err => anyhow::Error::new(err),
+                })
+                .with_context(|| "failed to commit transaction")
         }
     }
 
-    async fn create_user(_user: User, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    async fn create_user(user: User, txn: &DatabaseTransaction) -> Result<(), DbErr> {
+        users::ActiveModel {
+            name: Set(user.name),
+            version: Set(user.version as i64),
+            ..Default::default()
+        }
+        .insert(txn)
+        .await?;
+        Ok(())
     }
 
-    async fn update_user(_user: User, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    async fn update_user(user: User, txn: &DatabaseTransaction) -> Result<u64, CommitError> {
+        let result = users::Entity::update_many()
+            .col_expr(users::Column::Name, Expr::value(user.name))
+            .col_expr(users::Column::Version, Expr::value(user.version as i64 + 1))
+            .filter(users::Column::Id.eq(user.id))
+            .filter(users::Column::Version.eq(user.version as i64))
+            .exec(txn)
+            .await?;
+        if result.rows_affected == 0 && users::Entity::find_by_id(user.id).one(txn).await?.is_some()
+        {
+            return Err(ConflictError {
+                id: user.id,
+                version: user.version,
+            }
+            .into());
+        }
+        Ok(result.rows_affected)
     }
 
-    async fn delete_user(_user: User, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    /// Only the columns present on the patch are `Set`, so the generated `UPDATE` touches
+    /// nothing else.
+    async fn patch_user(patch: UserPatch, txn: &DatabaseTransaction) -> Result<u64, DbErr> {
+        let model = users::ActiveModel {
+            name: patch.name.map_or(NotSet, Set),
+            ..Default::default()
+        };
+        let result = users::Entity::update_many()
+            .set(model)
+            .filter(users::Column::Id.eq(patch.id))
+            .exec(txn)
+            .await?;
+        Ok(result.rows_affected)
     }
 
-    async fn create_shop(_shop: Shop, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    /// Users are soft-deleted: the row is kept and stamped with `deleted_at`.
+    async fn delete_user(
+        user: User,
+        now: DateTime<Utc>,
+        txn: &DatabaseTransaction,
+    ) -> Result<u64, DbErr> {
+        let result = users::Entity::update_many()
+            .col_expr(users::Column::DeletedAt, Expr::value(now))
+            .filter(users::Column::Id.eq(user.id))
+            .filter(users::Column::DeletedAt.is_null())
+            .exec(txn)
+            .await?;
+        Ok(result.rows_affected)
     }
 
-    async fn update_shop(_shop: Shop, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    fn shops_are_not_persisted() -> DbErr {
+        DbErr::Custom("shops are not persisted in this design".to_string())
     }
 
-    async fn delete_shop(_shop: Shop, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
-        unimplemented!()
+    async fn create_shop(_shop: Shop, _txn: &DatabaseTransaction) -> Result<(), DbErr> {
+        Err(shops_are_not_persisted())
     }
 
-    impl UserRepository for DatabaseClient {
-        fn create_user(&mut self, user: User) -> Result<()> {
-            self.commands
-                .push(Command::new(user.into(), DBOperation::Create));
-            Ok(())
-        }
-
-        fn update_user(&mut self, user: User) -> Result<()> {
-            self.commands
-                .push(Command::new(user.into(), DBOperation::Update));
-            Ok(())
-        }
+    async fn update_shop(_shop: Shop, _txn: &DatabaseTransaction) -> Result<u64, DbErr> {
+        Err(shops_are_not_persisted())
+    }
 
-        fn delete_user(&mut self, user: User) -> Result<()> {
-            self.commands
-                .push(Command::new(user.into(), DBOperation::Delete));
-            Ok(())
-        }
+    async fn delete_shop(_shop: Shop, _txn: &DatabaseTransaction) -> Result<u64, DbErr> {
+        Err(shops_are_not_persisted())
     }
 
-    impl ShopRepository for DatabaseClient {
-        fn create_shop(&mut self, shop: Shop) -> Result<()> {
+    impl<T: Into<Aggregate>> Repository<T> for DatabaseClient {
+        fn create(&mut self, aggregate: T) -> Result<()> {
             self.commands
-                .push(Command::new(shop.into(), DBOperation::Create));
+                .push(Command::new(aggregate.into(), DBOperation::Create));
             Ok(())
         }
 
-        fn update_shop(&mut self, shop: Shop) -> Result<()> {
+        fn update(&mut self, aggregate: T) -> Result<()> {
             self.commands
-                .push(Command::new(shop.into(), DBOperation::Update));
+                .push(Command::new(aggregate.into(), DBOperation::Update));
             Ok(())
         }
 
-        fn delete_shop(&mut self, shop: Shop) -> Result<()> {
+        fn delete(&mut self, aggregate: T) -> Result<()> {
             self.commands
-                .push(Command::new(shop.into(), DBOperation::Delete));
+                .push(Command::new(aggregate.into(), DBOperation::Delete));
             Ok(())
         }
     }
 }
 
-mod context {
-    use sea_orm::prelude::DatabaseConnection;
+pub mod context {
+    use anyhow::{Context as _, Result};
+    use async_trait::async_trait;
+    use sea_orm::{prelude::DatabaseConnection, Database};
 
     use super::infrastructure::DatabaseClient;
 
+    #[async_trait]
     pub trait ProvideUnitOfWork {
         type UnitOfWork: super::domain::UnitOfWork + Send + Sync;
         fn provide(&self) -> Self::UnitOfWork;
+
+        async fn provide_async(&self) -> Result<Self::UnitOfWork> {
+            Ok(self.provide())
+        }
     }
 
     pub struct Context {
         conn: DatabaseConnection,
     }
 
+    impl Context {
+        pub async fn connect(url: &str) -> Result<Self> {
+            let conn = Database::connect(url)
+                .await
+                .with_context(|| "Failed to connect to database")?;
+            Ok(Self { conn })
+        }
+    }
+
+    #[async_trait]
     impl ProvideUnitOfWork for Context {
         type UnitOfWork = super::infrastructure::DatabaseClient;
 
         fn provide(&self) -> Self::UnitOfWork {
             DatabaseClient::new(self.conn.clone(), vec![])
         }
+
+        async fn provide_async(&self) -> Result<Self::UnitOfWork> {
+            self.conn
+                .ping()
+                .await
+                .with_context(|| "Failed to acquire connection")?;
+            Ok(self.provide())
+        }
     }
 }
 
@@ -206,10 +475,275 @@ mod use_case {
     async fn use_case(context: Context) -> anyhow::Result<()> {
         let mut uow = context.provide();
 
-        uow.update_user(User::new());
+        uow.update_user(User::new(1, "user".to_string(), 0));
         uow.update_shop(Shop::new());
         uow.commit().await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        domain::{
+            Aggregate, Command, ConflictError, DBOperation, FixedClock, Repository, Shop,
+            ShopRepository, UnitOfWork, UpdateBuilder, User, UserRepository,
+        },
+        infrastructure::DatabaseClient,
+    };
+    use crate::test_support::block_on;
+
+    use std::sync::{Arc, Mutex};
+
+    use chrono::{DateTime, TimeZone, Utc};
+    use sea_orm::{ConnectionTrait, Database, DatabaseConnection, Statement};
+
+    async fn connect() -> DatabaseConnection {
+        let conn = Database::connect("sqlite::memory:").await.unwrap();
+        conn.execute_unprepared(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT NOT NULL, \
+             version INTEGER NOT NULL, deleted_at TEXT)",
+        )
+        .await
+        .unwrap();
+        conn
+    }
+
+    fn user(id: i64, version: u64) -> User {
+        User::new(id, format!("user {}", id), version)
+    }
+
+    fn noon() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn deletes_and_updates_report_the_rows_they_touched() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow =
+                DatabaseClient::new(conn, vec![]).with_clock(Arc::new(FixedClock::new(noon())));
+            uow.create_user(user(0, 0)).unwrap();
+            uow.commit().await.unwrap();
+
+            uow.update_user(user(1, 0)).unwrap();
+            uow.update_user(user(2, 0)).unwrap();
+            uow.delete_user(user(1, 1)).unwrap();
+            uow.delete_user(user(2, 0)).unwrap();
+            let report = uow.commit().await.unwrap();
+
+            assert_eq!(report.affected_rows, [(0, 1), (1, 0), (2, 1), (3, 0)]);
+        });
+    }
+
+    #[test]
+    fn the_second_of_two_updates_from_the_same_version_is_a_conflict() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = DatabaseClient::new(conn.clone(), vec![]);
+            uow.create_user(user(0, 3)).unwrap();
+            uow.commit().await.unwrap();
+
+            let mut first = DatabaseClient::new(conn.clone(), vec![]);
+            let mut second = DatabaseClient::new(conn, vec![]);
+            first.update_user(user(1, 3)).unwrap();
+            second.update_user(user(1, 3)).unwrap();
+            first.commit().await.unwrap();
+            let err = second.commit().await.unwrap_err();
+
+            let conflict = err.downcast_ref::<ConflictError>().unwrap();
+            assert_eq!((conflict.id, conflict.version), (1, 3));
+        });
+    }
+
+    #[test]
+    fn create_user_inserts_a_row_with_the_users_name() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = DatabaseClient::new(conn.clone(), vec![]);
+            uow.create_user(User::new(0, "ada".to_string(), 0)).unwrap();
+            uow.create_user(User::new(0, "grace".to_string(), 0))
+                .unwrap();
+            uow.commit().await.unwrap();
+
+            let rows = conn
+                .query_all(Statement::from_string(
+                    conn.get_database_backend(),
+                    "SELECT id, name FROM users ORDER BY id",
+                ))
+                .await
+                .unwrap();
+            let rows = rows
+                .iter()
+                .map(|row| {
+                    (
+                        row.try_get::<i64>("", "id").unwrap(),
+                        row.try_get::<String>("", "name").unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(rows, [(1, "ada".to_string()), (2, "grace".to_string())]);
+        });
+    }
+
+    async fn deleted_at(conn: &DatabaseConnection, id: i64) -> Option<String> {
+        let row = conn
+            .query_one(Statement::from_sql_and_values(
+                conn.get_database_backend(),
+                "SELECT deleted_at FROM users WHERE id = ?",
+                [id.into()],
+            ))
+            .await
+            .unwrap()
+            .unwrap();
+        row.try_get("", "deleted_at").unwrap()
+    }
+
+    #[test]
+    fn deleting_a_user_keeps_the_row_and_stamps_deleted_at() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = DatabaseClient::new(conn.clone(), vec![]);
+            uow.create_user(user(0, 0)).unwrap();
+            uow.create_user(user(0, 0)).unwrap();
+            uow.commit().await.unwrap();
+
+            uow.delete_user(user(1, 0)).unwrap();
+            uow.commit().await.unwrap();
+
+            assert!(deleted_at(&conn, 1).await.is_some());
+            assert!(deleted_at(&conn, 2).await.is_none());
+        });
+    }
+
+    #[test]
+    fn soft_deletes_are_stamped_with_the_clocks_time() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = DatabaseClient::new(conn.clone(), vec![])
+                .with_clock(Arc::new(FixedClock::new(noon())));
+            uow.create_user(user(0, 0)).unwrap();
+            uow.commit().await.unwrap();
+            uow.delete_user(user(1, 0)).unwrap();
+            uow.commit().await.unwrap();
+
+            let row = conn
+                .query_one(Statement::from_string(
+                    conn.get_database_backend(),
+                    "SELECT deleted_at FROM users WHERE id = 1",
+                ))
+                .await
+                .unwrap()
+                .unwrap();
+            let deleted_at: Option<DateTime<Utc>> = row.try_get("", "deleted_at").unwrap();
+            assert_eq!(deleted_at, Some(noon()));
+        });
+    }
+
+    #[test]
+    fn the_generic_repository_queues_a_command() {
+        let mut uow = DatabaseClient::new(DatabaseConnection::Disconnected, vec![]);
+        Repository::<User>::create(&mut uow, user(1, 0)).unwrap();
+        Repository::<Shop>::delete(&mut uow, Shop::new()).unwrap();
+
+        let commands = uow.take_commands();
+        assert!(matches!(
+            commands.as_slice(),
+            [
+                Command {
+                    aggregate: Aggregate::User(User { id: 1, .. }),
+                    db_operation: DBOperation::Create,
+                },
+                Command {
+                    aggregate: Aggregate::Shop(_),
+                    db_operation: DBOperation::Delete,
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn a_partial_update_writes_only_the_columns_it_sets() {
+        block_on(async {
+            let mut conn = connect().await;
+            let updates = Arc::new(Mutex::new(Vec::new()));
+            let seen = updates.clone();
+            conn.set_metric_callback(move |info| {
+                if info.statement.sql.starts_with("UPDATE") {
+                    seen.lock().unwrap().push(info.statement.sql.clone());
+                }
+            });
+            let mut uow = DatabaseClient::new(conn.clone(), vec![]);
+            uow.create_user(user(0, 5)).unwrap();
+            uow.commit().await.unwrap();
+
+            uow.stage(UpdateBuilder::user(1).name("renamed").build());
+            uow.stage(UpdateBuilder::user(1).build());
+            let report = uow.commit().await.unwrap();
+            assert_eq!(report.affected_rows, [(0, 1), (1, 0)]);
+            assert_eq!(
+                *updates.lock().unwrap(),
+                [r#"UPDATE "users" SET "name" = ? WHERE "users"."id" = ?"#]
+            );
+
+            let row = conn
+                .query_one(Statement::from_string(
+                    conn.get_database_backend(),
+                    "SELECT name, version FROM users WHERE id = 1",
+                ))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(row.try_get::<String>("", "name").unwrap(), "renamed");
+            assert_eq!(row.try_get::<i64>("", "version").unwrap(), 5);
+        });
+    }
+
+    #[test]
+    fn a_partial_update_of_a_whole_user_is_rejected() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = DatabaseClient::new(conn.clone(), vec![]);
+            uow.create_user(user(0, 5)).unwrap();
+            uow.commit().await.unwrap();
+
+            uow.stage(Command::new(
+                Aggregate::User(User::new(1, "renamed".to_string(), 5)),
+                DBOperation::PartialUpdate,
+            ));
+            uow.commit().await.unwrap_err();
+
+            let row = conn
+                .query_one(Statement::from_string(
+                    conn.get_database_backend(),
+                    "SELECT version FROM users WHERE id = 1",
+                ))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(row.try_get::<i64>("", "version").unwrap(), 5);
+        });
+    }
+
+    #[test]
+    fn committing_a_shop_command_fails_and_rolls_back_the_transaction() {
+        block_on(async {
+            let conn = connect().await;
+            let mut uow = DatabaseClient::new(conn.clone(), vec![]);
+            uow.create_user(user(0, 0)).unwrap();
+            uow.update_shop(Shop::new()).unwrap();
+            let err = uow.commit().await.unwrap_err();
+            assert!(format!("{:#}", err).contains("shops are not persisted in this design"));
+
+            let rows = conn
+                .query_all(Statement::from_string(
+                    conn.get_database_backend(),
+                    "SELECT id FROM users",
+                ))
+                .await
+                .unwrap();
+            assert!(rows.is_empty());
+        });
+    }
+}